@@ -0,0 +1,270 @@
+use crate::auth::AuthenticatedUser;
+use crate::ephemeral::EphemeralSessions;
+use crate::store::{ConversationStore, UserExport};
+use crate::tasks::UserTasks;
+use actix_web::{delete, get, web, HttpResponse, Result as ActixResult};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{Cursor, Write};
+use zip::{write::FileOptions, ZipWriter};
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+/// Packs an export as a ZIP with one JSON file per top-level section, so
+/// new kinds of stored data show up in the archive without changes here.
+fn export_zip(export: &UserExport) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let sections = match serde_json::to_value(export)? {
+        Value::Object(map) => map,
+        _ => unreachable!("UserExport serializes to an object"),
+    };
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default();
+    let mut manifest = serde_json::Map::new();
+    for (name, value) in sections {
+        if value.is_array() {
+            zip.start_file(format!("{}.json", name), options)?;
+            zip.write_all(&serde_json::to_vec_pretty(&value)?)?;
+        } else {
+            manifest.insert(name, value);
+        }
+    }
+    zip.start_file("manifest.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Everything stored for the caller, as JSON (default) or `?format=zip`.
+#[get("/me/export")]
+async fn export_me(
+    query: web::Query<ExportQuery>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<HttpResponse> {
+    info!("Exporting data for user_id={}", user.user_id);
+    let export = store.export_user(&user.user_id).await.map_err(|e| {
+        error!("Export failed: {}", e);
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+
+    match query.format.as_deref().unwrap_or("json") {
+        "json" => Ok(HttpResponse::Ok()
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"hearthly-export.json\"",
+            ))
+            .json(export)),
+        "zip" => {
+            let bytes = export_zip(&export).map_err(|e| {
+                error!("Export archive failed: {}", e);
+                actix_web::error::ErrorInternalServerError(e.to_string())
+            })?;
+            Ok(HttpResponse::Ok()
+                .content_type("application/zip")
+                .insert_header((
+                    "Content-Disposition",
+                    "attachment; filename=\"hearthly-export.zip\"",
+                ))
+                .body(bytes))
+        }
+        _ => Err(actix_web::error::ErrorBadRequest(
+            "format must be json or zip",
+        )),
+    }
+}
+
+/// Permanently deletes everything stored for the caller, including any
+/// off-the-record sessions held in memory, and reports what was removed.
+/// Background work for the caller is stopped first and kept from starting
+/// until the deletion is done, so none of it writes their data back.
+#[delete("/me")]
+async fn delete_me(
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+    sessions: web::Data<EphemeralSessions>,
+    tasks: web::Data<UserTasks>,
+) -> ActixResult<HttpResponse> {
+    let ended = sessions.forget_user(&user.user_id);
+    if ended > 0 {
+        info!("Ended {} ephemeral sessions for user_id={}", ended, user.user_id);
+    }
+    let _stopped = tasks.stop_user(&user.user_id).await;
+    let report = store.delete_user(&user.user_id).await.map_err(|e| {
+        error!("Delete failed for user_id={}: {}", user.user_id, e);
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+    info!("Deleted data for user_id={}: {:?}", user.user_id, report);
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthProvider, StaticTokenAuth};
+    use crate::store::{ChatMessage, Fact, MemoryStore, Thread, Turn};
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::sync::Arc;
+    use zip::ZipArchive;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    /// A store holding a thread, a turn, a fact and an embedding for
+    /// `alice`, and a turn for `bob`.
+    async fn seeded_store() -> Arc<dyn ConversationStore> {
+        let store: Arc<dyn ConversationStore> = Arc::new(MemoryStore::new());
+        let thread = store.create_thread("alice", Thread::new("Work".into())).await.unwrap();
+        let turn = Turn::new(vec![message("user", "My sister Ana visited"), message("assistant", "How was it?")]);
+        let stored = store.append_turn("alice", Some(&thread.id), turn).await.unwrap();
+        store
+            .put_embeddings("alice", "test-model", vec![(stored[0].id, vec![0.5, 0.5])])
+            .await
+            .unwrap();
+        let fact = Fact::new("people".into(), "Has a sister, Ana".into(), None, "My sister Ana".into());
+        store.add_facts("alice", vec![fact]).await.unwrap();
+        let turn = Turn::new(vec![message("user", "hi"), message("assistant", "hello")]);
+        store.append_turn("bob", None, turn).await.unwrap();
+        store
+    }
+
+    /// Sends `request` as `alice` to an app serving the account routes.
+    async fn call_as_alice(
+        store: Arc<dyn ConversationStore>,
+        tasks: Arc<UserTasks>,
+        request: TestRequest,
+    ) -> ServiceResponse {
+        let tokens = HashMap::from([
+            ("alice-token".to_string(), "alice".to_string()),
+            ("bob-token".to_string(), "bob".to_string()),
+        ]);
+        let auth: Arc<dyn AuthProvider> = Arc::new(StaticTokenAuth::new(tokens));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(auth))
+                .app_data(web::Data::from(store))
+                .app_data(web::Data::new(EphemeralSessions::from_env()))
+                .app_data(web::Data::from(tasks))
+                .service(export_me)
+                .service(delete_me),
+        )
+        .await;
+        let request = request
+            .insert_header((header::AUTHORIZATION, "Bearer alice-token"))
+            .to_request();
+        test::call_service(&app, request).await
+    }
+
+    #[actix_web::test]
+    async fn exports_json_by_default() {
+        let request = TestRequest::get().uri("/me/export");
+        let response = call_as_alice(seeded_store().await, Arc::new(UserTasks::new()), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let disposition = response.headers().get(header::CONTENT_DISPOSITION).unwrap();
+        assert!(disposition.to_str().unwrap().contains("hearthly-export.json"));
+
+        let export: Value = test::read_body_json(response).await;
+        assert_eq!(export["user_id"], "alice");
+        assert_eq!(export["threads"].as_array().unwrap().len(), 1);
+        assert_eq!(export["messages"].as_array().unwrap().len(), 2);
+        assert_eq!(export["messages"][0]["content"], "My sister Ana visited");
+        assert_eq!(export["facts"][0]["content"], "Has a sister, Ana");
+        assert_eq!(export["embeddings"][0]["model"], "test-model");
+    }
+
+    #[actix_web::test]
+    async fn zip_exports_hold_one_file_per_section_and_a_manifest() {
+        let request = TestRequest::get().uri("/me/export?format=zip");
+        let response = call_as_alice(seeded_store().await, Arc::new(UserTasks::new()), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/zip");
+
+        let body = test::read_body(response).await;
+        let mut archive = ZipArchive::new(std::io::Cursor::new(body.to_vec())).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "embeddings.json",
+                "facts.json",
+                "manifest.json",
+                "messages.json",
+                "summaries.json",
+                "suppressed_facts.json",
+                "threads.json",
+            ]
+        );
+
+        let mut read = |name: &str| -> Value {
+            let mut text = String::new();
+            archive.by_name(name).unwrap().read_to_string(&mut text).unwrap();
+            serde_json::from_str(&text).unwrap()
+        };
+        let manifest = read("manifest.json");
+        assert_eq!(manifest["user_id"], "alice");
+        assert!(manifest["exported_at"].is_string());
+        assert!(manifest.get("retention").is_some());
+        assert_eq!(read("messages.json").as_array().unwrap().len(), 2);
+        assert_eq!(read("summaries.json"), Value::Array(Vec::new()));
+    }
+
+    #[actix_web::test]
+    async fn unknown_export_formats_are_rejected() {
+        let request = TestRequest::get().uri("/me/export?format=xml");
+        let response = call_as_alice(seeded_store().await, Arc::new(UserTasks::new()), request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn deleting_reports_counts_and_leaves_other_users_alone() {
+        let store = seeded_store().await;
+        let request = TestRequest::delete().uri("/me");
+        let response = call_as_alice(store.clone(), Arc::new(UserTasks::new()), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let report: Value = test::read_body_json(response).await;
+        assert_eq!(report["messages"], 2);
+        assert_eq!(report["threads"], 1);
+        assert_eq!(report["facts"], 1);
+        assert_eq!(report["embeddings"], 1);
+        assert_eq!(report["summaries"], 0);
+
+        let export = store.export_user("alice").await.unwrap();
+        assert!(export.messages.is_empty() && export.threads.is_empty() && export.facts.is_empty());
+        assert_eq!(store.export_user("bob").await.unwrap().messages.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn deleting_stops_background_work_before_it_writes_back() {
+        let store = seeded_store().await;
+        let tasks = Arc::new(UserTasks::new());
+        // Stands in for a fact extraction still waiting on the model.
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let late_store = store.clone();
+        tasks.spawn("alice", async move {
+            let _ = released.await;
+            let fact = Fact::new("people".into(), "Late fact".into(), None, String::new());
+            late_store.add_facts("alice", vec![fact]).await.unwrap();
+        });
+
+        let response = call_as_alice(store.clone(), tasks, TestRequest::delete().uri("/me")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert!(release.send(()).is_err(), "extraction was still running after the delete");
+        tokio::task::yield_now().await;
+        assert!(store.list_facts("alice").await.unwrap().is_empty());
+    }
+}
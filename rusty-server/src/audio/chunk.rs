@@ -0,0 +1,183 @@
+use super::decode::{wav_bytes, wav_samples};
+use crate::AudioError;
+use log::debug;
+use std::env;
+
+const DEFAULT_CHUNK_SECS: usize = 120;
+// Each chunk runs this far into the next, so a word cut at the boundary is
+// heard whole by at least one of them.
+const OVERLAP_SECS: f32 = 1.0;
+// How far back from the chunk limit to look for a pause to cut at.
+const SEARCH_SECS: usize = 15;
+// Longest run of repeated words looked for when stitching.
+const MAX_STITCH_WORDS: usize = 12;
+
+/// Splits a mono WAV into pieces of at most `TRANSCRIBE_CHUNK_SECS`
+/// (default two minutes), cutting in the quietest moment near each limit.
+/// Short recordings come back whole and untouched.
+pub fn split(wav: &[u8]) -> Result<Vec<Vec<u8>>, AudioError> {
+    let (samples, rate) = wav_samples(wav)?;
+    let rate = rate as usize;
+    let chunk_secs = env::var("TRANSCRIBE_CHUNK_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > SEARCH_SECS)
+        .unwrap_or(DEFAULT_CHUNK_SECS);
+    let chunk_len = chunk_secs * rate;
+    if samples.len() <= chunk_len {
+        return Ok(vec![wav.to_vec()]);
+    }
+
+    let frame = rate / 50;
+    let overlap = (OVERLAP_SECS * rate as f32) as usize;
+    let energy = |at: usize| samples[at..at + frame].iter().map(|s| s * s).sum::<f32>();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while samples.len() - start > chunk_len {
+        let limit = start + chunk_len - overlap;
+        // Latest of the quietest frames, to keep chunks long.
+        let cut = (limit - SEARCH_SECS * rate..limit - frame)
+            .step_by(frame)
+            .rev()
+            .min_by(|a, b| energy(*a).total_cmp(&energy(*b)))
+            .map_or(limit, |quietest| quietest + frame / 2);
+        debug!("Cutting transcription chunk at {:.2}s", cut as f32 / rate as f32);
+        chunks.push(wav_bytes(&samples[start..cut + overlap], rate as u32)?);
+        start = cut;
+    }
+    chunks.push(wav_bytes(&samples[start..], rate as u32)?);
+    Ok(chunks)
+}
+
+fn normalized(word: &str) -> String {
+    word.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Joins transcripts of consecutive overlapping chunks, dropping words the
+/// overlap made both of them hear.
+pub fn stitch(transcripts: Vec<String>) -> String {
+    let mut text = String::new();
+    for transcript in transcripts {
+        let next: Vec<&str> = transcript.split_whitespace().collect();
+        let tail: Vec<String> = text
+            .split_whitespace()
+            .rev()
+            .take(MAX_STITCH_WORDS)
+            .map(normalized)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        let head: Vec<String> = next.iter().take(MAX_STITCH_WORDS).map(|w| normalized(w)).collect();
+        let repeated = (1..=tail.len().min(head.len()))
+            .rev()
+            .find(|&n| tail[tail.len() - n..] == head[..n])
+            .unwrap_or(0);
+        for word in &next[repeated..] {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(word);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Low, to keep minutes of audio cheap; the 200 Hz tone still fits.
+    const RATE: usize = 2_000;
+
+    /// A steady tone with silent pauses at the given `(start, end)` seconds.
+    fn speech_with_pauses(seconds: f32, pauses: &[(f32, f32)]) -> Vec<f32> {
+        (0..(seconds * RATE as f32) as usize)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                if pauses.iter().any(|&(start, end)| (start..end).contains(&t)) {
+                    0.0
+                } else {
+                    0.3 * (2.0 * std::f32::consts::PI * 200.0 * t).sin()
+                }
+            })
+            .collect()
+    }
+
+    fn chunk_lengths(samples: &[f32]) -> Vec<usize> {
+        split(&wav_bytes(samples, RATE as u32).unwrap())
+            .unwrap()
+            .iter()
+            .map(|chunk| wav_samples(chunk).unwrap().0.len())
+            .collect()
+    }
+
+    fn words(transcripts: &[&str]) -> String {
+        stitch(transcripts.iter().map(|t| t.to_string()).collect())
+    }
+
+    #[test]
+    fn cuts_land_in_pauses() {
+        let pauses = [(110.0, 110.4), (225.0, 225.4)];
+        let lengths = chunk_lengths(&speech_with_pauses(250.0, &pauses));
+        assert_eq!(lengths.len(), 3);
+
+        let overlap = (OVERLAP_SECS * RATE as f32) as usize;
+        let mut start = 0;
+        for (length, (pause_start, pause_end)) in lengths.iter().zip(pauses) {
+            let cut = (start + length - overlap) as f32 / RATE as f32;
+            assert!(
+                (pause_start..pause_end).contains(&cut),
+                "cut at {:.2}s, pause at {}..{}s",
+                cut,
+                pause_start,
+                pause_end
+            );
+            start += length - overlap;
+        }
+        assert_eq!(start + lengths[2], 250 * RATE);
+    }
+
+    #[test]
+    fn chunks_stay_within_the_limit() {
+        let limit = DEFAULT_CHUNK_SECS * RATE;
+        for samples in [
+            speech_with_pauses(400.0, &[(50.0, 50.5), (300.0, 300.2)]),
+            speech_with_pauses(400.0, &[]),
+        ] {
+            let lengths = chunk_lengths(&samples);
+            assert!(lengths.len() >= 4, "{} chunks", lengths.len());
+            assert!(lengths.iter().all(|&len| len <= limit), "chunk lengths {:?}", lengths);
+        }
+    }
+
+    #[test]
+    fn short_recordings_come_back_whole() {
+        let wav = wav_bytes(&speech_with_pauses(10.0, &[]), RATE as u32).unwrap();
+        assert_eq!(split(&wav).unwrap(), [wav]);
+    }
+
+    #[test]
+    fn overlap_words_are_dropped_once() {
+        assert_eq!(
+            words(&["I went to the shop", "to the Shop, and bought milk"]),
+            "I went to the shop and bought milk"
+        );
+        assert_eq!(words(&["no overlap here", "at all"]), "no overlap here at all");
+        assert_eq!(words(&["", "first words", ""]), "first words");
+    }
+
+    #[test]
+    fn genuine_repeats_are_kept() {
+        assert_eq!(
+            words(&["it was very very hard", "to say"]),
+            "it was very very hard to say"
+        );
+        // The overlap heard one "very"; the speaker said it twice.
+        assert_eq!(words(&["it was very", "very very hard"]), "it was very very hard");
+    }
+}
+
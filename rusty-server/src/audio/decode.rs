@@ -0,0 +1,559 @@
+use super::validate::Container;
+use crate::AudioError;
+use log::debug;
+use rubato::{FftFixedIn, Resampler};
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, Packet};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+const RESAMPLE_CHUNK: usize = 1024;
+
+/// Decoded audio, downmixed to mono.
+pub struct Pcm {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// Decodes the first audio track of a WebM/Matroska, Ogg, MP4/M4A, MP3,
+/// FLAC or WAV file, Opus included when built with the `opus` feature.
+/// Codecs neither symphonia nor libopus handle are reported as
+/// `AudioError::UnsupportedCodec` so the caller can fall back to ffmpeg.
+/// Decoding stops with `AudioError::TooLong` once `max_duration` is passed.
+pub fn decode(bytes: Vec<u8>, container: Container, max_duration: Duration) -> Result<Pcm, AudioError> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(
+            Hint::new().with_extension(container.extension()),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| match e {
+            SymphoniaError::Unsupported(_) => {
+                AudioError::UnsupportedCodec("unrecognised container format".to_string())
+            }
+            e => AudioError::Decode(e.to_string()),
+        })?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| AudioError::Decode("no audio track".to_string()))?;
+    let track_id = track.id;
+    let mut sample_rate = match track.codec_params.codec {
+        #[cfg(feature = "opus")]
+        CODEC_TYPE_OPUS => Some(super::opus::SAMPLE_RATE),
+        _ => track.codec_params.sample_rate,
+    };
+    let too_long = |frames: u64, rate: u32| frames > (max_duration.as_secs_f64() * rate as f64) as u64;
+    // Trust the header's length when it has one, so a long file is turned
+    // away without decoding any of it.
+    if let (Some(frames), Some(rate)) = (track.codec_params.n_frames, sample_rate) {
+        if too_long(frames, rate) {
+            return Err(AudioError::TooLong { max: max_duration });
+        }
+    }
+    let mut decoder = TrackDecoder::new(&track.codec_params)?;
+
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(AudioError::Decode(e.to_string())),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        if let Some(rate) = decoder.decode(&packet, &mut samples)? {
+            sample_rate.get_or_insert(rate);
+        }
+        if sample_rate.is_some_and(|rate| too_long(samples.len() as u64, rate)) {
+            return Err(AudioError::TooLong { max: max_duration });
+        }
+    }
+
+    let sample_rate =
+        sample_rate.ok_or_else(|| AudioError::Decode("unknown sample rate".to_string()))?;
+    debug!("Decoded {} samples at {} Hz", samples.len(), sample_rate);
+    Ok(Pcm {
+        samples,
+        sample_rate,
+    })
+}
+
+/// Symphonia's decoder for a track's codec, or libopus for Opus.
+enum TrackDecoder {
+    Symphonia {
+        decoder: Box<dyn Decoder>,
+        buffer: Option<SampleBuffer<f32>>,
+    },
+    #[cfg(feature = "opus")]
+    Opus(super::opus::OpusDecoder),
+}
+
+impl TrackDecoder {
+    fn new(params: &CodecParameters) -> Result<Self, AudioError> {
+        if params.codec == CODEC_TYPE_OPUS {
+            #[cfg(feature = "opus")]
+            return super::opus::OpusDecoder::new(params).map(TrackDecoder::Opus);
+            #[cfg(not(feature = "opus"))]
+            return Err(AudioError::UnsupportedCodec("opus".to_string()));
+        }
+        let decoder = symphonia::default::get_codecs()
+            .make(params, &DecoderOptions::default())
+            .map_err(|e| match e {
+                SymphoniaError::Unsupported(what) => AudioError::UnsupportedCodec(what.to_string()),
+                e => AudioError::Decode(e.to_string()),
+            })?;
+        Ok(TrackDecoder::Symphonia {
+            decoder,
+            buffer: None,
+        })
+    }
+
+    /// Appends a packet's samples, downmixed to mono, and returns their
+    /// sample rate if the decoder reports one.
+    fn decode(&mut self, packet: &Packet, samples: &mut Vec<f32>) -> Result<Option<u32>, AudioError> {
+        match self {
+            TrackDecoder::Symphonia { decoder, buffer } => {
+                let decoded = match decoder.decode(packet) {
+                    Ok(decoded) => decoded,
+                    // A corrupt packet costs a few milliseconds of audio, not the request.
+                    Err(SymphoniaError::DecodeError(e)) => {
+                        debug!("Skipping undecodable packet: {}", e);
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(AudioError::Decode(e.to_string())),
+                };
+                let spec = *decoded.spec();
+                let channels = spec.channels.count().max(1);
+                let buffer = match buffer {
+                    Some(b) if b.capacity() >= decoded.capacity() * channels => b,
+                    _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+                };
+                buffer.copy_interleaved_ref(decoded);
+                samples.extend(
+                    buffer
+                        .samples()
+                        .chunks(channels)
+                        .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+                );
+                Ok(Some(spec.rate))
+            }
+            #[cfg(feature = "opus")]
+            TrackDecoder::Opus(decoder) => {
+                if let Err(e) = decoder.decode(&packet.data, samples) {
+                    debug!("Skipping undecodable packet: {}", e);
+                }
+                Ok(Some(super::opus::SAMPLE_RATE))
+            }
+        }
+    }
+}
+
+/// Resamples mono audio to `rate`, keeping its duration.
+pub fn resample(pcm: Pcm, rate: u32) -> Result<Vec<f32>, AudioError> {
+    if pcm.sample_rate == rate || pcm.samples.is_empty() {
+        return Ok(pcm.samples);
+    }
+    let resample_error = |e: &dyn std::fmt::Display| AudioError::Decode(format!("resampling failed: {}", e));
+    let mut resampler = FftFixedIn::<f32>::new(
+        pcm.sample_rate as usize,
+        rate as usize,
+        RESAMPLE_CHUNK,
+        2,
+        1,
+    )
+    .map_err(|e| resample_error(&e))?;
+
+    let expected = (pcm.samples.len() as u64 * rate as u64 / pcm.sample_rate as u64) as usize;
+    let delay = resampler.output_delay();
+    let mut output = Vec::with_capacity(expected + delay + RESAMPLE_CHUNK);
+    let mut chunks = pcm.samples.chunks_exact(RESAMPLE_CHUNK);
+    for chunk in &mut chunks {
+        let out = resampler.process(&[chunk], None).map_err(|e| resample_error(&e))?;
+        output.extend_from_slice(&out[0]);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        let out = resampler
+            .process_partial(Some(&[rest]), None)
+            .map_err(|e| resample_error(&e))?;
+        output.extend_from_slice(&out[0]);
+    }
+    // Flush what the filter still holds.
+    while output.len() < expected + delay {
+        let out = resampler
+            .process_partial::<&[f32]>(None, None)
+            .map_err(|e| resample_error(&e))?;
+        output.extend_from_slice(&out[0]);
+    }
+    output.drain(..delay);
+    output.truncate(expected);
+    Ok(output)
+}
+
+/// Opens a WAV with hound, first fixing the sizes a writer that cannot seek
+/// back (ffmpeg writing to a pipe, a browser streaming a recording) leaves
+/// as placeholders, which hound would otherwise reject.
+pub fn wav_reader(wav: &[u8]) -> Result<hound::WavReader<Cursor<Cow<'_, [u8]>>>, AudioError> {
+    hound::WavReader::new(Cursor::new(repair_streamed_header(wav)))
+        .map_err(|e| AudioError::Decode(e.to_string()))
+}
+
+/// Sets the `data` chunk's size, when it runs past the end of the file, to
+/// the whole samples actually there, and the RIFF size to match.
+fn repair_streamed_header(wav: &[u8]) -> Cow<'_, [u8]> {
+    if wav.len() < 12 || &wav[..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Cow::Borrowed(wav);
+    }
+    let mut block_align = 1;
+    let mut at = 12;
+    while at + 8 <= wav.len() {
+        let id = &wav[at..at + 4];
+        let size = u32::from_le_bytes([wav[at + 4], wav[at + 5], wav[at + 6], wav[at + 7]]) as usize;
+        let body = at + 8;
+        if id == b"fmt " && body + 14 <= wav.len() {
+            block_align = u16::from_le_bytes([wav[body + 12], wav[body + 13]]).max(1) as usize;
+        }
+        if id == b"data" {
+            let available = wav.len() - body;
+            if size <= available {
+                return Cow::Borrowed(wav);
+            }
+            let size = available - available % block_align;
+            debug!("Repairing streamed WAV header, {} bytes of samples", size);
+            let mut fixed = wav[..body + size].to_vec();
+            let riff_size = (fixed.len() - 8) as u32;
+            fixed[4..8].copy_from_slice(&riff_size.to_le_bytes());
+            fixed[at + 4..at + 8].copy_from_slice(&(size as u32).to_le_bytes());
+            return Cow::Owned(fixed);
+        }
+        at = body.saturating_add(size).saturating_add(size % 2);
+    }
+    Cow::Borrowed(wav)
+}
+
+/// Reads the samples of a 16-bit mono WAV.
+pub fn wav_samples(wav: &[u8]) -> Result<(Vec<f32>, u32), AudioError> {
+    let mut reader = wav_reader(wav)?;
+    let sample_rate = reader.spec().sample_rate;
+    let samples = reader
+        .samples::<i16>()
+        .map(|s| s.map(|s| s as f32 / i16::MAX as f32))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AudioError::Decode(e.to_string()))?;
+    Ok((samples, sample_rate))
+}
+
+/// Reads headerless 16-bit little-endian PCM.
+pub fn s16le_samples(pcm: &[u8]) -> Vec<f32> {
+    pcm.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+        .collect()
+}
+
+/// Encodes mono samples as a 16-bit PCM WAV.
+pub fn wav_bytes(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>, AudioError> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut bytes = Vec::new();
+    let mut writer = hound::WavWriter::new(Cursor::new(&mut bytes), spec)
+        .map_err(|e| AudioError::Decode(e.to_string()))?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        writer
+            .write_sample(value)
+            .map_err(|e| AudioError::Decode(e.to_string()))?;
+    }
+    writer
+        .finalize()
+        .map_err(|e| AudioError::Decode(e.to_string()))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, rate: u32, secs: f32) -> Vec<f32> {
+        (0..(rate as f32 * secs) as usize)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * freq * i as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    /// What ffmpeg's wav muxer writes to a pipe: both sizes left at the
+    /// 0xFFFFFFFF placeholder.
+    fn streamed(wav: &[u8]) -> Vec<u8> {
+        let mut wav = wav.to_vec();
+        wav[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        wav[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        wav
+    }
+
+    /// Frequency of a tone, from how often it crosses zero.
+    fn frequency(samples: &[f32], rate: u32) -> f32 {
+        let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        crossings as f32 * rate as f32 / samples.len() as f32
+    }
+
+    fn decode_to_24khz(bytes: Vec<u8>) -> Vec<f32> {
+        let container = Container::sniff(&bytes).unwrap();
+        let pcm = decode(bytes, container, Duration::from_secs(60)).unwrap();
+        resample(pcm, 24_000).unwrap()
+    }
+
+    #[test]
+    fn decodes_stereo_wav_to_24khz_mono() {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44_100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = Vec::new();
+        let mut writer = hound::WavWriter::new(Cursor::new(&mut wav), spec).unwrap();
+        for sample in sine(440.0, 44_100, 1.0) {
+            let value = (sample * i16::MAX as f32) as i16;
+            writer.write_sample(value).unwrap();
+            writer.write_sample(value).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let samples = decode_to_24khz(wav);
+        assert_eq!(samples.len(), 24_000);
+        assert!((frequency(&samples, 24_000) - 440.0).abs() < 2.0);
+        let peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!((peak - 0.5).abs() < 0.02, "peak {}", peak);
+    }
+
+    /// A 16-bit mono FLAC of verbatim (uncompressed) subframes.
+    fn flac(samples: &[f32], rate: u32) -> Vec<u8> {
+        const BLOCK: usize = 4096;
+        fn crc8(bytes: &[u8]) -> u8 {
+            bytes.iter().fold(0u8, |crc, byte| {
+                (0..8).fold(crc ^ byte, |c, _| if c & 0x80 != 0 { (c << 1) ^ 0x07 } else { c << 1 })
+            })
+        }
+        fn crc16(bytes: &[u8]) -> u16 {
+            bytes.iter().fold(0u16, |crc, byte| {
+                (0..8).fold(crc ^ ((*byte as u16) << 8), |c, _| {
+                    if c & 0x8000 != 0 { (c << 1) ^ 0x8005 } else { c << 1 }
+                })
+            })
+        }
+
+        let mut flac = b"fLaC".to_vec();
+        flac.extend_from_slice(&[0x80, 0, 0, 34]);
+        flac.extend_from_slice(&(BLOCK as u16).to_be_bytes());
+        flac.extend_from_slice(&(BLOCK as u16).to_be_bytes());
+        flac.extend_from_slice(&[0; 6]);
+        // Rate (20 bits), channels - 1 (3), bits per sample - 1 (5), then a
+        // 36-bit sample count.
+        let info = (rate as u64) << 44 | 15 << 36 | samples.len() as u64;
+        flac.extend_from_slice(&info.to_be_bytes());
+        flac.extend_from_slice(&[0; 16]);
+
+        for (number, block) in samples.chunks(BLOCK).enumerate() {
+            let mut frame = vec![0xFF, 0xF8, 0x70, 0x08, number as u8];
+            frame.extend_from_slice(&(block.len() as u16 - 1).to_be_bytes());
+            frame.push(crc8(&frame));
+            frame.push(0x02);
+            for sample in block {
+                frame.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_be_bytes());
+            }
+            frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+            flac.extend_from_slice(&frame);
+        }
+        flac
+    }
+
+    #[test]
+    fn decodes_flac() {
+        let samples = decode_to_24khz(flac(&sine(440.0, 16_000, 1.5), 16_000));
+        assert_eq!(samples.len(), 36_000);
+        assert!((frequency(&samples, 24_000) - 440.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn stops_decoding_past_max_duration() {
+        let bytes = flac(&sine(440.0, 16_000, 3.0), 16_000);
+        let result = decode(bytes, Container::Flac, Duration::from_secs(2));
+        assert!(matches!(result, Err(AudioError::TooLong { .. })));
+    }
+
+    #[cfg(feature = "opus")]
+    mod opus {
+        use super::*;
+        use audiopus::coder::Encoder;
+        use audiopus::{Application, Channels, SampleRate};
+
+        const PRE_SKIP: u16 = 312;
+
+        fn opus_head() -> Vec<u8> {
+            let mut head = b"OpusHead".to_vec();
+            head.extend_from_slice(&[1, 1]);
+            head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+            head.extend_from_slice(&48_000u32.to_le_bytes());
+            head.extend_from_slice(&[0, 0, 0]);
+            head
+        }
+
+        /// 20 ms Opus packets of a 48 kHz tone.
+        fn opus_packets(samples: &[f32]) -> Vec<Vec<u8>> {
+            let encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Audio).unwrap();
+            samples
+                .chunks_exact(960)
+                .map(|frame| {
+                    let mut packet = vec![0; 4000];
+                    let len = encoder.encode_float(frame, &mut packet).unwrap();
+                    packet.truncate(len);
+                    packet
+                })
+                .collect()
+        }
+
+        fn ogg_opus(samples: &[f32]) -> Vec<u8> {
+            use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+            let mut ogg = Vec::new();
+            let mut writer = PacketWriter::new(Cursor::new(&mut ogg));
+            writer.write_packet(opus_head().into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+            let mut tags = b"OpusTags".to_vec();
+            tags.extend_from_slice(&[0; 8]);
+            writer.write_packet(tags.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+            let packets = opus_packets(samples);
+            let count = packets.len();
+            for (i, packet) in packets.into_iter().enumerate() {
+                let end = if i + 1 == count {
+                    PacketWriteEndInfo::EndStream
+                } else {
+                    PacketWriteEndInfo::NormalPacket
+                };
+                writer.write_packet(packet.into(), 1, end, (i as u64 + 1) * 960).unwrap();
+            }
+            drop(writer);
+            ogg
+        }
+
+        /// An EBML element with an eight-byte size.
+        fn element(id: &[u8], body: &[u8]) -> Vec<u8> {
+            let mut element = id.to_vec();
+            element.push(0x01);
+            element.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+            element.extend_from_slice(body);
+            element
+        }
+
+        /// A WebM like a browser's MediaRecorder writes: one Opus track,
+        /// one cluster of SimpleBlocks.
+        fn webm_opus(samples: &[f32]) -> Vec<u8> {
+            let header = element(
+                &[0x1A, 0x45, 0xDF, 0xA3],
+                &[element(&[0x42, 0x86], &[1]), element(&[0x42, 0x82], b"webm")].concat(),
+            );
+            let info = element(
+                &[0x15, 0x49, 0xA9, 0x66],
+                &element(&[0x2A, 0xD7, 0xB1], &1_000_000u32.to_be_bytes()),
+            );
+            let audio = element(
+                &[0xE1],
+                &[
+                    element(&[0xB5], &48_000f64.to_be_bytes()),
+                    element(&[0x9F], &[1]),
+                ]
+                .concat(),
+            );
+            let track = element(
+                &[0xAE],
+                &[
+                    element(&[0xD7], &[1]),
+                    element(&[0x73, 0xC5], &[1]),
+                    element(&[0x83], &[2]),
+                    element(&[0x86], b"A_OPUS"),
+                    element(&[0x63, 0xA2], &opus_head()),
+                    audio,
+                ]
+                .concat(),
+            );
+            let tracks = element(&[0x16, 0x54, 0xAE, 0x6B], &track);
+            let mut cluster = element(&[0xE7], &[0]);
+            for (i, packet) in opus_packets(samples).into_iter().enumerate() {
+                // Track 1, timecode in ms relative to the cluster, keyframe.
+                let mut block = vec![0x81];
+                block.extend_from_slice(&(i as i16 * 20).to_be_bytes());
+                block.push(0x80);
+                block.extend_from_slice(&packet);
+                cluster.extend(element(&[0xA3], &block));
+            }
+            let cluster = element(&[0x1F, 0x43, 0xB6, 0x75], &cluster);
+            let segment = element(&[0x18, 0x53, 0x80, 0x67], &[info, tracks, cluster].concat());
+            [header, segment].concat()
+        }
+
+        fn check_tone(samples: &[f32]) {
+            // 1 s of 48 kHz less the pre-skip, at 24 kHz.
+            let expected = (48_000 - PRE_SKIP as usize) / 2;
+            assert!(samples.len().abs_diff(expected) <= 1, "{} samples", samples.len());
+            // Past the codec's fade-in.
+            assert!((frequency(&samples[2_400..], 24_000) - 440.0).abs() < 3.0);
+        }
+
+        #[test]
+        fn decodes_ogg_opus() {
+            check_tone(&decode_to_24khz(ogg_opus(&sine(440.0, 48_000, 1.0))));
+        }
+
+        #[test]
+        fn decodes_webm_opus() {
+            check_tone(&decode_to_24khz(webm_opus(&sine(440.0, 48_000, 1.0))));
+        }
+    }
+
+    #[test]
+    fn reads_wav_with_streamed_header() {
+        let samples = sine(440.0, 24_000, 0.5);
+        let wav = streamed(&wav_bytes(&samples, 24_000).unwrap());
+        assert!(hound::WavReader::new(Cursor::new(&wav)).is_err());
+
+        let (read, rate) = wav_samples(&wav).unwrap();
+        assert_eq!(rate, 24_000);
+        assert_eq!(read.len(), samples.len());
+        assert!(read.iter().zip(&samples).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn drops_partial_sample_at_end_of_streamed_wav() {
+        let mut wav = streamed(&wav_bytes(&sine(440.0, 24_000, 0.1), 24_000).unwrap());
+        wav.push(0x7f);
+        let (read, _) = wav_samples(&wav).unwrap();
+        assert_eq!(read.len(), 2_400);
+    }
+
+    #[test]
+    fn wraps_raw_pcm_as_wav() {
+        let pcm: Vec<u8> = [0i16, 16_384, -16_384, i16::MAX]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let wav = wav_bytes(&s16le_samples(&pcm), 24_000).unwrap();
+        let reader = hound::WavReader::new(Cursor::new(&wav)).unwrap();
+        let read: Vec<i16> = reader.into_samples().map(Result::unwrap).collect();
+        assert_eq!(read, [0, 16_384, -16_384, i16::MAX]);
+    }
+}
@@ -0,0 +1,367 @@
+use log::{debug, info};
+use realfft::num_complex::Complex;
+use realfft::RealFftPlanner;
+use std::env;
+use std::f64::consts::PI;
+
+const DEFAULT_HIGHPASS_HZ: f32 = 80.0;
+const DEFAULT_TARGET_LUFS: f32 = -23.0;
+
+// Noise suppression works on 512-sample Hann frames at 50% overlap, which
+// sum back to unity without a synthesis window.
+const FFT_LEN: usize = 512;
+const HOP: usize = FFT_LEN / 2;
+// Quietest share of frames taken as the noise estimate.
+const NOISE_FRACTION: f32 = 0.1;
+// Subtract somewhat more than the estimate, but never cut a bin by more
+// than 20 dB, to keep the residual noise from turning into chirps.
+const OVERSUBTRACTION: f32 = 2.0;
+const GAIN_FLOOR: f32 = 0.1;
+
+// Normalisation never boosts more than this, nor past this peak.
+const MAX_GAIN_DB: f32 = 30.0;
+const PEAK_CEILING_DB: f32 = -1.0;
+
+/// Cleanup applied to speech before transcription. Each stage is optional:
+/// `AUDIO_HIGHPASS_HZ` (default 80, `0` disables), `AUDIO_NOISE_SUPPRESSION`
+/// (on unless `0`, `false` or `no`) and `AUDIO_TARGET_LUFS` (default -23,
+/// `off` disables). Unparseable values fall back to the defaults.
+#[derive(Clone, Copy, Debug)]
+pub struct DspChain {
+    pub highpass_hz: Option<f32>,
+    pub noise_suppression: bool,
+    pub target_lufs: Option<f32>,
+}
+
+impl DspChain {
+    pub fn from_env() -> Self {
+        let highpass_hz = Some(
+            env::var("AUDIO_HIGHPASS_HZ")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_HIGHPASS_HZ),
+        )
+        .filter(|hz| *hz > 0.0);
+        let noise_suppression = !matches!(
+            env::var("AUDIO_NOISE_SUPPRESSION").as_deref(),
+            Ok("0" | "false" | "no")
+        );
+        let target_lufs = match env::var("AUDIO_TARGET_LUFS").as_deref() {
+            Ok("off") => None,
+            Ok(v) => Some(v.parse().unwrap_or(DEFAULT_TARGET_LUFS)),
+            Err(_) => Some(DEFAULT_TARGET_LUFS),
+        };
+        let chain = DspChain {
+            highpass_hz,
+            noise_suppression,
+            target_lufs,
+        };
+        info!(
+            "Audio preprocessing: high-pass {:?} Hz, noise suppression {}, target {:?} LUFS",
+            chain.highpass_hz, chain.noise_suppression, chain.target_lufs
+        );
+        chain
+    }
+
+    /// Runs the enabled stages in order: high-pass, noise suppression,
+    /// loudness normalisation.
+    pub fn apply(&self, samples: &mut [f32], sample_rate: u32) {
+        if let Some(hz) = self.highpass_hz {
+            Biquad::highpass(hz as f64, sample_rate as f64).run(samples);
+        }
+        if self.noise_suppression {
+            suppress_noise(samples);
+        }
+        if let Some(target) = self.target_lufs {
+            normalize_loudness(samples, sample_rate, target);
+        }
+    }
+}
+
+/// Second-order IIR section, transposed direct form II.
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    /// Butterworth high-pass.
+    fn highpass(cutoff: f64, rate: f64) -> Self {
+        let w0 = 2.0 * PI * cutoff / rate;
+        let alpha = w0.sin() / (2.0 * std::f64::consts::FRAC_1_SQRT_2);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b: [(1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+        }
+    }
+
+    /// The two BS.1770 K-weighting stages (head-related shelf, then
+    /// high-pass), derived for any sample rate.
+    fn k_weighting(rate: f64) -> [Self; 2] {
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+        [shelf, highpass]
+    }
+
+    fn run(&self, samples: &mut [f32]) {
+        let (mut z1, mut z2) = (0.0, 0.0);
+        for sample in samples {
+            let x = *sample as f64;
+            let y = self.b[0] * x + z1;
+            z1 = self.b[1] * x - self.a[0] * y + z2;
+            z2 = self.b[2] * x - self.a[1] * y;
+            *sample = y as f32;
+        }
+    }
+}
+
+fn hann() -> Vec<f32> {
+    (0..FFT_LEN)
+        .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f64 / FFT_LEN as f64).cos()) as f32)
+        .collect()
+}
+
+/// Spectral subtraction against a noise spectrum averaged over the
+/// quietest frames, which in a trimmed recording are the pauses.
+fn suppress_noise(samples: &mut [f32]) {
+    if samples.len() < FFT_LEN {
+        return;
+    }
+    let window = hann();
+    // Pad so the first and last samples get full overlap.
+    let mut padded = vec![0.0; HOP];
+    padded.extend_from_slice(samples);
+    padded.resize(padded.len().div_ceil(HOP) * HOP + HOP, 0.0);
+    let starts: Vec<usize> = (0..=padded.len() - FFT_LEN).step_by(HOP).collect();
+
+    let mut planner = RealFftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(FFT_LEN);
+    let inverse = planner.plan_fft_inverse(FFT_LEN);
+    let mut frame = forward.make_input_vec();
+    let mut spectrum = forward.make_output_vec();
+    let mut spectrum_of = |start: usize, spectrum: &mut Vec<Complex<f32>>| {
+        for ((f, s), w) in frame.iter_mut().zip(&padded[start..start + FFT_LEN]).zip(&window) {
+            *f = s * w;
+        }
+        // Lengths come from the planner, so this cannot fail.
+        forward.process(&mut frame, spectrum).ok();
+    };
+
+    let mut energies: Vec<(f32, usize)> = starts
+        .iter()
+        .map(|&start| (padded[start..start + FFT_LEN].iter().map(|s| s * s).sum(), start))
+        .collect();
+    energies.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let quiet = &energies[..((energies.len() as f32 * NOISE_FRACTION).ceil() as usize).max(1)];
+    let mut noise = vec![0.0f32; spectrum.len()];
+    for &(_, start) in quiet {
+        spectrum_of(start, &mut spectrum);
+        for (n, bin) in noise.iter_mut().zip(&spectrum) {
+            *n += bin.norm_sqr() / quiet.len() as f32;
+        }
+    }
+
+    let mut output = vec![0.0f32; padded.len()];
+    let mut restored = inverse.make_output_vec();
+    for &start in &starts {
+        spectrum_of(start, &mut spectrum);
+        for (bin, n) in spectrum.iter_mut().zip(&noise) {
+            let power = bin.norm_sqr().max(f32::MIN_POSITIVE);
+            let gain = (1.0 - OVERSUBTRACTION * n / power).max(GAIN_FLOOR * GAIN_FLOOR).sqrt();
+            *bin *= gain;
+        }
+        // The inverse transform rejects any imaginary part at DC or Nyquist.
+        spectrum[0].im = 0.0;
+        if let Some(last) = spectrum.last_mut() {
+            last.im = 0.0;
+        }
+        inverse.process(&mut spectrum, &mut restored).ok();
+        for (o, r) in output[start..start + FFT_LEN].iter_mut().zip(&restored) {
+            *o += r / FFT_LEN as f32;
+        }
+    }
+    samples.copy_from_slice(&output[HOP..HOP + samples.len()]);
+    debug!("Suppressed noise using {} quiet frames", quiet.len());
+}
+
+/// Integrated loudness in LUFS per ITU-R BS.1770: K-weighted mean square
+/// over 400 ms blocks every 100 ms, with the absolute (-70 LUFS) and
+/// relative (-10 LU) gates. `None` for silence.
+fn integrated_loudness(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    let mut weighted = samples.to_vec();
+    for stage in Biquad::k_weighting(sample_rate as f64) {
+        stage.run(&mut weighted);
+    }
+    let block = (sample_rate as usize * 4 / 10).min(weighted.len());
+    let step = (sample_rate as usize / 10).max(1);
+    if block == 0 {
+        return None;
+    }
+    let powers: Vec<f64> = (0..=weighted.len() - block)
+        .step_by(step)
+        .map(|start| {
+            weighted[start..start + block]
+                .iter()
+                .map(|s| (*s as f64).powi(2))
+                .sum::<f64>()
+                / block as f64
+        })
+        .collect();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean_above = |gate: f64| {
+        let gated: Vec<f64> = powers.iter().copied().filter(|p| loudness(*p) > gate).collect();
+        (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+    let relative_gate = loudness(mean_above(-70.0)?) - 10.0;
+    mean_above(relative_gate).map(|p| loudness(p) as f32)
+}
+
+/// Scales to `target` LUFS, holding peaks under -1 dBFS.
+fn normalize_loudness(samples: &mut [f32], sample_rate: u32, target: f32) {
+    let Some(loudness) = integrated_loudness(samples, sample_rate) else {
+        return;
+    };
+    let peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    let peak_headroom_db = PEAK_CEILING_DB - 20.0 * peak.max(f32::MIN_POSITIVE).log10();
+    let gain_db = (target - loudness).min(MAX_GAIN_DB).min(peak_headroom_db);
+    let gain = 10f32.powf(gain_db / 20.0);
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+    debug!(
+        "Loudness {:.1} LUFS, applied {:+.1} dB towards {:.1} LUFS",
+        loudness, gain_db, target
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 24_000;
+
+    fn sine(freq: f32, amplitude: f32, seconds: f32, rate: u32) -> Vec<f32> {
+        (0..(seconds * rate as f32) as usize)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    /// Uniform white noise from a fixed xorshift seed.
+    fn white_noise(amplitude: f32, len: usize) -> Vec<f32> {
+        let mut state: u32 = 0x9E37_79B9;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                amplitude * (state as f32 / u32::MAX as f32 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn db(ratio: f32) -> f32 {
+        20.0 * ratio.log10()
+    }
+
+    // Gain of the default high-pass at `freq`, once the filter has settled.
+    fn highpass_gain(freq: f32) -> f32 {
+        let mut samples = sine(freq, 0.5, 1.0, RATE);
+        let before = rms(&samples[RATE as usize / 2..]);
+        Biquad::highpass(DEFAULT_HIGHPASS_HZ as f64, RATE as f64).run(&mut samples);
+        rms(&samples[RATE as usize / 2..]) / before
+    }
+
+    #[test]
+    fn highpass_attenuates_mains_hum_and_passes_speech() {
+        // Second-order Butterworth at 80 Hz: about -8.8 dB at 50 Hz.
+        let hum = db(highpass_gain(50.0));
+        assert!((-10.0..-7.5).contains(&hum), "50 Hz gain {:.1} dB", hum);
+        let voice = db(highpass_gain(1000.0));
+        assert!(voice.abs() < 0.1, "1 kHz gain {:.2} dB", voice);
+    }
+
+    #[test]
+    fn noise_suppression_quietens_pauses_and_keeps_the_tone() {
+        // A tone in the middle second of two, with noise throughout, so the
+        // quietest frames are noise only, as in the pauses of speech.
+        let len = 2 * RATE as usize;
+        let (start, end) = (len / 4, 3 * len / 4);
+        let noise = white_noise(0.05, len);
+        let tone = sine(1000.0, 0.5, 2.0, RATE);
+        let mut samples: Vec<f32> = (0..len)
+            .map(|i| noise[i] + if (start..end).contains(&i) { tone[i] } else { 0.0 })
+            .collect();
+
+        suppress_noise(&mut samples);
+
+        let pause = RATE as usize / 10..start - RATE as usize / 10;
+        let noise_drop = db(rms(&samples[pause.clone()]) / rms(&noise[pause]));
+        assert!(noise_drop < -6.0, "noise only lowered {:.1} dB", noise_drop);
+
+        let middle = start + RATE as usize / 10..end - RATE as usize / 10;
+        let residual: Vec<f32> = middle.clone().map(|i| samples[i] - tone[i]).collect();
+        let tone_level = db(rms(&samples[middle.clone()]) / rms(&tone[middle.clone()]));
+        assert!(tone_level.abs() < 0.5, "tone changed {:.2} dB", tone_level);
+        let snr_before = db(rms(&tone[middle.clone()]) / rms(&noise[middle]));
+        let snr_after = db(0.5 / std::f32::consts::SQRT_2 / rms(&residual));
+        assert!(snr_after > snr_before + 6.0, "SNR {:.1} -> {:.1} dB", snr_before, snr_after);
+    }
+
+    #[test]
+    fn loudness_matches_the_bs1770_reference() {
+        // A full-scale 997 Hz sine reads -3.01 LUFS.
+        let loudness = integrated_loudness(&sine(997.0, 1.0, 3.0, 48_000), 48_000).unwrap();
+        assert!((loudness + 3.01).abs() < 0.05, "{:.3} LUFS", loudness);
+        assert!(integrated_loudness(&vec![0.0; 48_000], 48_000).is_none());
+    }
+
+    #[test]
+    fn normalizes_to_the_target_loudness() {
+        for (amplitude, target) in [(0.01, -23.0), (0.5, -23.0), (0.05, -16.0)] {
+            let mut samples = sine(440.0, amplitude, 3.0, RATE);
+            normalize_loudness(&mut samples, RATE, target);
+            let loudness = integrated_loudness(&samples, RATE).unwrap();
+            assert!(
+                (loudness - target).abs() < 0.1,
+                "amplitude {} reached {:.2} LUFS, not {}",
+                amplitude,
+                loudness,
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn normalization_holds_peaks_under_the_ceiling() {
+        let mut samples = sine(440.0, 0.1, 3.0, RATE);
+        normalize_loudness(&mut samples, RATE, 0.0);
+        let peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!((db(peak) - PEAK_CEILING_DB).abs() < 0.05, "peak {:.2} dBFS", db(peak));
+    }
+}
@@ -0,0 +1,245 @@
+use super::decode;
+use crate::AudioError;
+use log::{debug, error, warn};
+use std::env;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Limits concurrent ffmpeg processes to `FFMPEG_MAX_CONCURRENCY`
+/// (default: the number of CPUs).
+fn slots() -> &'static Semaphore {
+    static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+    SLOTS.get_or_init(|| {
+        let permits = env::var("FFMPEG_MAX_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(2, |n| n.get()));
+        debug!("Allowing {} concurrent ffmpeg processes", permits);
+        Semaphore::new(permits)
+    })
+}
+
+fn timeout() -> Duration {
+    Duration::from_secs(
+        env::var("FFMPEG_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TIMEOUT_SECS),
+    )
+}
+
+/// Converts anything ffmpeg understands to a 24 kHz mono 16-bit WAV. Output
+/// stops a second past `max_duration`, enough for the caller to see that
+/// the recording is too long without converting all of it.
+///
+/// ffmpeg is asked for raw samples rather than WAV: writing to a pipe it
+/// cannot seek back to fill in the header's sizes.
+pub async fn to_wav_24khz(audio_bytes: &[u8], max_duration: Duration) -> Result<Vec<u8>, AudioError> {
+    debug!("Converting audio to PCM with ffmpeg");
+    let max_secs = (max_duration + Duration::from_secs(1)).as_secs_f64().to_string();
+    let pcm = run(
+        audio_bytes,
+        &[
+            "-i", "pipe:0",
+            "-t", &max_secs,
+            "-ac", "1",
+            "-ar", "24000",
+            "-f", "s16le",
+            "-y",
+            "pipe:1",
+        ],
+    )
+    .await?;
+    debug!("PCM conversion successful, {} bytes of samples", pcm.len());
+    decode::wav_bytes(&decode::s16le_samples(&pcm), super::SAMPLE_RATE)
+}
+
+/// Encodes 24 kHz mono 16-bit PCM with the given ffmpeg codec and muxer,
+/// optionally resampled and at a set bitrate.
+pub async fn encode_pcm_24khz(
+    pcm: &[u8],
+    codec: &str,
+    muxer: &str,
+    sample_rate: Option<u32>,
+    bitrate_kbps: Option<u32>,
+) -> Result<Vec<u8>, AudioError> {
+    debug!("Encoding speech as {} with ffmpeg", codec);
+    let mut args = vec![
+        "-f".to_string(), "s16le".to_string(),
+        "-ar".to_string(), "24000".to_string(),
+        "-ac".to_string(), "1".to_string(),
+        "-i".to_string(), "pipe:0".to_string(),
+        "-c:a".to_string(), codec.to_string(),
+    ];
+    if let Some(rate) = sample_rate {
+        args.extend(["-ar".to_string(), rate.to_string()]);
+    }
+    if let Some(kbps) = bitrate_kbps {
+        args.extend(["-b:a".to_string(), format!("{}k", kbps)]);
+    }
+    args.extend(["-f".to_string(), muxer.to_string(), "-y".to_string(), "pipe:1".to_string()]);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run(pcm, &args).await
+}
+
+/// Runs ffmpeg over `input` and returns its output. Fails with
+/// `AudioError::Busy` instead of queueing when every slot is taken, and
+/// kills ffmpeg if it runs past `FFMPEG_TIMEOUT_SECS`.
+async fn run(input: &[u8], args: &[&str]) -> Result<Vec<u8>, AudioError> {
+    run_limited("ffmpeg", slots(), timeout(), input, args).await
+}
+
+/// `run` with the program, slots and time limit passed in.
+async fn run_limited(
+    program: &str,
+    slots: &Semaphore,
+    limit: Duration,
+    input: &[u8],
+    args: &[&str],
+) -> Result<Vec<u8>, AudioError> {
+    let _permit = slots.try_acquire().map_err(|_| {
+        warn!("All ffmpeg slots busy, rejecting conversion");
+        AudioError::Busy
+    })?;
+
+    let mut ffmpeg = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            error!("FFmpeg command failed: {}", e);
+            AudioError::FFmpeg(e.to_string())
+        })?;
+
+    // Feed stdin while draining stdout and stderr, so neither side can
+    // fill its pipe and stall the other.
+    let mut stdin = ffmpeg.stdin.take();
+    let write = async {
+        if let Some(stdin) = stdin.as_mut() {
+            stdin.write_all(input).await?;
+            stdin.shutdown().await?;
+        }
+        drop(stdin);
+        Ok::<_, std::io::Error>(())
+    };
+    let (written, output) = tokio::time::timeout(limit, async {
+        tokio::join!(write, ffmpeg.wait_with_output())
+    })
+    .await
+    .map_err(|_| {
+        // Dropping the child kills it.
+        error!("FFmpeg timed out after {:?}, killed", limit);
+        AudioError::FFmpegTimeout(limit)
+    })?;
+
+    let output = output.map_err(|e| {
+        error!("FFmpeg failed to complete: {}", e);
+        AudioError::FFmpeg(e.to_string())
+    })?;
+
+    let ffmpeg_stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("FFmpeg stderr: {}", ffmpeg_stderr);
+
+    if !output.status.success() {
+        error!("FFmpeg failed: {}", ffmpeg_stderr);
+        return Err(AudioError::FFmpeg(ffmpeg_stderr.to_string()));
+    }
+    // ffmpeg can close stdin once it has read enough; that only matters if
+    // it also failed, which was reported above.
+    if let Err(e) = written {
+        debug!("FFmpeg closed stdin early: {}", e);
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // ffmpeg itself isn't needed to exercise the plumbing: `cat` echoes its
+    // input and `sh` can stall or fail on demand.
+
+    #[tokio::test]
+    async fn full_slots_are_busy_rather_than_queued() {
+        let slots = Semaphore::new(1);
+        let _held = slots.try_acquire().unwrap();
+        let result = run_limited("cat", &slots, Duration::from_secs(5), b"", &[]).await;
+        assert!(matches!(result, Err(AudioError::Busy)), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn a_slot_is_released_when_the_run_ends() {
+        let slots = Semaphore::new(1);
+        for _ in 0..3 {
+            let output = run_limited("cat", &slots, Duration::from_secs(5), b"pcm", &[]).await.unwrap();
+            assert_eq!(output, b"pcm");
+        }
+        assert_eq!(slots.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn large_input_streams_through_without_deadlocking() {
+        // Far past a pipe's buffer: writing all of stdin before reading
+        // stdout would stall both sides until the timeout.
+        let input: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let slots = Semaphore::new(1);
+        let output = run_limited("cat", &slots, Duration::from_secs(30), &input, &[]).await.unwrap();
+        assert!(output == input, "output differs from input");
+    }
+
+    #[tokio::test]
+    async fn overrunning_processes_are_killed() {
+        let pid_file = env::temp_dir().join(format!("ffmpeg-timeout-{}.pid", std::process::id()));
+        let script = format!("echo $$ > {}; exec sleep 30", pid_file.display());
+        let slots = Semaphore::new(1);
+        let started = Instant::now();
+        let result = run_limited("sh", &slots, Duration::from_millis(300), b"", &["-c", &script]).await;
+        assert!(matches!(result, Err(AudioError::FFmpegTimeout(_))), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(slots.available_permits(), 1);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        // Killed either way; a zombie only means it hasn't been reaped yet.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match std::fs::read_to_string(&stat) {
+                Err(_) => break,
+                Ok(s) if s.rsplit(')').next().unwrap().trim_start().starts_with('Z') => break,
+                Ok(_) if Instant::now() > deadline => panic!("sleep {} still running", pid.trim()),
+                Ok(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_report_stderr() {
+        let slots = Semaphore::new(1);
+        let result = run_limited(
+            "sh",
+            &slots,
+            Duration::from_secs(5),
+            b"",
+            &["-c", "echo 'Invalid data found' >&2; exit 1"],
+        )
+        .await;
+        assert!(
+            matches!(&result, Err(AudioError::FFmpeg(stderr)) if stderr.contains("Invalid data found")),
+            "{:?}",
+            result
+        );
+    }
+}
+
@@ -0,0 +1,105 @@
+mod chunk;
+mod decode;
+mod dsp;
+mod ffmpeg;
+#[cfg(feature = "opus")]
+mod opus;
+mod output;
+mod validate;
+mod vad;
+
+pub use chunk::{split as split_for_transcription, stitch as stitch_transcripts};
+pub use dsp::DspChain;
+pub use output::{OutputFormat, OutputSpec};
+pub use validate::AudioLimits;
+
+use crate::AudioError;
+use log::{debug, info};
+use std::env;
+use std::time::Duration;
+use validate::Container;
+
+/// Sample rate of the PCM handed to transcription.
+pub const SAMPLE_RATE: u32 = 24_000;
+
+/// ffmpeg is tried when native decoding fails, unless
+/// `AUDIO_FFMPEG_FALLBACK` is `0`, `false` or `no`.
+fn ffmpeg_fallback() -> bool {
+    !matches!(
+        env::var("AUDIO_FFMPEG_FALLBACK").as_deref(),
+        Ok("0" | "false" | "no")
+    )
+}
+
+/// Trims silence from a 24 kHz WAV, or returns `None` if it holds no
+/// speech. Skipped, returning the WAV as is, when `AUDIO_VAD` is `0`,
+/// `false` or `no`.
+pub fn detect_speech(wav: Vec<u8>) -> Result<Option<Vec<u8>>, AudioError> {
+    if matches!(env::var("AUDIO_VAD").as_deref(), Ok("0" | "false" | "no")) {
+        return Ok(Some(wav));
+    }
+    vad::trim_silence(&wav)
+}
+
+/// Resamples a mono WAV to `rate`.
+pub fn resample_wav(wav: &[u8], rate: u32) -> Result<Vec<u8>, AudioError> {
+    let (samples, sample_rate) = decode::wav_samples(wav)?;
+    let samples = decode::resample(decode::Pcm { samples, sample_rate }, rate)?;
+    decode::wav_bytes(&samples, rate)
+}
+
+/// Runs `chain` over a WAV on the blocking pool.
+pub async fn preprocess(wav: Vec<u8>, chain: DspChain) -> Result<Vec<u8>, AudioError> {
+    tokio::task::spawn_blocking(move || {
+        let (mut samples, sample_rate) = decode::wav_samples(&wav)?;
+        chain.apply(&mut samples, sample_rate);
+        decode::wav_bytes(&samples, sample_rate)
+    })
+    .await
+    .map_err(|e| AudioError::Decode(e.to_string()))?
+}
+
+/// Converts an uploaded recording to a 24 kHz mono 16-bit WAV after
+/// checking it against `limits`. Decoding and resampling run on the
+/// blocking pool.
+pub async fn to_wav_24khz(bytes: Vec<u8>, limits: &AudioLimits) -> Result<Vec<u8>, AudioError> {
+    limits.check_size(bytes.len())?;
+    let container = Container::sniff(&bytes).ok_or(AudioError::UnrecognizedFormat)?;
+    debug!("Upload looks like {:?}, {} bytes", container, bytes.len());
+
+    let wav = convert_any(bytes, container, limits.max_duration).await?;
+    limits.check_duration(wav_duration(&wav)?)?;
+    Ok(wav)
+}
+
+async fn convert_any(bytes: Vec<u8>, container: Container, max_duration: Duration) -> Result<Vec<u8>, AudioError> {
+    let native = bytes.clone();
+    let decoded = tokio::task::spawn_blocking(move || convert(native, container, max_duration))
+        .await
+        .map_err(|e| AudioError::Decode(e.to_string()))?;
+    match decoded {
+        Ok(wav) => {
+            debug!("Decoded audio natively, WAV size: {} bytes", wav.len());
+            Ok(wav)
+        }
+        Err(e @ (AudioError::UnsupportedCodec(_) | AudioError::Decode(_))) if ffmpeg_fallback() => {
+            info!("Native decoding failed ({}), falling back to ffmpeg", e);
+            ffmpeg::to_wav_24khz(&bytes, max_duration).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn convert(bytes: Vec<u8>, container: Container, max_duration: Duration) -> Result<Vec<u8>, AudioError> {
+    decode::decode(bytes, container, max_duration)
+        .and_then(|pcm| decode::resample(pcm, SAMPLE_RATE))
+        .and_then(|samples| decode::wav_bytes(&samples, SAMPLE_RATE))
+}
+
+fn wav_duration(wav: &[u8]) -> Result<Duration, AudioError> {
+    let reader = decode::wav_reader(wav)?;
+    let spec = reader.spec();
+    Ok(Duration::from_secs_f64(
+        reader.duration() as f64 / spec.sample_rate as f64,
+    ))
+}
@@ -0,0 +1,68 @@
+use crate::AudioError;
+use audiopus::coder::Decoder;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals, SampleRate};
+use log::debug;
+use symphonia::core::codecs::CodecParameters;
+
+/// Opus always decodes at 48 kHz, whatever rate the recording was made at.
+pub const SAMPLE_RATE: u32 = 48_000;
+// Longest Opus packet: 120 ms.
+const MAX_PACKET_SAMPLES: usize = 5_760;
+
+/// Decodes the Opus packets symphonia's WebM and Ogg demuxers hand out,
+/// with libopus, as symphonia has no Opus decoder of its own. Output is
+/// mono: libopus downmixes stereo itself.
+pub struct OpusDecoder {
+    decoder: Decoder,
+    // Encoder warm-up at the start of the stream, still to be dropped.
+    pre_skip: usize,
+    buffer: Vec<f32>,
+}
+
+impl OpusDecoder {
+    /// Sets up a decoder from the track's `OpusHead`, which both demuxers
+    /// pass on as its extra data.
+    pub fn new(params: &CodecParameters) -> Result<Self, AudioError> {
+        let head = params
+            .extra_data
+            .as_deref()
+            .filter(|head| head.len() >= 19 && head.starts_with(b"OpusHead"))
+            .ok_or_else(|| AudioError::Decode("Opus track without an OpusHead".to_string()))?;
+        let channels = head[9];
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]) as usize;
+        let gain = i16::from_le_bytes([head[16], head[17]]);
+        let mapping = head[18];
+        // Surround streams are several Opus streams multiplexed, which
+        // would need libopus's multistream API.
+        if channels > 2 || mapping != 0 {
+            return Err(AudioError::UnsupportedCodec(format!("{}-channel opus", channels)));
+        }
+
+        let opus_error = |e: audiopus::Error| AudioError::Decode(format!("opus: {}", e));
+        let decoder = Decoder::new(SampleRate::Hz48000, Channels::Mono).map_err(opus_error)?;
+        // Both are Q7.8 dB.
+        decoder.set_gain(gain.into()).map_err(opus_error)?;
+        debug!("Decoding {}-channel Opus, pre-skip {}", channels, pre_skip);
+        Ok(OpusDecoder {
+            decoder,
+            pre_skip,
+            buffer: vec![0.0; MAX_PACKET_SAMPLES],
+        })
+    }
+
+    /// Appends the samples of one packet to `samples`.
+    pub fn decode(&mut self, packet: &[u8], samples: &mut Vec<f32>) -> Result<(), AudioError> {
+        let packet = Packet::try_from(packet).map_err(|e| AudioError::Decode(format!("opus: {}", e)))?;
+        let output = MutSignals::try_from(&mut self.buffer[..])
+            .map_err(|e| AudioError::Decode(format!("opus: {}", e)))?;
+        let decoded = self
+            .decoder
+            .decode_float(Some(packet), output, false)
+            .map_err(|e| AudioError::Decode(format!("opus: {}", e)))?;
+        let skipped = self.pre_skip.min(decoded);
+        self.pre_skip -= skipped;
+        samples.extend_from_slice(&self.buffer[skipped..decoded]);
+        Ok(())
+    }
+}
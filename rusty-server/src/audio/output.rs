@@ -0,0 +1,320 @@
+use super::decode::{self, Pcm};
+use super::ffmpeg;
+use crate::AudioError;
+use log::debug;
+use serde::Deserialize;
+
+// What the speech provider's raw `pcm` format is: 16-bit little-endian mono.
+const PROVIDER_PCM_RATE: u32 = 24_000;
+const DEFAULT_MULAW_RATE: u32 = 8_000;
+const OPUS_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+const MP3_RATES: [u32; 9] = [8_000, 11_025, 12_000, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000];
+// ffmpeg's native AAC encoder takes the same rates as LAME in this range.
+const AAC_RATES: [u32; 9] = MP3_RATES;
+// Rates a FLAC frame header can name directly.
+const FLAC_RATES: [u32; 7] = [8_000, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000];
+// Uncompressed output is resampled natively, so any rate in this range works.
+const PCM_RATES: std::ops::RangeInclusive<u32> = 8_000..=48_000;
+
+/// Encoding of the spoken reply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Mp3,
+    /// Opus in an Ogg container.
+    Opus,
+    /// AAC in ADTS frames.
+    Aac,
+    Flac,
+    /// 16-bit PCM WAV.
+    Wav,
+    /// Headerless 16-bit little-endian PCM.
+    Pcm,
+    /// G.711 mu-law WAV, 8 kHz unless a rate is given, for telephony.
+    Mulaw,
+}
+
+/// The format, sample rate and bitrate a client asked for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputSpec {
+    pub format: OutputFormat,
+    pub sample_rate: Option<u32>,
+    pub bitrate_kbps: Option<u32>,
+}
+
+impl OutputSpec {
+    pub fn validate(&self) -> Result<(), AudioError> {
+        let invalid = |message: String| Err(AudioError::InvalidOutput(message));
+        if let Some(rate) = self.sample_rate {
+            let allowed: &[u32] = match self.format {
+                OutputFormat::Opus => &OPUS_RATES,
+                OutputFormat::Mp3 => &MP3_RATES,
+                OutputFormat::Aac => &AAC_RATES,
+                OutputFormat::Flac => &FLAC_RATES,
+                OutputFormat::Wav | OutputFormat::Pcm | OutputFormat::Mulaw
+                    if PCM_RATES.contains(&rate) =>
+                {
+                    &[rate]
+                }
+                OutputFormat::Wav | OutputFormat::Pcm | OutputFormat::Mulaw => &[],
+            };
+            if !allowed.contains(&rate) {
+                return invalid(format!("{:?} output does not support {} Hz", self.format, rate));
+            }
+        }
+        if let Some(kbps) = self.bitrate_kbps {
+            if !matches!(self.format, OutputFormat::Mp3 | OutputFormat::Opus | OutputFormat::Aac) {
+                return invalid(format!("{:?} output has no bitrate to set", self.format));
+            }
+            if !(6..=320).contains(&kbps) {
+                return invalid(format!("Bitrate must be 6 to 320 kbps, not {}", kbps));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mime_type(&self) -> String {
+        match self.format {
+            OutputFormat::Mp3 => "audio/mpeg".to_string(),
+            OutputFormat::Opus => "audio/ogg; codecs=opus".to_string(),
+            OutputFormat::Aac => "audio/aac".to_string(),
+            OutputFormat::Flac => "audio/flac".to_string(),
+            OutputFormat::Wav | OutputFormat::Mulaw => "audio/wav".to_string(),
+            OutputFormat::Pcm => format!(
+                "audio/pcm; rate={}; encoding=s16le; channels=1",
+                self.sample_rate.unwrap_or(PROVIDER_PCM_RATE)
+            ),
+        }
+    }
+
+    /// Whether the provider can produce this exactly, with its own rate and
+    /// bitrate.
+    fn provider_native(&self) -> bool {
+        self.bitrate_kbps.is_none()
+            && self.format != OutputFormat::Mulaw
+            && self.sample_rate.is_none_or(|rate| {
+                rate == PROVIDER_PCM_RATE && matches!(self.format, OutputFormat::Wav | OutputFormat::Pcm)
+            })
+    }
+
+    /// The `response_format` to ask the speech provider for: the requested
+    /// format when it can deliver it as is, otherwise raw PCM to transcode.
+    pub fn provider_format(&self) -> &'static str {
+        if !self.provider_native() {
+            return "pcm";
+        }
+        match self.format {
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Opus => "opus",
+            OutputFormat::Aac => "aac",
+            OutputFormat::Flac => "flac",
+            OutputFormat::Wav => "wav",
+            OutputFormat::Pcm | OutputFormat::Mulaw => "pcm",
+        }
+    }
+
+    /// Turns what the provider returned for `provider_format` into the
+    /// requested output. Uncompressed formats are resampled natively; the
+    /// compressed ones are encoded with ffmpeg.
+    pub async fn render(&self, speech: Vec<u8>) -> Result<Vec<u8>, AudioError> {
+        if self.provider_native() {
+            return Ok(speech);
+        }
+        debug!("Transcoding {} bytes of speech PCM to {:?}", speech.len(), self.format);
+        let (codec, muxer) = match self.format {
+            OutputFormat::Mp3 => ("libmp3lame", "mp3"),
+            OutputFormat::Opus => ("libopus", "ogg"),
+            OutputFormat::Aac => ("aac", "adts"),
+            OutputFormat::Flac => ("flac", "flac"),
+            OutputFormat::Wav | OutputFormat::Pcm | OutputFormat::Mulaw => {
+                let spec = *self;
+                return tokio::task::spawn_blocking(move || spec.render_pcm(&speech))
+                    .await
+                    .map_err(|e| AudioError::Decode(e.to_string()))?;
+            }
+        };
+        ffmpeg::encode_pcm_24khz(&speech, codec, muxer, self.sample_rate, self.bitrate_kbps).await
+    }
+
+    fn render_pcm(&self, speech: &[u8]) -> Result<Vec<u8>, AudioError> {
+        let samples = decode::s16le_samples(speech);
+        let default_rate = match self.format {
+            OutputFormat::Mulaw => DEFAULT_MULAW_RATE,
+            _ => PROVIDER_PCM_RATE,
+        };
+        let rate = self.sample_rate.unwrap_or(default_rate);
+        let samples = decode::resample(
+            Pcm {
+                samples,
+                sample_rate: PROVIDER_PCM_RATE,
+            },
+            rate,
+        )?;
+        Ok(match self.format {
+            OutputFormat::Mulaw => mulaw_wav(&samples, rate),
+            OutputFormat::Pcm => samples.iter().flat_map(|s| to_i16(*s).to_le_bytes()).collect(),
+            _ => decode::wav_bytes(&samples, rate)?,
+        })
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+/// G.711 mu-law encoding of one sample.
+fn mulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32_635;
+    let magnitude = (sample as i32).abs().min(CLIP) + BIAS;
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let exponent = (31 - magnitude.leading_zeros() as i32 - 7).clamp(0, 7);
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+/// A mono WAV of mu-law samples. hound only writes PCM, so the header is
+/// built here: a non-PCM `fmt ` chunk needs `cbSize` and a `fact` chunk.
+fn mulaw_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data: Vec<u8> = samples.iter().map(|s| mulaw(to_i16(*s))).collect();
+    let padding = data.len() % 2;
+    let riff_len = 4 + (8 + 18) + (8 + 4) + (8 + data.len() + padding);
+    let mut wav = Vec::with_capacity(8 + riff_len);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(riff_len as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&18u32.to_le_bytes());
+    wav.extend_from_slice(&7u16.to_le_bytes()); // WAVE_FORMAT_MULAW
+    wav.extend_from_slice(&1u16.to_le_bytes()); // channels
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes()); // bytes per second
+    wav.extend_from_slice(&1u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&8u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(&0u16.to_le_bytes()); // cbSize
+    wav.extend_from_slice(b"fact");
+    wav.extend_from_slice(&4u32.to_le_bytes());
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&data);
+    wav.resize(wav.len() + padding, 0);
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(format: OutputFormat, sample_rate: Option<u32>, bitrate_kbps: Option<u32>) -> OutputSpec {
+        OutputSpec {
+            format,
+            sample_rate,
+            bitrate_kbps,
+        }
+    }
+
+    #[test]
+    fn validate_allows_only_rates_and_bitrates_the_encoder_takes() {
+        use OutputFormat::*;
+        let accepted = [
+            spec(Opus, Some(24_000), Some(32)),
+            spec(Mp3, Some(22_050), Some(320)),
+            spec(Aac, Some(44_100), Some(6)),
+            spec(Flac, Some(48_000), None),
+            spec(Wav, Some(9_000), None),
+            spec(Pcm, Some(48_000), None),
+            spec(Mulaw, Some(8_000), None),
+            spec(Mp3, None, None),
+        ];
+        for spec in accepted {
+            assert!(spec.validate().is_ok(), "{:?} was rejected", spec);
+        }
+        let rejected = [
+            spec(Opus, Some(44_100), None),
+            spec(Mp3, Some(9_000), None),
+            spec(Aac, Some(9_000), None),
+            spec(Flac, Some(9_000), None),
+            spec(Flac, Some(11_025), None),
+            spec(Pcm, Some(7_999), None),
+            spec(Wav, Some(96_000), None),
+            spec(Wav, None, Some(128)),
+            spec(Flac, None, Some(128)),
+            spec(Mp3, None, Some(5)),
+            spec(Opus, None, Some(321)),
+        ];
+        for spec in rejected {
+            assert!(
+                matches!(spec.validate(), Err(AudioError::InvalidOutput(_))),
+                "{:?} was accepted",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn provider_delivers_only_what_it_can_make_as_is() {
+        use OutputFormat::*;
+        let cases = [
+            (spec(Mp3, None, None), "mp3"),
+            (spec(Opus, None, None), "opus"),
+            (spec(Aac, None, None), "aac"),
+            (spec(Flac, None, None), "flac"),
+            (spec(Wav, None, None), "wav"),
+            (spec(Wav, Some(24_000), None), "wav"),
+            (spec(Pcm, Some(24_000), None), "pcm"),
+            // Anything else is transcoded from the provider's raw PCM.
+            (spec(Wav, Some(16_000), None), "pcm"),
+            (spec(Mp3, Some(24_000), None), "pcm"),
+            (spec(Opus, None, Some(24)), "pcm"),
+            (spec(Mulaw, None, None), "pcm"),
+        ];
+        for (spec, format) in cases {
+            assert_eq!(spec.provider_format(), format, "{:?}", spec);
+        }
+        assert!(spec(Pcm, None, None).provider_native());
+        assert!(!spec(Mulaw, None, None).provider_native());
+        assert!(!spec(Flac, Some(48_000), None).provider_native());
+    }
+
+    #[test]
+    fn mulaw_matches_g711() {
+        assert_eq!(mulaw(0), 0xFF);
+        assert_eq!(mulaw(i16::MAX), 0x80);
+        assert_eq!(mulaw(i16::MIN), 0x00);
+        assert_eq!(mulaw(-1), 0x7F);
+        // Tops of the first and sixth segments, once biased.
+        assert_eq!(mulaw(123), 0xF0);
+        assert_eq!(mulaw(-8031), 0x20);
+    }
+
+    #[test]
+    fn mulaw_wav_has_a_complete_non_pcm_header() {
+        let samples = [0.0, 1.0, -1.0];
+        let wav = mulaw_wav(&samples, 8_000);
+        let u16_at = |at: usize| u16::from_le_bytes([wav[at], wav[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([wav[at], wav[at + 1], wav[at + 2], wav[at + 3]]);
+
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 18);
+        assert_eq!(u16_at(20), 7);
+        assert_eq!(u16_at(22), 1);
+        assert_eq!(u32_at(24), 8_000);
+        assert_eq!(u32_at(28), 8_000);
+        assert_eq!(u16_at(32), 1);
+        assert_eq!(u16_at(34), 8);
+        assert_eq!(u16_at(36), 0);
+        assert_eq!(&wav[38..42], b"fact");
+        assert_eq!(u32_at(42), 4);
+        assert_eq!(u32_at(46), 3);
+        assert_eq!(&wav[50..54], b"data");
+        assert_eq!(u32_at(54), 3);
+        assert_eq!(&wav[58..61], [0xFF, 0x80, 0x00]);
+        // Odd-length data is padded to keep chunks word-aligned.
+        assert_eq!(wav.len(), 62);
+    }
+}
+
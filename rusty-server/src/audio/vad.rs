@@ -0,0 +1,167 @@
+use super::decode::{wav_bytes, wav_samples};
+use super::SAMPLE_RATE;
+use crate::AudioError;
+use log::debug;
+
+// 20 ms analysis frames.
+const FRAME: usize = SAMPLE_RATE as usize / 50;
+// Frames quieter than this are silence however quiet the room is.
+const ABSOLUTE_FLOOR_DB: f32 = -50.0;
+// Speech must stand this far above the recording's own noise floor.
+const MARGIN_DB: f32 = 12.0;
+// Without that much contrast anywhere, a recording is taken as speech from
+// end to end only if it is at least this loud; steady room tone is quieter.
+const SPEECH_LEVEL_DB: f32 = -35.0;
+// A run of loud frames shorter than this is a click or a bump, not speech.
+const MIN_SPEECH_FRAMES: usize = 10;
+// Kept either side of the speech so soft onsets and tails survive.
+const PADDING_FRAMES: usize = 10;
+
+fn frame_db(frame: &[f32]) -> f32 {
+    let energy = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+    10.0 * energy.max(1e-10).log10()
+}
+
+/// Finds speech in a 24 kHz mono WAV by frame energy against the
+/// recording's noise floor. Returns the WAV with leading and trailing
+/// silence trimmed, or `None` when nothing sounds like speech.
+pub fn trim_silence(wav: &[u8]) -> Result<Option<Vec<u8>>, AudioError> {
+    let (samples, _) = wav_samples(wav)?;
+
+    let levels: Vec<f32> = samples.chunks(FRAME).map(frame_db).collect();
+    if levels.is_empty() {
+        return Ok(None);
+    }
+    let mut sorted = levels.clone();
+    sorted.sort_by(f32::total_cmp);
+    let noise_floor = sorted[sorted.len() / 10];
+    let peak = sorted[sorted.len() - 1];
+    if peak - noise_floor < MARGIN_DB && noise_floor < SPEECH_LEVEL_DB {
+        debug!(
+            "No speech found: level is flat at about {:.1} dB (peak {:.1} dB)",
+            noise_floor, peak
+        );
+        return Ok(None);
+    }
+    // A recording that is speech from end to end has no quiet frames to
+    // measure against, so the margin is also taken down from the peak.
+    let threshold = (noise_floor + MARGIN_DB)
+        .min(peak - MARGIN_DB)
+        .max(ABSOLUTE_FLOOR_DB);
+
+    // From the first loud run long enough to be speech to the end of the last.
+    let mut speech: Option<(usize, usize)> = None;
+    let mut run_start = None;
+    for (i, level) in levels.iter().chain([&f32::MIN]).enumerate() {
+        match (*level >= threshold, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(start)) => {
+                if i - start >= MIN_SPEECH_FRAMES {
+                    speech = Some((speech.map_or(start, |(first, _)| first), i));
+                }
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    let Some((first, end)) = speech else {
+        debug!(
+            "No speech found (noise floor {:.1} dB, threshold {:.1} dB)",
+            noise_floor, threshold
+        );
+        return Ok(None);
+    };
+    let start = first.saturating_sub(PADDING_FRAMES) * FRAME;
+    let end = ((end + PADDING_FRAMES) * FRAME).min(samples.len());
+    debug!(
+        "Speech spans samples {}..{} of {}, threshold {:.1} dB",
+        start,
+        end,
+        samples.len(),
+        threshold
+    );
+    wav_bytes(&samples[start..end], SAMPLE_RATE).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: usize = SAMPLE_RATE as usize;
+
+    fn sine(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    /// Uniform white noise from a fixed xorshift seed.
+    fn white_noise(amplitude: f32, len: usize) -> Vec<f32> {
+        let mut state: u32 = 0x9E37_79B9;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                amplitude * (state as f32 / u32::MAX as f32 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    // Uniform noise with an RMS of -45 dBFS, like a quiet room.
+    fn room_tone(len: usize) -> Vec<f32> {
+        white_noise(10f32.powf(-45.0 / 20.0) * 3f32.sqrt(), len)
+    }
+
+    /// Length in samples of what `trim_silence` keeps, if anything.
+    fn kept(samples: &[f32]) -> Option<usize> {
+        let wav = wav_bytes(samples, SAMPLE_RATE).unwrap();
+        trim_silence(&wav)
+            .unwrap()
+            .map(|trimmed| wav_samples(&trimmed).unwrap().0.len())
+    }
+
+    #[test]
+    fn silence_has_no_speech() {
+        assert_eq!(kept(&vec![0.0; 2 * SECOND]), None);
+    }
+
+    #[test]
+    fn steady_room_tone_has_no_speech() {
+        assert_eq!(kept(&room_tone(3 * SECOND)), None);
+    }
+
+    #[test]
+    fn a_tone_in_room_tone_is_kept_with_padding() {
+        let mut samples = room_tone(3 * SECOND);
+        for (sample, tone) in samples[SECOND..2 * SECOND].iter_mut().zip(sine(440.0, 0.3, SECOND)) {
+            *sample += tone;
+        }
+        let kept = kept(&samples).expect("tone was not detected");
+        let padding = 2 * PADDING_FRAMES * FRAME;
+        assert!(
+            (SECOND..=SECOND + padding + FRAME).contains(&kept),
+            "kept {} samples",
+            kept
+        );
+    }
+
+    #[test]
+    fn speech_from_end_to_end_is_kept_whole() {
+        // A voice-like level moving at a syllable rate, and a flat loud tone.
+        let syllables: Vec<f32> = sine(220.0, 1.0, 2 * SECOND)
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                s * 0.3 * (0.6 + 0.4 * (2.0 * std::f32::consts::PI * 4.0 * t).sin())
+            })
+            .collect();
+        let flat = sine(220.0, 0.3, 2 * SECOND);
+        for samples in [syllables, flat] {
+            let kept = kept(&samples).expect("speech was not detected");
+            assert!(kept >= samples.len() * 9 / 10, "kept {} of {}", kept, samples.len());
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::AudioError;
+use log::info;
+use std::env;
+use std::time::Duration;
+
+const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_DURATION_SECS: u64 = 300;
+const DEFAULT_MIN_DURATION_MS: u64 = 300;
+
+/// Containers accepted for upload, recognised by their leading bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Wav,
+    Flac,
+    Ogg,
+    /// WebM and Matroska share the EBML header.
+    WebM,
+    Mp4,
+    Mp3,
+    Aac,
+}
+
+impl Container {
+    /// Identifies the container from its magic bytes.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Container::Wav),
+            [b'f', b'L', b'a', b'C', ..] => Some(Container::Flac),
+            [b'O', b'g', b'g', b'S', ..] => Some(Container::Ogg),
+            [0x1A, 0x45, 0xDF, 0xA3, ..] => Some(Container::WebM),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(Container::Mp4),
+            [b'I', b'D', b'3', ..] => Some(Container::Mp3),
+            // MPEG frame sync; layer bits 00 mark an AAC ADTS stream.
+            [0xFF, second, ..] if second & 0xF6 == 0xF0 => Some(Container::Aac),
+            [0xFF, second, ..] if second & 0xE0 == 0xE0 => Some(Container::Mp3),
+            _ => None,
+        }
+    }
+
+    /// File extension used as a hint for the demuxer.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Wav => "wav",
+            Container::Flac => "flac",
+            Container::Ogg => "ogg",
+            Container::WebM => "webm",
+            Container::Mp4 => "m4a",
+            Container::Mp3 => "mp3",
+            Container::Aac => "aac",
+        }
+    }
+}
+
+/// Bounds on uploaded audio, from `AUDIO_MAX_BYTES` (default 10 MiB),
+/// `AUDIO_MAX_DURATION_SECS` (default 5 minutes) and
+/// `AUDIO_MIN_DURATION_MS` (default 300 ms).
+#[derive(Clone, Copy, Debug)]
+pub struct AudioLimits {
+    pub max_bytes: usize,
+    pub max_duration: Duration,
+    pub min_duration: Duration,
+}
+
+impl AudioLimits {
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(name).ok().and_then(|v| v.parse().ok());
+        let limits = AudioLimits {
+            max_bytes: var("AUDIO_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES as u64) as usize,
+            max_duration: Duration::from_secs(
+                var("AUDIO_MAX_DURATION_SECS").unwrap_or(DEFAULT_MAX_DURATION_SECS),
+            ),
+            min_duration: Duration::from_millis(
+                var("AUDIO_MIN_DURATION_MS").unwrap_or(DEFAULT_MIN_DURATION_MS),
+            ),
+        };
+        info!(
+            "Audio uploads limited to {} bytes and {:?}..{:?}",
+            limits.max_bytes, limits.min_duration, limits.max_duration
+        );
+        limits
+    }
+
+    /// Largest JSON body needed to carry a maximal upload as base64.
+    pub fn max_json_bytes(&self) -> usize {
+        self.max_bytes.div_ceil(3) * 4 + 64 * 1024
+    }
+
+    /// Rejects a base64 payload that would decode to more than `max_bytes`,
+    /// before spending the memory to decode it.
+    pub fn check_base64_len(&self, len: usize) -> Result<(), AudioError> {
+        let size = len / 4 * 3;
+        if size > self.max_bytes + 2 {
+            return Err(AudioError::TooLarge {
+                size,
+                max: self.max_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_size(&self, size: usize) -> Result<(), AudioError> {
+        if size > self.max_bytes {
+            return Err(AudioError::TooLarge {
+                size,
+                max: self.max_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_duration(&self, duration: Duration) -> Result<(), AudioError> {
+        if duration > self.max_duration {
+            return Err(AudioError::TooLong {
+                max: self.max_duration,
+            });
+        }
+        if duration < self.min_duration {
+            return Err(AudioError::TooShort {
+                duration,
+                min: self.min_duration,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+    fn limits() -> AudioLimits {
+        AudioLimits {
+            max_bytes: 30,
+            max_duration: Duration::from_secs(300),
+            min_duration: Duration::from_millis(300),
+        }
+    }
+
+    #[test]
+    fn sniffs_each_container_by_its_magic_bytes() {
+        let cases: &[(&[u8], Option<Container>)] = &[
+            (b"RIFF\x24\x00\x00\x00WAVEfmt ", Some(Container::Wav)),
+            (b"fLaC\x00\x00\x00\x22", Some(Container::Flac)),
+            (b"OggS\x00\x02", Some(Container::Ogg)),
+            (b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81", Some(Container::WebM)),
+            (b"\x00\x00\x00\x20ftypM4A ", Some(Container::Mp4)),
+            (b"ID3\x04\x00", Some(Container::Mp3)),
+            (b"\xFF\xFB\x90\x64", Some(Container::Mp3)),
+            (b"\xFF\xF1\x50\x80", Some(Container::Aac)),
+            (b"\xFF\xF9\x50\x80", Some(Container::Aac)),
+            // A RIFF file that isn't audio, text, a bare sync byte, nothing.
+            (b"RIFF\x24\x00\x00\x00AVI LIST", None),
+            (b"hello, world", None),
+            (b"\xFF\x00\x00\x00", None),
+            (b"", None),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(Container::sniff(bytes), *expected, "sniffing {:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn size_is_checked_at_the_boundary() {
+        let limits = limits();
+        assert!(limits.check_size(30).is_ok());
+        assert!(matches!(
+            limits.check_size(31),
+            Err(AudioError::TooLarge { size: 31, max: 30 })
+        ));
+    }
+
+    #[test]
+    fn base64_length_is_checked_before_decoding() {
+        let limits = limits();
+        let encoded_len = |size: usize| STANDARD.encode(vec![0u8; size]).len();
+        assert!(limits.check_base64_len(encoded_len(30)).is_ok());
+        assert!(matches!(
+            limits.check_base64_len(encoded_len(31)),
+            Err(AudioError::TooLarge { max: 30, .. })
+        ));
+        assert!(limits.max_json_bytes() >= encoded_len(30));
+    }
+
+    #[test]
+    fn duration_is_checked_at_both_ends() {
+        let limits = limits();
+        assert!(limits.check_duration(Duration::from_secs(300)).is_ok());
+        assert!(limits.check_duration(Duration::from_millis(300)).is_ok());
+        assert!(matches!(
+            limits.check_duration(Duration::from_millis(300_001)),
+            Err(AudioError::TooLong { .. })
+        ));
+        assert!(matches!(
+            limits.check_duration(Duration::from_millis(299)),
+            Err(AudioError::TooShort { .. })
+        ));
+    }
+}
+
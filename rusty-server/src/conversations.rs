@@ -0,0 +1,211 @@
+use crate::auth::AuthenticatedUser;
+use crate::store::{ConversationStore, Cursor, StoreError, StoredMessage, Thread, ThreadUpdate};
+use actix_web::{get, patch, post, web, HttpResponse, Result as ActixResult};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const DEFAULT_TITLE: &str = "New conversation";
+const MAX_TITLE_LEN: usize = 200;
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+// Path id that addresses messages sent without a `conversation_id`.
+const DEFAULT_STREAM_ID: &str = "default";
+
+#[derive(Deserialize)]
+struct CreateThreadRequest {
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListThreadsQuery {
+    #[serde(default)]
+    include_archived: bool,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// A page of results, newest first. Pass `next_cursor` back as `cursor` to
+/// fetch the next (older) page; it is absent on the last page.
+#[derive(Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from up to `limit + 1` rows; the extra row only signals
+    /// that another page exists.
+    fn from_rows(mut rows: Vec<T>, limit: usize, cursor_of: impl Fn(&T) -> Cursor) -> Self {
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+        let next_cursor = if has_more {
+            rows.last().map(|last| cursor_of(last).encode())
+        } else {
+            None
+        };
+        Page {
+            items: rows,
+            next_cursor,
+        }
+    }
+}
+
+fn page_size(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+fn parse_cursor(cursor: Option<&str>) -> ActixResult<Option<Cursor>> {
+    cursor
+        .map(|c| Cursor::decode(c).ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid cursor")))
+        .transpose()
+}
+
+fn store_error(e: StoreError) -> actix_web::Error {
+    error!("Conversation store failed: {}", e);
+    actix_web::error::ErrorInternalServerError(e.to_string())
+}
+
+fn validate_title(title: &str) -> ActixResult<()> {
+    if title.trim().is_empty() || title.len() > MAX_TITLE_LEN {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Title must be 1-{} characters",
+            MAX_TITLE_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Looks up one of the user's threads, answering 404 for ids that are
+/// malformed, unknown, or belong to someone else.
+pub async fn find_thread(
+    store: &dyn ConversationStore,
+    user_id: &str,
+    thread_id: &str,
+) -> ActixResult<Thread> {
+    if Uuid::parse_str(thread_id).is_err() {
+        return Err(actix_web::error::ErrorNotFound("Conversation not found"));
+    }
+    store
+        .get_thread(user_id, thread_id)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Conversation not found"))
+}
+
+/// Checks that new messages may be added to `conversation_id`: it must be
+/// the user's own, unarchived thread. `None` is the default stream.
+pub async fn check_writable_thread(
+    store: &dyn ConversationStore,
+    user_id: &str,
+    conversation_id: Option<&str>,
+) -> ActixResult<()> {
+    if let Some(id) = conversation_id {
+        let thread = find_thread(store, user_id, id).await?;
+        if thread.archived {
+            return Err(actix_web::error::ErrorConflict(
+                "Conversation is archived",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[post("/conversations")]
+async fn create_conversation(
+    req: web::Json<CreateThreadRequest>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<HttpResponse> {
+    let title = req
+        .into_inner()
+        .title
+        .unwrap_or_else(|| DEFAULT_TITLE.to_string());
+    validate_title(&title)?;
+
+    let thread = store
+        .create_thread(&user.user_id, Thread::new(title))
+        .await
+        .map_err(store_error)?;
+    info!("Created conversation {} for user_id={}", thread.id, user.user_id);
+    Ok(HttpResponse::Created().json(thread))
+}
+
+#[get("/conversations")]
+async fn list_conversations(
+    query: web::Query<ListThreadsQuery>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<web::Json<Page<Thread>>> {
+    let limit = page_size(query.limit);
+    let before = parse_cursor(query.cursor.as_deref())?;
+    let threads = store
+        .list_threads(&user.user_id, query.include_archived, before.as_ref(), limit + 1)
+        .await
+        .map_err(store_error)?;
+    Ok(web::Json(Page::from_rows(threads, limit, |t| {
+        Cursor::new(&t.created_at, &t.id)
+    })))
+}
+
+/// Stored messages of a thread, newest first. `default` addresses the
+/// messages sent without a `conversation_id`.
+#[get("/conversations/{id}/messages")]
+async fn list_messages(
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<web::Json<Page<StoredMessage>>> {
+    let thread_id = path.into_inner();
+    let conversation_id = if thread_id == DEFAULT_STREAM_ID {
+        None
+    } else {
+        find_thread(store.get_ref(), &user.user_id, &thread_id).await?;
+        Some(thread_id.as_str())
+    };
+
+    let limit = page_size(query.limit);
+    let before = parse_cursor(query.cursor.as_deref())?;
+    if before.as_ref().is_some_and(|c| c.id.parse::<i64>().is_err()) {
+        return Err(actix_web::error::ErrorBadRequest("Invalid cursor"));
+    }
+
+    let messages = store
+        .messages(&user.user_id, conversation_id, before.as_ref(), limit + 1)
+        .await
+        .map_err(store_error)?;
+    Ok(web::Json(Page::from_rows(messages, limit, |m| {
+        Cursor::new(&m.timestamp, m.id)
+    })))
+}
+
+/// Renames and/or archives a thread: `{"title": "..."}`, `{"archived": true}`.
+#[patch("/conversations/{id}")]
+async fn update_conversation(
+    path: web::Path<String>,
+    req: web::Json<ThreadUpdate>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<web::Json<Thread>> {
+    let thread_id = path.into_inner();
+    let update = req.into_inner();
+    if let Some(title) = &update.title {
+        validate_title(title)?;
+    }
+
+    find_thread(store.get_ref(), &user.user_id, &thread_id).await?;
+    let thread = store
+        .update_thread(&user.user_id, &thread_id, update)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Conversation not found"))?;
+    info!("Updated conversation {} for user_id={}", thread.id, user.user_id);
+    Ok(web::Json(thread))
+}
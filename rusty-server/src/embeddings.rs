@@ -0,0 +1,395 @@
+use crate::store::{ChatMessage, ConversationStore, Cursor, StoredMessage};
+use crate::tasks::UserTasks;
+use crate::AudioError;
+use futures::future::{ready, BoxFuture};
+use log::{debug, error, info};
+use reqwest::Client;
+use serde_json::json;
+use std::env;
+use std::sync::Arc;
+
+const RECALL_LIMIT: usize = 4;
+// Matches weaker than this are noise more often than not.
+const MIN_SIMILARITY: f32 = 0.3;
+const MAX_RECALLED_CHARS: usize = 500;
+const LOCAL_DIMENSIONS: usize = 256;
+
+/// Turns text into vectors for similarity search. One provider is chosen at
+/// startup and registered as `web::Data<dyn EmbeddingProvider>`.
+pub trait EmbeddingProvider: Send + Sync {
+    /// Names the vector space. Vectors are only compared with others from
+    /// the same model, so switching providers starts a fresh index.
+    fn model(&self) -> &str;
+
+    /// One vector per input text, in order.
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>, AudioError>>;
+}
+
+/// OpenAI's embeddings endpoint (`text-embedding-3-small` unless
+/// `EMBEDDING_MODEL` says otherwise).
+pub struct OpenAIEmbeddings {
+    client: Client,
+    model: String,
+}
+
+impl OpenAIEmbeddings {
+    pub fn from_env() -> Self {
+        OpenAIEmbeddings {
+            client: Client::new(),
+            model: env::var("EMBEDDING_MODEL").unwrap_or_else(|_| "text-embedding-3-small".to_string()),
+        }
+    }
+
+    async fn request(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AudioError> {
+        let api_key = env::var("OPENAI_API_KEY")
+            .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
+
+        let response = self
+            .client
+            .post("https://api.openai.com/v1/embeddings")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .json(&json!({
+                "model": self.model,
+                "input": texts,
+            }))
+            .send()
+            .await
+            .map_err(AudioError::Http)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Embeddings API failed: status={}, error={}", status, error_text);
+            return Err(AudioError::OpenAI(format!("Embeddings API failed: {}", error_text)));
+        }
+
+        let json: serde_json::Value = response.json().await.map_err(AudioError::Http)?;
+        let data = json["data"]
+            .as_array()
+            .ok_or_else(|| AudioError::OpenAI("No data in Embeddings API".to_string()))?;
+        let mut vectors = vec![Vec::new(); texts.len()];
+        for item in data {
+            let index = item["index"].as_u64().unwrap_or(0) as usize;
+            let vector = item["embedding"]
+                .as_array()
+                .map(|v| v.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect())
+                .unwrap_or_default();
+            if let Some(slot) = vectors.get_mut(index) {
+                *slot = vector;
+            }
+        }
+        Ok(vectors)
+    }
+}
+
+impl EmbeddingProvider for OpenAIEmbeddings {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>, AudioError>> {
+        Box::pin(self.request(texts))
+    }
+}
+
+/// Deterministic bag-of-words embeddings from feature hashing. No network
+/// and no model, so it suits tests and offline development; similarity is
+/// purely lexical.
+pub struct HashEmbeddings {
+    model: String,
+    dimensions: usize,
+}
+
+impl HashEmbeddings {
+    pub fn new(dimensions: usize) -> Self {
+        HashEmbeddings {
+            model: format!("local-hash-{}", dimensions),
+            dimensions,
+        }
+    }
+
+    // FNV-1a, fixed so vectors stay comparable across builds.
+    fn hash(word: &str) -> u64 {
+        word.bytes().fold(0xcbf29ce484222325, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    fn vector(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0f32; self.dimensions];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+        {
+            let h = Self::hash(&word.to_lowercase());
+            let sign = if h >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(h % self.dimensions as u64) as usize] += sign;
+        }
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+impl EmbeddingProvider for HashEmbeddings {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>, AudioError>> {
+        Box::pin(ready(Ok(texts.iter().map(|t| self.vector(t)).collect())))
+    }
+}
+
+/// Picks the provider named by `EMBEDDING_PROVIDER` (`openai` or `local`),
+/// defaulting to OpenAI.
+pub fn provider_from_env() -> Result<Arc<dyn EmbeddingProvider>, AudioError> {
+    let provider: Arc<dyn EmbeddingProvider> = match env::var("EMBEDDING_PROVIDER")
+        .unwrap_or_else(|_| "openai".to_string())
+        .as_str()
+    {
+        "openai" => Arc::new(OpenAIEmbeddings::from_env()),
+        "local" => Arc::new(HashEmbeddings::new(LOCAL_DIMENSIONS)),
+        other => {
+            return Err(AudioError::Config(format!(
+                "Unknown EMBEDDING_PROVIDER '{}'; use openai or local",
+                other
+            )))
+        }
+    };
+    info!("Using embedding model: {}", provider.model());
+    Ok(provider)
+}
+
+/// Older messages from any of the user's threads that resemble `message`,
+/// leaving out those already in `history`. Retrieval only adds context, so
+/// failures are logged and yield nothing.
+pub async fn recall(
+    store: &dyn ConversationStore,
+    embedder: &dyn EmbeddingProvider,
+    user_id: &str,
+    message: &str,
+    history: &[ChatMessage],
+) -> Vec<StoredMessage> {
+    let query = match embedder.embed(&[message.to_string()]).await {
+        Ok(mut vectors) => vectors.pop().unwrap_or_default(),
+        Err(e) => {
+            error!("Embedding the message failed, skipping recall: {}", e);
+            return Vec::new();
+        }
+    };
+    let matches = match store
+        .similar_messages(user_id, embedder.model(), &query, RECALL_LIMIT + history.len())
+        .await
+    {
+        Ok(matches) => matches,
+        Err(e) => {
+            error!("Similarity search failed, skipping recall: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let recalled: Vec<StoredMessage> = matches
+        .into_iter()
+        .filter(|m| m.score >= MIN_SIMILARITY)
+        .filter(|m| {
+            !history
+                .iter()
+                .any(|h| h.role == m.message.role && h.content == m.message.content)
+        })
+        .take(RECALL_LIMIT)
+        .map(|m| m.message)
+        .collect();
+    debug!("Recalled {} older messages for user_id={}", recalled.len(), user_id);
+    recalled
+}
+
+/// System prompt section quoting recalled messages, oldest first.
+pub fn prompt_section(recalled: &[StoredMessage]) -> Option<String> {
+    if recalled.is_empty() {
+        return None;
+    }
+    let mut recalled: Vec<&StoredMessage> = recalled.iter().collect();
+    recalled.sort_by(|a, b| (&a.timestamp, a.id).cmp(&(&b.timestamp, b.id)));
+    let lines = recalled
+        .iter()
+        .map(|m| {
+            let content = match m.content.char_indices().nth(MAX_RECALLED_CHARS) {
+                Some((end, _)) => format!("{}…", &m.content[..end]),
+                None => m.content.clone(),
+            };
+            let date = m.timestamp.get(..10).unwrap_or(&m.timestamp);
+            format!("- [{}] {}: {}", date, m.role, content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(format!(
+        "RELATED MOMENTS FROM EARLIER CONVERSATIONS (may be from other threads):\n{}",
+        lines
+    ))
+}
+
+/// Embeds freshly stored messages in the background so later turns can
+/// recall them.
+pub fn spawn_index(
+    tasks: &UserTasks,
+    store: Arc<dyn ConversationStore>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    user_id: String,
+    messages: Vec<StoredMessage>,
+) {
+    // Nothing to index when the write was queued for later delivery.
+    if messages.is_empty() {
+        return;
+    }
+    tasks.spawn(&user_id.clone(), async move {
+        let texts: Vec<String> = messages.iter().map(|m| m.content.clone()).collect();
+        let result = match embedder.embed(&texts).await {
+            Ok(vectors) => {
+                let embeddings = messages.iter().map(|m| m.id).zip(vectors).collect();
+                store
+                    .put_embeddings(&user_id, embedder.model(), embeddings)
+                    .await
+                    .map_err(AudioError::from)
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Indexing messages failed for user_id={}: {}", user_id, e);
+        }
+    });
+}
+
+/// Indexes turns the store writes late, such as queued writes replayed
+/// after an outage. They are read back through `store`, which decrypts
+/// them if it needs to, before being embedded.
+pub fn index_deferred_writes(
+    store: &Arc<dyn ConversationStore>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    tasks: Arc<UserTasks>,
+) {
+    let weak = Arc::downgrade(store);
+    store.on_deferred_write(Arc::new(move |user_id, conversation_id, written| {
+        let (Some(store), Some(newest)) = (weak.upgrade(), written.iter().max_by_key(|m| m.id)) else {
+            return;
+        };
+        // Every message of a turn shares one timestamp, so the page just
+        // below the newest one's successor is exactly the turn.
+        let cursor = Cursor::new(&newest.timestamp, newest.id + 1);
+        let user_id = user_id.to_string();
+        let conversation_id = conversation_id.map(str::to_string);
+        let embedder = embedder.clone();
+        let owner = tasks.clone();
+        tasks.spawn(&user_id.clone(), async move {
+            match store
+                .messages(&user_id, conversation_id.as_deref(), Some(&cursor), written.len())
+                .await
+            {
+                Ok(messages) => spawn_index(&owner, store, embedder, user_id, messages),
+                Err(e) => error!("Reading replayed messages failed for user_id={}: {}", user_id, e),
+            }
+        });
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryStore, Turn};
+    use std::time::Duration;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    /// A store holding two indexed turns in different threads, and the
+    /// embedder that indexed them.
+    async fn indexed_store() -> (Arc<dyn ConversationStore>, Arc<dyn EmbeddingProvider>) {
+        let store: Arc<dyn ConversationStore> = Arc::new(MemoryStore::new());
+        let embedder: Arc<dyn EmbeddingProvider> = Arc::new(HashEmbeddings::new(LOCAL_DIMENSIONS));
+        let tasks = UserTasks::new();
+        let turns = [
+            (Some("pets"), "my dog Biscuit loves the park", "Biscuit sounds lovely"),
+            (None, "deadlines at work are stressful", "That sounds hard"),
+        ];
+        let mut indexed = 0;
+        for (thread, question, answer) in turns {
+            let turn = Turn::new(vec![message("user", question), message("assistant", answer)]);
+            let stored = store.append_turn("u1", thread, turn).await.unwrap();
+            indexed += stored.len();
+            spawn_index(&tasks, store.clone(), embedder.clone(), "u1".to_string(), stored);
+        }
+
+        // Indexing runs in the background; wait until every vector is in.
+        let probe = vec![0.0; LOCAL_DIMENSIONS];
+        for _ in 0..100 {
+            let found = store
+                .similar_messages("u1", embedder.model(), &probe, usize::MAX)
+                .await
+                .unwrap();
+            if found.len() == indexed {
+                return (store, embedder);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("messages were not indexed");
+    }
+
+    #[test]
+    fn provider_from_env_rejects_unknown_providers() {
+        env::set_var("EMBEDDING_PROVIDER", "local");
+        let local = provider_from_env().unwrap();
+        assert_eq!(local.model(), HashEmbeddings::new(LOCAL_DIMENSIONS).model());
+        env::set_var("EMBEDDING_PROVIDER", "lcoal");
+        let err = provider_from_env().err().unwrap();
+        env::remove_var("EMBEDDING_PROVIDER");
+        assert!(matches!(err, AudioError::Config(_)));
+    }
+
+    fn contents(recalled: &[StoredMessage]) -> Vec<&str> {
+        recalled.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn recalls_related_messages_from_other_threads() {
+        let (store, embedder) = indexed_store().await;
+        let recalled = recall(store.as_ref(), embedder.as_ref(), "u1", "how is my dog Biscuit", &[]).await;
+        let recalled = contents(&recalled);
+        assert!(recalled.contains(&"my dog Biscuit loves the park"));
+        assert!(!recalled.contains(&"deadlines at work are stressful"));
+    }
+
+    #[tokio::test]
+    async fn leaves_out_messages_already_in_history() {
+        let (store, embedder) = indexed_store().await;
+        let history = [message("user", "my dog Biscuit loves the park")];
+        let recalled = recall(store.as_ref(), embedder.as_ref(), "u1", "how is my dog Biscuit", &history).await;
+        assert!(!contents(&recalled).contains(&"my dog Biscuit loves the park"));
+    }
+
+    #[tokio::test]
+    async fn only_compares_vectors_from_the_same_model() {
+        let (store, _) = indexed_store().await;
+        let other = HashEmbeddings::new(LOCAL_DIMENSIONS / 2);
+        let recalled = recall(store.as_ref(), &other, "u1", "how is my dog Biscuit", &[]).await;
+        assert!(recalled.is_empty());
+        // Nor across users.
+        let embedder = HashEmbeddings::new(LOCAL_DIMENSIONS);
+        assert!(recall(store.as_ref(), &embedder, "u2", "how is my dog Biscuit", &[]).await.is_empty());
+    }
+
+    #[test]
+    fn hash_embeddings_are_normalized_and_lexical() {
+        let embedder = HashEmbeddings::new(LOCAL_DIMENSIONS);
+        let a = embedder.vector("my dog Biscuit");
+        let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+        assert_eq!(a, embedder.vector("My DOG, biscuit!"));
+        assert!(embedder.vector("").iter().all(|x| *x == 0.0));
+    }
+}
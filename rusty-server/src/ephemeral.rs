@@ -0,0 +1,175 @@
+use crate::store::ChatMessage;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TTL_SECS: u64 = 1800;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// Oldest messages are dropped past this; the prompt budget trims further.
+const MAX_SESSION_MESSAGES: usize = 200;
+
+struct Session {
+    messages: Vec<ChatMessage>,
+    touched: Instant,
+}
+
+/// Off-the-record conversations, held only in this process's memory and
+/// forgotten `EPHEMERAL_TTL_SECS` (default 30 minutes) after their last
+/// turn. Keyed by user and thread, `None` being the default stream.
+pub struct EphemeralSessions {
+    sessions: Mutex<HashMap<(String, Option<String>), Session>>,
+    ttl: Duration,
+}
+
+impl EphemeralSessions {
+    pub fn from_env() -> Self {
+        let ttl = env::var("EPHEMERAL_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+        info!("Ephemeral conversations expire after {} seconds idle", ttl);
+        EphemeralSessions {
+            sessions: Mutex::new(HashMap::new()),
+            ttl: Duration::from_secs(ttl),
+        }
+    }
+
+    fn key(user_id: &str, conversation_id: Option<&str>) -> (String, Option<String>) {
+        (user_id.to_string(), conversation_id.map(str::to_string))
+    }
+
+    /// The live session for `key`, started afresh if there is none or it
+    /// has expired.
+    fn live_session<'a>(
+        &self,
+        sessions: &'a mut HashMap<(String, Option<String>), Session>,
+        key: (String, Option<String>),
+    ) -> &'a mut Session {
+        let session = sessions.entry(key).or_insert_with(|| Session {
+            messages: Vec::new(),
+            touched: Instant::now(),
+        });
+        if session.touched.elapsed() >= self.ttl {
+            session.messages.clear();
+        }
+        session.touched = Instant::now();
+        session
+    }
+
+    /// Whether a turn is off the record. An explicit flag wins; `true`
+    /// starts the session right away, so a follow-up stays off the record
+    /// even if this turn fails, and `false` ends any live session. Without
+    /// one, a thread stays ephemeral while its session lives, so a
+    /// conversation is never half stored.
+    pub fn resolve(&self, user_id: &str, conversation_id: Option<&str>, requested: Option<bool>) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = Self::key(user_id, conversation_id);
+        match requested {
+            Some(true) => {
+                self.live_session(&mut sessions, key);
+                true
+            }
+            Some(false) => {
+                if sessions.remove(&key).is_some() {
+                    debug!("Ended ephemeral session for user_id={}", user_id);
+                }
+                false
+            }
+            None => sessions
+                .get(&key)
+                .is_some_and(|s| s.touched.elapsed() < self.ttl),
+        }
+    }
+
+    /// The session's messages, oldest first; empty if it has expired.
+    pub fn history(&self, user_id: &str, conversation_id: Option<&str>) -> Vec<ChatMessage> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&Self::key(user_id, conversation_id))
+            .filter(|s| s.touched.elapsed() < self.ttl)
+            .map(|s| s.messages.clone())
+            .unwrap_or_default()
+    }
+
+    /// Adds a turn, starting the session if needed.
+    pub fn append(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+        user_message: String,
+        assistant_message: String,
+    ) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = self.live_session(&mut sessions, Self::key(user_id, conversation_id));
+        session.messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: user_message,
+        });
+        session.messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content: assistant_message,
+        });
+        let excess = session.messages.len().saturating_sub(MAX_SESSION_MESSAGES);
+        session.messages.drain(..excess);
+    }
+
+    /// Ends all of `user_id`'s sessions, returning how many there were.
+    pub fn forget_user(&self, user_id: &str) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|(user, _), _| user != user_id);
+        before - sessions.len()
+    }
+
+    fn sweep(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, s| s.touched.elapsed() < self.ttl);
+        if sessions.len() < before {
+            debug!("Dropped {} expired ephemeral sessions", before - sessions.len());
+        }
+    }
+}
+
+/// Periodically frees expired sessions; lookups already ignore them.
+pub fn spawn_sweeper(sessions: Arc<EphemeralSessions>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            sessions.sweep();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_failed_first_turn_still_keeps_the_thread_off_the_record() {
+        let sessions = EphemeralSessions::from_env();
+        assert!(sessions.resolve("u1", Some("t1"), Some(true)));
+        // The turn fails before anything is appended.
+        assert!(sessions.resolve("u1", Some("t1"), None));
+        assert!(!sessions.resolve("u1", Some("t2"), None));
+
+        assert!(!sessions.resolve("u1", Some("t1"), Some(false)));
+        assert!(!sessions.resolve("u1", Some("t1"), None));
+    }
+
+    #[test]
+    fn forgetting_a_user_ends_only_their_sessions() {
+        let sessions = EphemeralSessions::from_env();
+        sessions.append("u1", None, "hi".to_string(), "hello".to_string());
+        sessions.append("u1", Some("t1"), "hi".to_string(), "hello".to_string());
+        sessions.append("u2", None, "hi".to_string(), "hello".to_string());
+
+        assert_eq!(sessions.forget_user("u1"), 2);
+        assert!(sessions.history("u1", None).is_empty());
+        assert!(!sessions.resolve("u1", Some("t1"), None));
+        assert_eq!(sessions.history("u2", None).len(), 2);
+    }
+}
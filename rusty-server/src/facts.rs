@@ -0,0 +1,263 @@
+use crate::auth::AuthenticatedUser;
+use crate::store::{ConversationStore, Fact, SuppressedFact};
+use crate::tasks::UserTasks;
+use crate::AudioError;
+use actix_web::{delete, get, web, HttpResponse, Result as ActixResult};
+use log::{debug, error, info};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Facts beyond this are not stored; the user can prune them via the API.
+const MAX_FACTS_PER_USER: usize = 200;
+const MAX_PROMPT_FACTS: usize = 15;
+const MAX_EXCERPT_CHARS: usize = 280;
+const CATEGORIES: &[&str] = &["person", "goal", "trigger", "preference", "health", "other"];
+
+const EXTRACTION_INSTRUCTIONS: &str = r#"You extract durable facts about the user from one turn of a therapy conversation, for use in future sessions. Durable facts stay true beyond today: names and roles of people in their life, ongoing goals, known triggers, preferences about how they like to be supported, health conditions, and anything they ask to be remembered. Ignore passing moods, small talk, anything already listed under KNOWN FACTS, and anything listed under FORGOTTEN FACTS, which the user asked you to forget, however it is worded. Write each fact as one short third-person sentence about "the user". Reply with JSON: {"facts": [{"category": "person|goal|trigger|preference|health|other", "content": "..."}]}, using an empty list when there is nothing new."#;
+
+#[derive(Deserialize)]
+struct Extraction {
+    #[serde(default)]
+    facts: Vec<ExtractedFact>,
+}
+
+#[derive(Deserialize)]
+struct ExtractedFact {
+    category: String,
+    content: String,
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn excerpt(message: &str) -> String {
+    match message.char_indices().nth(MAX_EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", &message[..end]),
+        None => message.to_string(),
+    }
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Picks the facts worth showing the model for `message`: those sharing the
+/// most words with it, then the most recent. Small sets are used whole.
+pub fn relevant(mut facts: Vec<Fact>, message: &str) -> Vec<Fact> {
+    if facts.len() > MAX_PROMPT_FACTS {
+        let message_words = words(message);
+        // Stable sort keeps newer facts ahead among equal scores.
+        facts.reverse();
+        facts.sort_by_cached_key(|f| {
+            std::cmp::Reverse(words(&f.content).intersection(&message_words).count())
+        });
+        facts.truncate(MAX_PROMPT_FACTS);
+    }
+    facts
+}
+
+/// System prompt section listing what we remember about the user.
+pub fn prompt_section(facts: &[Fact]) -> Option<String> {
+    if facts.is_empty() {
+        return None;
+    }
+    let lines = facts
+        .iter()
+        .map(|f| format!("- {}", f.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(format!(
+        "WHAT YOU KNOW ABOUT THE USER (from earlier conversations; use naturally, don't recite):\n{}",
+        lines
+    ))
+}
+
+/// Starts a background extraction of new facts from one user/assistant turn.
+pub fn spawn_extract(
+    tasks: &UserTasks,
+    store: Arc<dyn ConversationStore>,
+    user_id: String,
+    conversation_id: Option<String>,
+    user_message: String,
+    assistant_message: String,
+) {
+    tasks.spawn(&user_id.clone(), async move {
+        if let Err(e) = extract(
+            store.as_ref(),
+            &user_id,
+            conversation_id,
+            &user_message,
+            &assistant_message,
+        )
+        .await
+        {
+            error!("Fact extraction failed for user_id={}: {}", user_id, e);
+        }
+    });
+}
+
+async fn extract(
+    store: &dyn ConversationStore,
+    user_id: &str,
+    conversation_id: Option<String>,
+    user_message: &str,
+    assistant_message: &str,
+) -> Result<(), AudioError> {
+    let known = store.list_facts(user_id).await?;
+    if known.len() >= MAX_FACTS_PER_USER {
+        debug!("user_id={} has {} facts, not extracting more", user_id, known.len());
+        return Ok(());
+    }
+
+    // Facts the user deleted stay out, even when mentioned again.
+    let suppressed = store.suppressed_facts(user_id).await?;
+    let extracted = request_facts(&known, &suppressed, user_message, assistant_message).await?;
+
+    let mut seen: HashSet<String> = known
+        .iter()
+        .map(|f| normalize(&f.content))
+        .chain(suppressed.iter().map(|f| normalize(&f.content)))
+        .collect();
+    let source_excerpt = excerpt(user_message);
+    let new_facts: Vec<Fact> = extracted
+        .into_iter()
+        .filter(|f| !f.content.trim().is_empty() && seen.insert(normalize(&f.content)))
+        .take(MAX_FACTS_PER_USER - known.len())
+        .map(|f| {
+            let category = if CATEGORIES.contains(&f.category.as_str()) {
+                f.category
+            } else {
+                "other".to_string()
+            };
+            Fact::new(
+                category,
+                f.content.trim().to_string(),
+                conversation_id.clone(),
+                source_excerpt.clone(),
+            )
+        })
+        .collect();
+    if new_facts.is_empty() {
+        return Ok(());
+    }
+
+    let count = new_facts.len();
+    store.add_facts(user_id, new_facts).await?;
+    info!("Stored {} new facts for user_id={}", count, user_id);
+    Ok(())
+}
+
+fn fact_list<'a>(contents: impl Iterator<Item = &'a str>) -> String {
+    let list = contents
+        .map(|c| format!("- {}", c))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if list.is_empty() {
+        "(none)".to_string()
+    } else {
+        list
+    }
+}
+
+async fn request_facts(
+    known: &[Fact],
+    suppressed: &[SuppressedFact],
+    user_message: &str,
+    assistant_message: &str,
+) -> Result<Vec<ExtractedFact>, AudioError> {
+    let client = Client::new();
+    let api_key = env::var("OPENAI_API_KEY")
+        .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
+
+    let input = format!(
+        "KNOWN FACTS:\n{}\n\nFORGOTTEN FACTS:\n{}\n\nUSER: {}\nASSISTANT: {}",
+        fact_list(known.iter().map(|f| f.content.as_str())),
+        fact_list(suppressed.iter().map(|f| f.content.as_str())),
+        user_message,
+        assistant_message
+    );
+
+    let response = client
+        .post("https://api.openai.com/v1/chat/completions")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&json!({
+            "model": "gpt-4o-mini",
+            "messages": [
+                {"role": "system", "content": EXTRACTION_INSTRUCTIONS},
+                {"role": "user", "content": input}
+            ],
+            "response_format": {"type": "json_object"},
+            "temperature": 0
+        }))
+        .send()
+        .await
+        .map_err(AudioError::Http)?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        error!("Fact extraction API failed: status={}, error={}", status, error_text);
+        return Err(AudioError::OpenAI(format!(
+            "Fact extraction API failed: {}",
+            error_text
+        )));
+    }
+
+    let json: serde_json::Value = response.json().await.map_err(AudioError::Http)?;
+    let content = json["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or_else(|| AudioError::OpenAI("No extraction in Chat API".to_string()))?;
+    let extraction: Extraction = serde_json::from_str(content)
+        .map_err(|e| AudioError::OpenAI(format!("Malformed fact extraction: {}", e)))?;
+    Ok(extraction.facts)
+}
+
+/// Everything the assistant remembers about the caller, oldest first.
+#[get("/me/facts")]
+async fn list_facts(
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<web::Json<Vec<Fact>>> {
+    let facts = store.list_facts(&user.user_id).await.map_err(|e| {
+        error!("Failed to list facts: {}", e);
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+    Ok(web::Json(facts))
+}
+
+/// Forgets one remembered fact, for good: it is not extracted again.
+#[delete("/me/facts/{id}")]
+async fn delete_fact(
+    path: web::Path<String>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<HttpResponse> {
+    let fact_id = path.into_inner();
+    if Uuid::parse_str(&fact_id).is_err() {
+        return Err(actix_web::error::ErrorNotFound("Fact not found"));
+    }
+    let deleted = store
+        .delete_fact(&user.user_id, &fact_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to delete fact: {}", e);
+            actix_web::error::ErrorInternalServerError(e.to_string())
+        })?;
+    if !deleted {
+        return Err(actix_web::error::ErrorNotFound("Fact not found"));
+    }
+    info!("Deleted fact {} for user_id={}", fact_id, user.user_id);
+    Ok(HttpResponse::NoContent().finish())
+}
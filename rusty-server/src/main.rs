@@ -1,22 +1,40 @@
+mod account;
+mod audio;
+mod auth;
+mod conversations;
+mod embeddings;
+mod ephemeral;
+mod facts;
+mod prompt;
+mod retention;
+mod store;
+mod summarizer;
+mod tasks;
+mod transcription;
+mod upload;
+
 use actix_cors::Cors;
 use actix_web::{
     get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder, Result as ActixResult,
-    dev::Payload, FromRequest,
 };
+use auth::AuthenticatedUser;
 use base64::{engine::general_purpose, Engine as _};
 use dotenvy::dotenv;
 use handlebars::Handlebars;
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::json;
 use std::env;
 use std::io;
-use std::process::{Command, Stdio};
+use std::sync::Arc;
 use thiserror::Error;
 use reqwest::Client; // Async client
-use reqwest::blocking::Client as BlockingClient; // Blocking client for auth
-use chrono::Utc;
-use futures::future::{ready, Ready};
+use embeddings::EmbeddingProvider;
+use futures::{StreamExt, TryStreamExt};
+use ephemeral::EphemeralSessions;
+use tasks::UserTasks;
+use transcription::SpeechToText;
+use store::{ChatMessage, ConversationStore, Cursor, Fact, StoreError, StoredMessage, Turn};
 
 #[derive(Error, Debug)]
 enum AudioError {
@@ -24,30 +42,100 @@ enum AudioError {
     Io(#[from] io::Error),
     #[error("Base64 decode error: {0}")]
     Base64(#[from] base64::DecodeError),
+    #[error("Audio configuration error: {0}")]
+    Config(String),
     #[error("FFmpeg error: {0}")]
     FFmpeg(String),
+    #[error("FFmpeg timed out after {0:?}")]
+    FFmpegTimeout(std::time::Duration),
+    #[error("Audio conversion is at capacity, try again shortly")]
+    Busy,
+    #[error("Audio decode error: {0}")]
+    Decode(String),
+    #[error("Unsupported audio codec: {0}")]
+    UnsupportedCodec(String),
+    #[error("Audio exceeds the {max} byte limit (at least {size} bytes)")]
+    TooLarge { size: usize, max: usize },
+    #[error("Unrecognised audio format; send WAV, FLAC, Ogg, WebM, MP4/M4A, MP3 or AAC")]
+    UnrecognizedFormat,
+    #[error("Audio is longer than {max:?}")]
+    TooLong { max: std::time::Duration },
+    #[error("Audio is {duration:?} long, under the {min:?} minimum")]
+    TooShort {
+        duration: std::time::Duration,
+        min: std::time::Duration,
+    },
+    #[error("Invalid output: {0}")]
+    InvalidOutput(String),
     #[error("Invalid language")]
     InvalidLanguage,
     #[error("OpenAI API error: {0}")]
     OpenAI(String),
+    #[error("Transcription server error: {0}")]
+    Transcription(String),
     #[error("HTTP error: {0}")]
     Http(#[from] reqwest::Error),
+    #[error("Conversation store error: {0}")]
+    Store(#[from] StoreError),
 }
 
 #[derive(Deserialize)]
 struct AudioRequest {
-    audio: String,
+    audio: String, // Base64-encoded recording
+    #[serde(flatten)]
+    options: AudioOptions,
+}
+
+/// Everything about a voice turn except the recording, which may arrive
+/// as JSON, multipart fields or query parameters (see `upload`).
+#[derive(Deserialize)]
+struct AudioOptions {
     language: String,
+    #[serde(default)]
     genz_mode: bool,
+    #[serde(default)]
     sarcastic_mode: bool,
+    #[serde(default)]
     shenanigan_mode: bool,
+    #[serde(default)]
     seductive_mode: bool,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// Off the record: see `ChatRequest::ephemeral`.
+    #[serde(default)]
+    ephemeral: Option<bool>,
+    /// Encoding of the spoken reply; MP3 at the provider's defaults unless
+    /// set.
+    #[serde(default)]
+    output_format: audio::OutputFormat,
+    #[serde(default)]
+    output_sample_rate: Option<u32>,
+    #[serde(default)]
+    output_bitrate_kbps: Option<u32>,
+}
+
+impl AudioOptions {
+    fn output_spec(&self) -> audio::OutputSpec {
+        audio::OutputSpec {
+            format: self.output_format,
+            sample_rate: self.output_sample_rate,
+            bitrate_kbps: self.output_bitrate_kbps,
+        }
+    }
+}
+
+fn serialize_base64<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&general_purpose::STANDARD.encode(bytes))
 }
 
 #[derive(Serialize)]
 struct AudioResponse {
-    audio: String,        // Base64-encoded MP3 of GPT's response
+    #[serde(serialize_with = "serialize_base64")]
+    audio: Vec<u8>,        // Spoken GPT response, base64 in JSON
+    mime_type: String,     // Encoding of `audio`
     response_text: String, // Text of GPT's response
+    ephemeral: bool,
+    no_speech: bool, // Nothing was heard, so nothing was sent to GPT or stored
 }
 
 #[derive(Deserialize)]
@@ -58,210 +146,133 @@ struct ChatRequest {
     sarcastic_mode: bool,
     shenanigan_mode: bool,
     seductive_mode: bool,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// Off the record: the turn is kept only in server memory and nothing
+    /// is written to the store or read from it; stored facts and recalled
+    /// messages are left out of the reply. Once set, later requests to the same thread
+    /// stay off the record until the session expires or `false` is sent.
+    #[serde(default)]
+    ephemeral: Option<bool>,
 }
 
 #[derive(Serialize)]
 struct ChatResponse {
     response: String,
+    /// Whether this turn was off the record.
+    ephemeral: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-struct ChatMessage {
-    role: String,
-    content: String,
-}
-
-#[derive(Debug)]
-struct AuthenticatedUser {
-    user_id: String,
-}
-
-impl FromRequest for AuthenticatedUser {
-    type Error = actix_web::Error;
-    type Future = Ready<Result<Self, Self::Error>>;
-
-    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let auth_header = match req.headers().get("Authorization") {
-            Some(header) => header.to_str().unwrap_or(""),
-            None => {
-                return ready(Err(actix_web::error::ErrorUnauthorized(
-                    "Missing Authorization header",
-                )))
-            }
-        };
-
-        if !auth_header.starts_with("Bearer ") {
-            return ready(Err(actix_web::error::ErrorUnauthorized(
-                "Invalid Authorization header",
-            )));
+// Rows fetched per round trip while reading back a thread's history.
+const HISTORY_PAGE_SIZE: usize = 50;
+
+/// The thread's recent messages, oldest first: as many as the whole prompt
+/// budget could hold, for `prompt::fit_history` to trim to what actually
+/// fits next to the system prompt.
+async fn get_conversation_history(
+    store: &dyn ConversationStore,
+    user_id: &str,
+    conversation_id: Option<&str>,
+) -> Result<Vec<ChatMessage>, AudioError> {
+    debug!(
+        "Fetching conversation history for user_id: {}, conversation_id: {:?}",
+        user_id, conversation_id
+    );
+    let budget = prompt::token_budget();
+    let mut history = Vec::new();
+    let mut tokens = 0;
+    let mut before: Option<Cursor> = None;
+    loop {
+        let page = store
+            .messages(user_id, conversation_id, before.as_ref(), HISTORY_PAGE_SIZE)
+            .await?;
+        let exhausted = page.len() < HISTORY_PAGE_SIZE;
+        for msg in page {
+            tokens += prompt::message_tokens(&msg.content);
+            before = Some(Cursor::new(&msg.timestamp, msg.id));
+            history.push(ChatMessage {
+                role: msg.role,
+                content: msg.content,
+            });
+        }
+        if exhausted || tokens >= budget {
+            break;
         }
-
-        let token = &auth_header[7..]; // Skip "Bearer "
-        let client = BlockingClient::new();
-        let supabase_key = env::var("SUPABASE_KEY").unwrap_or_default();
-        let supabase_url = env::var("SUPABASE_URL").unwrap_or_default();
-
-        // Call Supabase Auth API synchronously
-        let response = client
-            .get(format!("{}/auth/v1/user", supabase_url))
-            .header("Authorization", format!("Bearer {}", token))
-            .header("apikey", &supabase_key)
-            .send();
-
-        ready(match response {
-            Ok(res) if res.status().is_success() => {
-                let json: Value = res.json().unwrap_or_default();
-                let user_id = json["id"].as_str().unwrap_or_default().to_string();
-                if user_id.is_empty() {
-                    Err(actix_web::error::ErrorUnauthorized("Invalid token"))
-                } else {
-                    Ok(AuthenticatedUser { user_id })
-                }
-            }
-            _ => Err(actix_web::error::ErrorUnauthorized(
-                "Invalid or expired token",
-            )),
-        })
-    }
-}
-
-async fn get_conversation_history(user_id: &str) -> Result<Vec<ChatMessage>, AudioError> {
-    debug!("Fetching conversation history for user_id: {}", user_id);
-    let client = Client::new();
-    let supabase_key = env::var("SUPABASE_KEY")
-        .map_err(|e| AudioError::OpenAI(format!("Missing SUPABASE_KEY: {}", e)))?;
-    let supabase_url = env::var("SUPABASE_URL")
-        .map_err(|e| AudioError::OpenAI(format!("Missing SUPABASE_URL: {}", e)))?;
-
-    let response = client
-        .get(format!(
-            "{}/rest/v1/conversations?select=message&user_id=eq.{}&order=timestamp.desc&limit=10",
-            supabase_url, user_id
-        ))
-        .header("apikey", &supabase_key)
-        .header("Authorization", format!("Bearer {}", supabase_key))
-        .send()
-        .await
-        .map_err(|e| AudioError::Http(e))?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("Supabase fetch failed: status={}, error={}", status, error_text);
-        return Err(AudioError::OpenAI(format!(
-            "Supabase fetch failed: {}",
-            error_text
-        )));
     }
-
-    let messages: Vec<Value> = response.json().await.map_err(|e| AudioError::Http(e))?;
-    let history: Vec<ChatMessage> = messages
-        .into_iter()
-        .filter_map(|item| serde_json::from_value(item["message"].clone()).ok())
-        .collect();
-
+    history.reverse();
     debug!("Retrieved {} messages from history", history.len());
-    Ok(history.into_iter().rev().collect()) // Reverse to chronological order
+    Ok(history)
 }
 
-async fn store_conversation(user_id: &str, message: ChatMessage) -> Result<(), AudioError> {
+/// Stores a user message and the assistant's reply as one turn, so the
+/// history never holds half an exchange.
+async fn store_conversation(
+    store: &dyn ConversationStore,
+    user_id: &str,
+    conversation_id: Option<&str>,
+    user_message: String,
+    assistant_message: String,
+) -> Result<Vec<StoredMessage>, AudioError> {
     debug!(
-        "Storing conversation for user_id: {}, role: {}",
-        user_id, message.role
+        "Storing conversation turn for user_id: {}, conversation_id: {:?}",
+        user_id, conversation_id
     );
-    let client = Client::new();
-    let supabase_key = env::var("SUPABASE_KEY")
-        .map_err(|e| AudioError::OpenAI(format!("Missing SUPABASE_KEY: {}", e)))?;
-    let supabase_url = env::var("SUPABASE_URL")
-        .map_err(|e| AudioError::OpenAI(format!("Missing SUPABASE_URL: {}", e)))?;
-
-    let response = client
-        .post(format!("{}/rest/v1/conversations", supabase_url))
-        .header("apikey", &supabase_key)
-        .header("Authorization", format!("Bearer {}", supabase_key))
-        .header("Content-Type", "application/json")
-        .json(&json!({
-            "user_id": user_id,
-            "message": message,
-            "timestamp": Utc::now().to_rfc3339(),
-        }))
-        .send()
-        .await
-        .map_err(|e| AudioError::Http(e))?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("Supabase store failed: status={}, error={}", status, error_text);
-        return Err(AudioError::OpenAI(format!(
-            "Supabase store failed: {}",
-            error_text
-        )));
-    }
-
+    let turn = Turn::new(vec![
+        ChatMessage {
+            role: "user".to_string(),
+            content: user_message,
+        },
+        ChatMessage {
+            role: "assistant".to_string(),
+            content: assistant_message,
+        },
+    ]);
+    let stored = store.append_turn(user_id, conversation_id, turn).await?;
     debug!("Conversation stored successfully");
-    Ok(())
+    Ok(stored)
 }
 
-fn convert_audio_to_pcm16_24khz(audio_base64: &str) -> Result<Vec<u8>, AudioError> {
-    debug!("Converting WebM to PCM in memory");
-    let audio_bytes = general_purpose::STANDARD
-        .decode(audio_base64)
-        .map_err(|e| {
-            error!("Base64 decode failed: {}", e);
-            AudioError::Base64(e)
-        })?;
-
-    let mut ffmpeg = Command::new("ffmpeg")
-        .args([
-            "-i", "pipe:0",
-            "-ac", "1",
-            "-ar", "24000",
-            "-acodec", "pcm_s16le",
-            "-f", "wav",
-            "-y",
-            "pipe:1",
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| {
-            error!("FFmpeg command failed: {}", e);
-            AudioError::FFmpeg(e.to_string())
-        })?;
-
-    if let Some(mut stdin) = ffmpeg.stdin.take() {
-        std::io::Write::write_all(&mut stdin, &audio_bytes).map_err(|e| {
-            error!("Failed to write to FFmpeg stdin: {}", e);
-            AudioError::Io(e)
-        })?;
-    }
-
-    let output = ffmpeg.wait_with_output().map_err(|e| {
-        error!("FFmpeg failed to complete: {}", e);
-        AudioError::FFmpeg(e.to_string())
-    })?;
-
-    let ffmpeg_stderr = String::from_utf8_lossy(&output.stderr);
-    debug!("FFmpeg PCM stderr: {}", ffmpeg_stderr);
-
-    if !output.status.success() {
-        error!("FFmpeg PCM failed: {}", ffmpeg_stderr);
-        return Err(AudioError::FFmpeg(ffmpeg_stderr.to_string()));
-    }
-
-    debug!("PCM conversion successful, WAV size: {} bytes", output.stdout.len());
-    Ok(output.stdout)
+async fn convert_audio_to_pcm16_24khz(
+    audio_bytes: Vec<u8>,
+    limits: &audio::AudioLimits,
+) -> Result<Vec<u8>, AudioError> {
+    debug!("Converting uploaded audio to PCM");
+    audio::to_wav_24khz(audio_bytes, limits).await
 }
 
-async fn transcribe_audio(wav_bytes: &[u8], language: &str) -> Result<String, AudioError> {
-    debug!("Transcribing audio with Whisper");
-    let client = Client::new();
-    let api_key = env::var("OPENAI_API_KEY")
-        .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
-    info!("Using OpenAI API key: {} (first 4 chars)", &api_key[..4]);
+/// Maps a failure to read an upload to a response whose JSON body carries
+/// a stable `error` code alongside the message.
+fn audio_input_error(e: AudioError) -> actix_web::Error {
+    use actix_web::http::StatusCode;
+    let (status, code) = match &e {
+        AudioError::Base64(_) => (StatusCode::BAD_REQUEST, "invalid_base64"),
+        AudioError::TooLarge { .. } => (StatusCode::PAYLOAD_TOO_LARGE, "audio_too_large"),
+        AudioError::UnrecognizedFormat => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unrecognized_format"),
+        AudioError::UnsupportedCodec(_) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_codec"),
+        AudioError::Decode(_) => (StatusCode::BAD_REQUEST, "undecodable_audio"),
+        AudioError::TooLong { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "audio_too_long"),
+        AudioError::TooShort { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "audio_too_short"),
+        AudioError::InvalidOutput(_) => (StatusCode::BAD_REQUEST, "invalid_output"),
+        AudioError::Busy => (StatusCode::SERVICE_UNAVAILABLE, "audio_busy"),
+        AudioError::FFmpegTimeout(_) => (StatusCode::GATEWAY_TIMEOUT, "conversion_timeout"),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "conversion_failed"),
+    };
+    let response = HttpResponse::build(status).json(json!({
+        "error": code,
+        "message": e.to_string(),
+    }));
+    actix_web::error::InternalError::from_response(e, response).into()
+}
 
+/// Transcribes a recording, in overlapping chunks when it is too long for
+/// one upload. Chunks are sent `TRANSCRIBE_CONCURRENCY` (default 4) at a
+/// time and their text joined in order.
+async fn transcribe_audio(
+    transcriber: &dyn SpeechToText,
+    wav_bytes: &[u8],
+    language: &str,
+) -> Result<String, AudioError> {
+    debug!("Transcribing audio with {}", transcriber.name());
     let language_code = match language {
         "en" => "en",
         "hi" => "hi",
@@ -269,42 +280,35 @@ async fn transcribe_audio(wav_bytes: &[u8], language: &str) -> Result<String, Au
         _ => return Err(AudioError::InvalidLanguage),
     };
 
-    let form = reqwest::multipart::Form::new()
-        .text("model", "whisper-1")
-        .text("language", language_code)
-        .part(
-            "file",
-            reqwest::multipart::Part::bytes(wav_bytes.to_vec())
-                .file_name("audio.wav")
-                .mime_str("audio/wav")
-                .map_err(|e| AudioError::OpenAI(e.to_string()))?,
-        );
-
-    let response = client
-        .post("https://api.openai.com/v1/audio/transcriptions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| AudioError::Http(e))?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("Whisper API failed: status={}, error={}", status, error_text);
-        return Err(AudioError::OpenAI(format!("Whisper API failed: {}", error_text)));
+    let mut chunks = audio::split_for_transcription(wav_bytes)?;
+    if chunks.len() == 1 {
+        let transcript = transcriber.transcribe(chunks.remove(0), language_code).await?;
+        debug!("Transcription successful: {}", transcript);
+        return Ok(transcript);
     }
 
-    let json: serde_json::Value = response.json().await.map_err(|e| AudioError::Http(e))?;
-    let transcript = json["text"]
-        .as_str()
-        .ok_or_else(|| AudioError::OpenAI("No transcript in response".to_string()))?
-        .to_string();
-
-    debug!("Transcription successful: {}", transcript);
+    let concurrency = env::var("TRANSCRIBE_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(4);
+    info!(
+        "Transcribing {} chunks, {} at a time",
+        chunks.len(),
+        concurrency
+    );
+    let transcripts: Vec<String> = futures::stream::iter(chunks)
+        .map(|chunk| transcriber.transcribe(chunk, language_code))
+        .buffered(concurrency)
+        .try_collect()
+        .await?;
+    let transcript = audio::stitch_transcripts(transcripts);
+    debug!("Stitched transcription: {}", transcript);
     Ok(transcript)
 }
 
+/// The reply, and how many tokens of the prompt were left for history.
+#[allow(clippy::too_many_arguments)]
 async fn generate_therapist_response(
     transcript: &str,
     language: &str,
@@ -313,7 +317,10 @@ async fn generate_therapist_response(
     shenanigan_mode: bool,
     seductive_mode: bool,
     history: Option<Vec<ChatMessage>>,
-) -> Result<String, AudioError> {
+    summary: Option<&str>,
+    facts: &[Fact],
+    recalled: &[StoredMessage],
+) -> Result<(String, usize), AudioError> {
     debug!("Generating therapist response for transcript: {}", transcript);
     let client = Client::new();
     let api_key = env::var("OPENAI_API_KEY")
@@ -327,9 +334,27 @@ async fn generate_therapist_response(
         shenanigan_mode,
         seductive_mode,
     )?;
+    let instructions = match summary {
+        Some(summary) => format!(
+            "{}\n\nEARLIER IN THIS CONVERSATION (summary of turns no longer shown):\n{}",
+            instructions, summary
+        ),
+        None => instructions,
+    };
+    let instructions = match facts::prompt_section(facts) {
+        Some(section) => format!("{}\n\n{}", instructions, section),
+        None => instructions,
+    };
+    let instructions = match embeddings::prompt_section(recalled) {
+        Some(section) => format!("{}\n\n{}", instructions, section),
+        None => instructions,
+    };
 
+    let budget = prompt::token_budget();
+    let history_tokens = prompt::history_budget(&instructions, transcript, budget);
     let mut messages = vec![json!({"role": "system", "content": instructions})];
     if let Some(hist) = history {
+        let hist = prompt::fit_history(&instructions, hist, transcript, budget);
         for msg in &hist {
             messages.push(json!({"role": msg.role, "content": msg.content}));
         }
@@ -347,7 +372,7 @@ async fn generate_therapist_response(
         }))
         .send()
         .await
-        .map_err(|e| AudioError::Http(e))?;
+        .map_err(AudioError::Http)?;
 
     let status = response.status();
     if !status.is_success() {
@@ -356,17 +381,23 @@ async fn generate_therapist_response(
         return Err(AudioError::OpenAI(format!("Chat API failed: {}", error_text)));
     }
 
-    let json: serde_json::Value = response.json().await.map_err(|e| AudioError::Http(e))?;
+    let json: serde_json::Value = response.json().await.map_err(AudioError::Http)?;
     let response_text = json["choices"][0]["message"]["content"]
         .as_str()
         .ok_or_else(|| AudioError::OpenAI("No response text in Chat API".to_string()))?
         .to_string();
 
     debug!("Therapist response: {}", response_text);
-    Ok(response_text)
+    Ok((response_text, history_tokens))
 }
 
-async fn text_to_speech(text: &str, language: &str) -> Result<Vec<u8>, AudioError> {
+/// Speaks `text` in the encoding `output` asks for, transcoding locally
+/// what the provider can't produce directly.
+async fn text_to_speech(
+    text: &str,
+    language: &str,
+    output: &audio::OutputSpec,
+) -> Result<Vec<u8>, AudioError> {
     debug!("Converting text to speech with TTS-1");
     let client = Client::new();
     let api_key = env::var("OPENAI_API_KEY")
@@ -387,11 +418,11 @@ async fn text_to_speech(text: &str, language: &str) -> Result<Vec<u8>, AudioErro
             "model": "tts-1",
             "input": text,
             "voice": voice,
-            "response_format": "mp3"
+            "response_format": output.provider_format()
         }))
         .send()
         .await
-        .map_err(|e| AudioError::Http(e))?;
+        .map_err(AudioError::Http)?;
 
     let status = response.status();
     if !status.is_success() {
@@ -400,9 +431,13 @@ async fn text_to_speech(text: &str, language: &str) -> Result<Vec<u8>, AudioErro
         return Err(AudioError::OpenAI(format!("TTS API failed: {}", error_text)));
     }
 
-    let mp3_bytes = response.bytes().await.map_err(|e| AudioError::Http(e))?.to_vec();
-    debug!("TTS successful, MP3 size: {} bytes", mp3_bytes.len());
-    Ok(mp3_bytes)
+    let speech = response.bytes().await.map_err(AudioError::Http)?.to_vec();
+    debug!(
+        "TTS successful, {} size: {} bytes",
+        output.provider_format(),
+        speech.len()
+    );
+    output.render(speech).await
 }
 
 fn get_language_instructions(
@@ -490,56 +525,118 @@ fn get_language_instructions(
     Ok(instructions)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_openai_realtime(
-    pcm_audio_base64: String,
+    store: Arc<dyn ConversationStore>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    sessions: &EphemeralSessions,
+    tasks: &UserTasks,
+    transcriber: &dyn SpeechToText,
+    ephemeral: bool,
+    user_id: &str,
+    conversation_id: Option<&str>,
+    pcm_audio_bytes: Vec<u8>,
     language: String,
     genz_mode: bool,
     sarcastic_mode: bool,
     shenanigan_mode: bool,
     seductive_mode: bool,
+    output: audio::OutputSpec,
 ) -> Result<AudioResponse, AudioError> {
     debug!("Processing OpenAI request for language: {}", language);
 
-    if !["en", "hi", "pa"].contains(&language.as_str()) {
-        error!("Invalid language: {}", language);
-        return Err(AudioError::InvalidLanguage);
-    }
-
-    let pcm_bytes = general_purpose::STANDARD
-        .decode(&pcm_audio_base64)
-        .map_err(|e| {
-            error!("Base64 decode failed: {}", e);
-            AudioError::Base64(e)
-        })?;
-
     // Transcribe audio (still needed for GPT input, but not returned)
-    let transcript = transcribe_audio(&pcm_bytes, &language).await?;
+    let transcript = transcribe_audio(transcriber, &pcm_audio_bytes, &language).await?;
+
+    // Voice and text turns share one conversation. Off the record, the
+    // reply draws only on the session, not on anything stored.
+    let (history, summary, known_facts, recalled) = if ephemeral {
+        (sessions.history(user_id, conversation_id), None, Vec::new(), Vec::new())
+    } else {
+        let history = get_conversation_history(store.as_ref(), user_id, conversation_id).await?;
+        let summary = store.get_summary(user_id, conversation_id).await?;
+        let known_facts = facts::relevant(store.list_facts(user_id).await?, &transcript);
+        let recalled =
+            embeddings::recall(store.as_ref(), embedder.as_ref(), user_id, &transcript, &history)
+                .await;
+        (history, summary, known_facts, recalled)
+    };
 
     // Generate therapist response
-    let response_text = generate_therapist_response(
+    let (response_text, history_tokens) = generate_therapist_response(
         &transcript,
         &language,
         genz_mode,
         sarcastic_mode,
         shenanigan_mode,
         seductive_mode,
-        None, // No history for audio
+        Some(history),
+        summary.as_ref().map(|s| s.content.as_str()),
+        &known_facts,
+        &recalled,
     )
     .await?;
 
+    if ephemeral {
+        sessions.append(user_id, conversation_id, transcript, response_text.clone());
+    } else {
+        let stored = store_conversation(
+            store.as_ref(),
+            user_id,
+            conversation_id,
+            transcript,
+            response_text.clone(),
+        )
+        .await?;
+        embeddings::spawn_index(tasks, store.clone(), embedder, user_id.to_string(), stored);
+        summarizer::spawn_fold(
+            tasks,
+            store,
+            user_id.to_string(),
+            conversation_id.map(str::to_string),
+            history_tokens,
+        );
+    }
+
     // Convert response to speech
-    let mp3_bytes = text_to_speech(&response_text, &language).await?;
-    let mp3_base64 = general_purpose::STANDARD.encode(&mp3_bytes);
+    let speech = text_to_speech(&response_text, &language, &output).await?;
 
     debug!("GPT response text: {}", response_text);
-    debug!("MP3 base64 length: {}", mp3_base64.len());
+    debug!("Speech length: {}", speech.len());
 
-    info!("Response processed: response_text length={}, mp3 base64 length={}", 
-        response_text.len(), mp3_base64.len());
+    info!("Response processed: response_text length={}, audio length={}", 
+        response_text.len(), speech.len());
 
     Ok(AudioResponse {
-        audio: mp3_base64,
+        audio: speech,
+        mime_type: output.mime_type(),
         response_text,
+        ephemeral,
+        no_speech: false,
+    })
+}
+
+/// Reply for a recording with no speech in it. Whisper tends to invent
+/// text for silence, so the model is not asked to answer and nothing is
+/// stored.
+async fn no_speech_response(
+    language: &str,
+    ephemeral: bool,
+    output: audio::OutputSpec,
+) -> Result<AudioResponse, AudioError> {
+    let response_text = match language {
+        "en" => "Sorry, I didn't catch that. Could you say it again?",
+        "hi" => "माफ़ कीजिए, मैं सुन नहीं पाया। क्या आप फिर से कह सकते हैं?",
+        "pa" => "ਮਾਫ਼ ਕਰਨਾ, ਮੈਂ ਸੁਣ ਨਹੀਂ ਸਕਿਆ। ਕੀ ਤੁਸੀਂ ਦੁਬਾਰਾ ਕਹਿ ਸਕਦੇ ਹੋ?",
+        _ => return Err(AudioError::InvalidLanguage),
+    };
+    let speech = text_to_speech(response_text, language, &output).await?;
+    Ok(AudioResponse {
+        audio: speech,
+        mime_type: output.mime_type(),
+        response_text: response_text.to_string(),
+        ephemeral,
+        no_speech: true,
     })
 }
 
@@ -560,28 +657,123 @@ async fn health() -> impl Responder {
     HttpResponse::Ok().body("OK")
 }
 
+/// Sends the reply as JSON, or as the audio itself when the client's
+/// `Accept` asks for audio. The raw form carries the text in
+/// `X-Response-Text`, percent-encoded, and the flags in `X-Ephemeral` and
+/// `X-No-Speech`.
+fn audio_reply(http: &HttpRequest, response: AudioResponse) -> HttpResponse {
+    let mut reply = HttpResponse::Ok();
+    reply.insert_header((actix_web::http::header::VARY, "Accept"));
+    if !upload::prefers_raw_audio(http) {
+        return reply.json(response);
+    }
+    let text = percent_encoding::utf8_percent_encode(
+        &response.response_text,
+        percent_encoding::NON_ALPHANUMERIC,
+    )
+    .to_string();
+    reply
+        .content_type(response.mime_type.as_str())
+        .insert_header(("X-Response-Text", text))
+        .insert_header(("X-Ephemeral", response.ephemeral.to_string()))
+        .insert_header(("X-No-Speech", response.no_speech.to_string()))
+        .body(response.audio)
+}
+
+/// A voice turn. The recording may be sent as JSON, multipart or a raw
+/// audio body; see `upload::AudioUpload`.
 #[post("/process-audio")]
-async fn process_audio(req: web::Json<AudioRequest>) -> ActixResult<web::Json<AudioResponse>> {
-    info!("Received /process-audio request: language={}, genz_mode={}", req.language, req.genz_mode);
-    debug!("Input audio base64 length: {}", req.audio.len());
+#[allow(clippy::too_many_arguments)]
+async fn process_audio(
+    http: HttpRequest,
+    upload: upload::AudioUpload,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+    embedder: web::Data<dyn EmbeddingProvider>,
+    sessions: web::Data<EphemeralSessions>,
+    tasks: web::Data<UserTasks>,
+    transcriber: web::Data<dyn SpeechToText>,
+    limits: web::Data<audio::AudioLimits>,
+    dsp: web::Data<audio::DspChain>,
+) -> ActixResult<HttpResponse> {
+    let req = upload.options;
+    info!(
+        "Received /process-audio request: user_id={}, language={}, genz_mode={}",
+        user.user_id, req.language, req.genz_mode
+    );
+    debug!("Input audio length: {}", upload.audio.len());
 
-    let pcm_audio_bytes = convert_audio_to_pcm16_24khz(&req.audio)
+    // Validate language before any of the audio work
+    if !["en", "hi", "pa"].contains(&req.language.as_str()) {
+        error!("Invalid language: {}", req.language);
+        return Err(actix_web::error::ErrorBadRequest("Invalid language"));
+    }
+
+    let output = req.output_spec();
+    output.validate().map_err(|e| {
+        error!("Invalid output request: {}", e);
+        audio_input_error(e)
+    })?;
+
+    let conversation_id = req.conversation_id.as_deref();
+    let ephemeral = sessions.resolve(&user.user_id, conversation_id, req.ephemeral);
+    if !ephemeral {
+        conversations::check_writable_thread(store.get_ref(), &user.user_id, conversation_id)
+            .await?;
+    }
+
+    let pcm_audio_bytes = convert_audio_to_pcm16_24khz(upload.audio, &limits)
+        .await
         .map_err(|e| {
             error!("Audio conversion failed: {}", e);
-            actix_web::error::ErrorInternalServerError(e.to_string())
+            audio_input_error(e)
         })?;
 
-    let pcm_audio_base64 = general_purpose::STANDARD.encode(&pcm_audio_bytes);
+    let Some(pcm_audio_bytes) = audio::detect_speech(pcm_audio_bytes).map_err(|e| {
+        error!("Voice activity detection failed: {}", e);
+        audio_input_error(e)
+    })?
+    else {
+        info!("No speech in /process-audio upload from user_id={}", user.user_id);
+        let response = no_speech_response(&req.language, ephemeral, output)
+            .await
+            .map_err(|e| {
+                error!("No-speech reply failed: {}", e);
+                match e {
+                    AudioError::InvalidLanguage => {
+                        actix_web::error::ErrorBadRequest("Invalid language")
+                    }
+                    _ => actix_web::error::ErrorInternalServerError(e.to_string()),
+                }
+            })?;
+        return Ok(audio_reply(&http, response));
+    };
 
-    debug!("PCM audio base64 length: {}", pcm_audio_base64.len());
+    let pcm_audio_bytes = audio::preprocess(pcm_audio_bytes, **dsp)
+        .await
+        .map_err(|e| {
+            error!("Audio preprocessing failed: {}", e);
+            audio_input_error(e)
+        })?;
+
+    debug!("PCM audio length: {}", pcm_audio_bytes.len());
 
     let response = process_openai_realtime(
-        pcm_audio_base64,
+        store.into_inner(),
+        embedder.into_inner(),
+        sessions.get_ref(),
+        tasks.get_ref(),
+        transcriber.get_ref(),
+        ephemeral,
+        &user.user_id,
+        conversation_id,
+        pcm_audio_bytes,
         req.language.clone(),
         req.genz_mode,
         req.sarcastic_mode,
         req.shenanigan_mode,
         req.seductive_mode,
+        output,
     )
     .await
     .map_err(|e| {
@@ -596,13 +788,17 @@ async fn process_audio(req: web::Json<AudioRequest>) -> ActixResult<web::Json<Au
 
     info!("Returning /process-audio response: response_text length={}, audio length={}", 
         response.response_text.len(), response.audio.len());
-    Ok(web::Json(response))
+    Ok(audio_reply(&http, response))
 }
 
 #[post("/chat")]
 async fn chat(
     req: web::Json<ChatRequest>,
     user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+    embedder: web::Data<dyn EmbeddingProvider>,
+    sessions: web::Data<EphemeralSessions>,
+    tasks: web::Data<UserTasks>,
 ) -> ActixResult<web::Json<ChatResponse>> {
     info!(
         "Received /chat request: user_id={}, language={}, message_length={}",
@@ -618,16 +814,50 @@ async fn chat(
         return Err(actix_web::error::ErrorBadRequest("Invalid language"));
     }
 
-    // Get conversation history
-    let history = get_conversation_history(&user.user_id)
-        .await
-        .map_err(|e| {
-            error!("Failed to get conversation history: {}", e);
-            actix_web::error::ErrorInternalServerError(e.to_string())
-        })?;
+    let conversation_id = req.conversation_id.as_deref();
+    let ephemeral = sessions.resolve(&user.user_id, conversation_id, req.ephemeral);
+
+    // Get conversation history. Off the record, the reply draws only on
+    // the session, not on anything stored.
+    let (history, summary, known_facts, recalled) = if ephemeral {
+        (sessions.history(&user.user_id, conversation_id), None, Vec::new(), Vec::new())
+    } else {
+        conversations::check_writable_thread(store.get_ref(), &user.user_id, conversation_id)
+            .await?;
+        let history = get_conversation_history(store.get_ref(), &user.user_id, conversation_id)
+            .await
+            .map_err(|e| {
+                error!("Failed to get conversation history: {}", e);
+                actix_web::error::ErrorInternalServerError(e.to_string())
+            })?;
+        let summary = store
+            .get_summary(&user.user_id, conversation_id)
+            .await
+            .map_err(|e| {
+                error!("Failed to get conversation summary: {}", e);
+                actix_web::error::ErrorInternalServerError(e.to_string())
+            })?;
+        let known_facts = store
+            .list_facts(&user.user_id)
+            .await
+            .map(|all| facts::relevant(all, &req.message))
+            .map_err(|e| {
+                error!("Failed to get user facts: {}", e);
+                actix_web::error::ErrorInternalServerError(e.to_string())
+            })?;
+        let recalled = embeddings::recall(
+            store.get_ref(),
+            embedder.get_ref(),
+            &user.user_id,
+            &req.message,
+            &history,
+        )
+        .await;
+        (history, summary, known_facts, recalled)
+    };
 
     // Generate therapist response
-    let response_text = generate_therapist_response(
+    let (response_text, history_tokens) = generate_therapist_response(
         &req.message,
         &req.language,
         req.genz_mode,
@@ -635,6 +865,9 @@ async fn chat(
         req.shenanigan_mode,
         req.seductive_mode,
         Some(history),
+        summary.as_ref().map(|s| s.content.as_str()),
+        &known_facts,
+        &recalled,
     )
     .await
     .map_err(|e| {
@@ -647,40 +880,65 @@ async fn chat(
         }
     })?;
 
-    // Store user message
-    store_conversation(
-        &user.user_id,
-        ChatMessage {
-            role: "user".to_string(),
-            content: req.message.clone(),
-        },
-    )
-    .await
-    .map_err(|e| {
-        error!("Failed to store user message: {}", e);
-        actix_web::error::ErrorInternalServerError(e.to_string())
-    })?;
+    if ephemeral {
+        sessions.append(
+            &user.user_id,
+            conversation_id,
+            req.message.clone(),
+            response_text.clone(),
+        );
+        info!("Returning off-the-record /chat response for user_id={}", user.user_id);
+        return Ok(web::Json(ChatResponse {
+            response: response_text,
+            ephemeral,
+        }));
+    }
 
-    // Store assistant response
-    store_conversation(
+    // Store the exchange
+    let stored = store_conversation(
+        store.get_ref(),
         &user.user_id,
-        ChatMessage {
-            role: "assistant".to_string(),
-            content: response_text.clone(),
-        },
+        conversation_id,
+        req.message.clone(),
+        response_text.clone(),
     )
     .await
     .map_err(|e| {
-        error!("Failed to store assistant message: {}", e);
+        error!("Failed to store conversation turn: {}", e);
         actix_web::error::ErrorInternalServerError(e.to_string())
     })?;
 
+    let store = store.into_inner();
+    embeddings::spawn_index(
+        &tasks,
+        store.clone(),
+        embedder.into_inner(),
+        user.user_id.clone(),
+        stored,
+    );
+    summarizer::spawn_fold(
+        &tasks,
+        store.clone(),
+        user.user_id.clone(),
+        req.conversation_id.clone(),
+        history_tokens,
+    );
+    facts::spawn_extract(
+        &tasks,
+        store,
+        user.user_id.clone(),
+        req.conversation_id.clone(),
+        req.message.clone(),
+        response_text.clone(),
+    );
+
     info!(
         "Returning /chat response: response_text length={}",
         response_text.len()
     );
     Ok(web::Json(ChatResponse {
         response: response_text,
+        ephemeral,
     }))
 }
 
@@ -718,6 +976,45 @@ async fn main() -> io::Result<()> {
     info!("Handlebars template registered");
 
     let handlebars_data = web::Data::new(handlebars);
+    let auth_provider = web::Data::from(auth::provider_from_env().map_err(|e| {
+        error!("Invalid auth configuration: {}", e);
+        io::Error::other(e.to_string())
+    })?);
+    let embedding_provider = web::Data::from(embeddings::provider_from_env().map_err(|e| {
+        error!("Invalid embedding configuration: {}", e);
+        io::Error::other(e.to_string())
+    })?);
+    let transcription_provider = web::Data::from(transcription::provider_from_env().map_err(|e| {
+        error!("Invalid transcription configuration: {}", e);
+        io::Error::other(e.to_string())
+    })?);
+    let conversation_store = store::store_from_env().map_err(|e| {
+        error!("Failed to initialise conversation store: {}", e);
+        io::Error::other(e.to_string())
+    })?;
+    let user_tasks = Arc::new(UserTasks::new());
+    embeddings::index_deferred_writes(
+        &conversation_store,
+        embedding_provider.clone().into_inner(),
+        user_tasks.clone(),
+    );
+    let user_tasks = web::Data::from(user_tasks);
+    let conversation_store = web::Data::from(conversation_store);
+    let ephemeral_sessions = Arc::new(EphemeralSessions::from_env());
+    ephemeral::spawn_sweeper(ephemeral_sessions.clone());
+    let ephemeral_sessions = web::Data::from(ephemeral_sessions);
+    let audio_limits = audio::AudioLimits::from_env();
+    // Uploads arrive as base64 inside JSON, so the body limit follows the
+    // audio limit.
+    let json_config = web::JsonConfig::default().limit(audio_limits.max_json_bytes());
+    let audio_limits = web::Data::new(audio_limits);
+    let dsp_chain = web::Data::new(audio::DspChain::from_env());
+    let retention_config = Arc::new(retention::RetentionConfig::from_env().map_err(|e| {
+        error!("Invalid retention configuration: {}", e);
+        io::Error::other(e.to_string())
+    })?);
+    retention::spawn(conversation_store.clone().into_inner(), retention_config.clone());
+    let retention_config = web::Data::from(retention_config);
     let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let address = format!("127.0.0.1:{}", port); // Bind to 0.0.0.0 for Cloud Run
     info!("Binding server to {}", address);
@@ -732,10 +1029,31 @@ async fn main() -> io::Result<()> {
                     .supports_credentials(),
             )
             .app_data(handlebars_data.clone())
+            .app_data(auth_provider.clone())
+            .app_data(conversation_store.clone())
+            .app_data(embedding_provider.clone())
+            .app_data(transcription_provider.clone())
+            .app_data(retention_config.clone())
+            .app_data(ephemeral_sessions.clone())
+            .app_data(user_tasks.clone())
+            .app_data(audio_limits.clone())
+            .app_data(dsp_chain.clone())
+            .app_data(json_config.clone())
             .service(get_index)
             .service(health)
             .service(process_audio)
             .service(chat)
+            .service(conversations::create_conversation)
+            .service(conversations::list_conversations)
+            .service(conversations::update_conversation)
+            .service(conversations::list_messages)
+            .service(account::export_me)
+            .service(account::delete_me)
+            .service(facts::list_facts)
+            .service(facts::delete_fact)
+            .service(retention::get_retention)
+            .service(retention::set_retention)
+            .service(retention::clear_retention)
     })
     .bind(&address)
     .map_err(|e| {
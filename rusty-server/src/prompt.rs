@@ -0,0 +1,149 @@
+use crate::store::ChatMessage;
+use log::{debug, error};
+use std::env;
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+const DEFAULT_TOKEN_BUDGET: usize = 4000;
+// Per-message framing the chat format adds around each message's content,
+// plus the tokens that prime the assistant's reply.
+const TOKENS_PER_MESSAGE: usize = 4;
+const REPLY_PRIMING_TOKENS: usize = 3;
+
+fn tokenizer() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    // o200k_base is the encoding used by the gpt-4o family.
+    BPE.get_or_init(|| tiktoken_rs::o200k_base().expect("bundled o200k_base ranks are valid"))
+}
+
+pub fn count_tokens(text: &str) -> usize {
+    tokenizer().encode_with_special_tokens(text).len()
+}
+
+/// Tokens a message costs in the prompt, framing included.
+pub fn message_tokens(content: &str) -> usize {
+    TOKENS_PER_MESSAGE + count_tokens(content)
+}
+
+/// Prompt size limit from `PROMPT_TOKEN_BUDGET`, covering the system prompt,
+/// history and the new message.
+pub fn token_budget() -> usize {
+    match env::var("PROMPT_TOKEN_BUDGET") {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            error!("Invalid PROMPT_TOKEN_BUDGET '{}', using {}", value, DEFAULT_TOKEN_BUDGET);
+            DEFAULT_TOKEN_BUDGET
+        }),
+        Err(_) => DEFAULT_TOKEN_BUDGET,
+    }
+}
+
+/// What is left of `budget` for history next to the system prompt and the
+/// new message.
+pub fn history_budget(system_prompt: &str, message: &str, budget: usize) -> usize {
+    let fixed = message_tokens(system_prompt) + message_tokens(message) + REPLY_PRIMING_TOKENS;
+    budget.saturating_sub(fixed)
+}
+
+/// Keeps as much of `history` (oldest first) as fits in `budget` next to the
+/// system prompt and the new message, dropping the oldest turns first. Turns
+/// go whole, so the kept history always opens with a user message rather
+/// than a reply to something no longer shown.
+pub fn fit_history(
+    system_prompt: &str,
+    history: Vec<ChatMessage>,
+    message: &str,
+    budget: usize,
+) -> Vec<ChatMessage> {
+    let available = history_budget(system_prompt, message, budget);
+
+    // A turn is a user message and the replies after it; only cut where one
+    // starts.
+    let mut keep_from = history.len();
+    let mut kept = 0;
+    let mut turn = 0;
+    for (i, msg) in history.iter().enumerate().rev() {
+        turn += message_tokens(&msg.content);
+        if msg.role != "user" {
+            continue;
+        }
+        if kept + turn > available {
+            break;
+        }
+        kept += turn;
+        turn = 0;
+        keep_from = i;
+    }
+
+    debug!(
+        "Prompt budget {}: {} tokens for history, keeping {} of {} history messages",
+        budget,
+        available,
+        history.len() - keep_from,
+        history.len()
+    );
+    history.into_iter().skip(keep_from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn roles(history: &[ChatMessage]) -> Vec<&str> {
+        history.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    fn thread(turns: usize) -> Vec<ChatMessage> {
+        (0..turns)
+            .flat_map(|i| {
+                [
+                    message("user", &format!("question number {}", i)),
+                    message("assistant", &format!("answer number {}", i)),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_everything_that_fits() {
+        let history = thread(3);
+        let fitted = fit_history("system", history.clone(), "hello", 4000);
+        assert_eq!(fitted.len(), history.len());
+    }
+
+    #[test]
+    fn drops_whole_turns_from_the_oldest() {
+        let history = thread(10);
+        let turn_tokens = message_tokens("question number 9") + message_tokens("answer number 9");
+        // Room for three turns and half of a fourth.
+        let budget = budget_for(3 * turn_tokens + turn_tokens / 2);
+        let fitted = fit_history("system", history, "hello", budget);
+        assert_eq!(fitted.len(), 6);
+        assert_eq!(roles(&fitted), ["user", "assistant"].repeat(3));
+        assert_eq!(fitted[0].content, "question number 7");
+    }
+
+    #[test]
+    fn never_opens_with_an_orphan_reply() {
+        // Room for the last reply alone, but not for its question.
+        let history = thread(2);
+        let budget = budget_for(message_tokens("answer number 1") + 1);
+        assert!(fit_history("system", history, "hello", budget).is_empty());
+
+        // History that starts mid-turn loses the stray reply.
+        let mut history = thread(2);
+        history.remove(0);
+        let fitted = fit_history("system", history, "hello", 4000);
+        assert_eq!(roles(&fitted), ["user", "assistant"]);
+    }
+
+    fn budget_for(history_tokens: usize) -> usize {
+        history_tokens + 4000 - history_budget("system", "hello", 4000)
+    }
+}
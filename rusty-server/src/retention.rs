@@ -0,0 +1,403 @@
+use crate::auth::AuthenticatedUser;
+use crate::store::{ConversationStore, ExpiryAction, RetentionPolicy, StoreError};
+use actix_web::{delete, get, put, web, HttpResponse, Result as ActixResult};
+use chrono::{Duration as ChronoDuration, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_INTERVAL_SECS: u64 = 3600;
+// Longest period a user may choose for themselves; about ten years.
+const MAX_USER_DAYS: u32 = 3650;
+// Longest period an operator may configure; about a century.
+const MAX_OPERATOR_DAYS: u32 = 36_500;
+
+/// A clinic or other organisation whose members share a retention policy.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TenantPolicy {
+    pub id: String,
+    #[serde(flatten)]
+    pub policy: RetentionPolicy,
+    pub users: Vec<String>,
+}
+
+/// Operator-set retention: a global default and per-tenant policies. Users
+/// can also pick their own, which live in the store. Every policy that
+/// applies to a user is enforced, so in effect the shortest one wins.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub global: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub tenants: Vec<TenantPolicy>,
+    /// Time between expiry passes, from `RETENTION_INTERVAL_SECS`.
+    #[serde(skip, default = "default_interval")]
+    pub interval: Duration,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            global: None,
+            tenants: Vec::new(),
+            interval: default_interval(),
+        }
+    }
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(DEFAULT_INTERVAL_SECS)
+}
+
+impl RetentionConfig {
+    /// Reads the JSON file at `RETENTION_POLICY_FILE` if set, otherwise a
+    /// global policy from `RETENTION_DAYS` and `RETENTION_ACTION` (`delete`,
+    /// the default, or `anonymize`). With neither, nothing expires. Passes
+    /// run every `RETENTION_INTERVAL_SECS` (default hourly, at least 1).
+    pub fn from_env() -> Result<Self, StoreError> {
+        let mut config: RetentionConfig = if let Ok(path) = env::var("RETENTION_POLICY_FILE") {
+            let text = std::fs::read_to_string(&path).map_err(|e| {
+                StoreError::Config(format!("Cannot read RETENTION_POLICY_FILE {}: {}", path, e))
+            })?;
+            serde_json::from_str(&text).map_err(|e| {
+                StoreError::Config(format!("Invalid RETENTION_POLICY_FILE {}: {}", path, e))
+            })?
+        } else if let Ok(days) = env::var("RETENTION_DAYS") {
+            let days = days
+                .parse()
+                .map_err(|e| StoreError::Config(format!("Invalid RETENTION_DAYS: {}", e)))?;
+            let action = match env::var("RETENTION_ACTION").as_deref() {
+                Ok("anonymize") => ExpiryAction::Anonymize,
+                Ok("delete") | Err(_) => ExpiryAction::Delete,
+                Ok(other) => {
+                    return Err(StoreError::Config(format!(
+                        "RETENTION_ACTION must be delete or anonymize, not '{}'",
+                        other
+                    )))
+                }
+            };
+            RetentionConfig {
+                global: Some(RetentionPolicy { days, action }),
+                ..RetentionConfig::default()
+            }
+        } else {
+            RetentionConfig::default()
+        };
+
+        if let Ok(value) = env::var("RETENTION_INTERVAL_SECS") {
+            let secs = value.parse().ok().filter(|secs| *secs > 0).ok_or_else(|| {
+                StoreError::Config(format!(
+                    "RETENTION_INTERVAL_SECS must be a whole number of seconds, at least 1, not '{}'",
+                    value
+                ))
+            })?;
+            config.interval = Duration::from_secs(secs);
+        }
+
+        config.validate()?;
+        if let Some(global) = config.global {
+            info!("Global retention: {} after {} days", global.action.as_str(), global.days);
+        }
+        for tenant in &config.tenants {
+            info!(
+                "Retention for tenant {}: {} after {} days ({} users)",
+                tenant.id,
+                tenant.policy.action.as_str(),
+                tenant.policy.days,
+                tenant.users.len()
+            );
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), StoreError> {
+        let mut policies = self
+            .global
+            .iter()
+            .chain(self.tenants.iter().map(|t| &t.policy));
+        if policies.any(|p| p.days == 0 || p.days > MAX_OPERATOR_DAYS) {
+            return Err(StoreError::Config(format!(
+                "Retention periods must be between 1 and {} days",
+                MAX_OPERATOR_DAYS
+            )));
+        }
+        Ok(())
+    }
+
+    fn tenant_policy(&self, user_id: &str) -> Option<RetentionPolicy> {
+        self.tenants
+            .iter()
+            .filter(|t| t.users.iter().any(|u| u == user_id))
+            .map(|t| t.policy)
+            .min_by_key(|p| p.days)
+    }
+}
+
+/// Starts the expiry job: one pass at startup, then every
+/// `config.interval`.
+pub fn spawn(store: Arc<dyn ConversationStore>, config: Arc<RetentionConfig>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.interval);
+        loop {
+            ticker.tick().await;
+            run(store.as_ref(), &config).await;
+        }
+    });
+}
+
+/// One pass over every policy. A failing scope is logged and skipped so it
+/// can't hold up the others.
+async fn run(store: &dyn ConversationStore, config: &RetentionConfig) {
+    if let Some(policy) = config.global {
+        let expired = expire(store, None, policy).await;
+        log_expired(expired, policy, "globally");
+    }
+
+    for tenant in &config.tenants {
+        let mut expired = 0;
+        for user_id in &tenant.users {
+            expired += expire(store, Some(user_id), tenant.policy).await;
+        }
+        log_expired(expired, tenant.policy, &format!("for tenant {}", tenant.id));
+    }
+
+    match store.retention_policies().await {
+        Ok(policies) => {
+            for (user_id, policy) in policies {
+                let expired = expire(store, Some(&user_id), policy).await;
+                log_expired(expired, policy, &format!("for user_id={}", user_id));
+            }
+        }
+        Err(e) => error!("Listing user retention policies failed: {}", e),
+    }
+}
+
+async fn expire(store: &dyn ConversationStore, user_id: Option<&str>, policy: RetentionPolicy) -> u64 {
+    // A period reaching past chrono's range keeps everything.
+    let Some(before) = Utc::now().checked_sub_signed(ChronoDuration::days(policy.days as i64)) else {
+        return 0;
+    };
+    let before = before.to_rfc3339();
+    store
+        .expire_messages(user_id, &before, policy.action)
+        .await
+        .unwrap_or_else(|e| {
+            error!(
+                "Expiring messages before {} failed (user_id={:?}): {}",
+                before, user_id, e
+            );
+            0
+        })
+}
+
+fn log_expired(expired: u64, policy: RetentionPolicy, scope: &str) {
+    if expired > 0 {
+        let verb = match policy.action {
+            ExpiryAction::Delete => "Deleted",
+            ExpiryAction::Anonymize => "Anonymized",
+        };
+        info!(
+            "Retention: {} {} messages older than {} days {}",
+            verb, expired, policy.days, scope
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct RetentionView {
+    /// What the caller chose, if anything.
+    user: Option<RetentionPolicy>,
+    tenant: Option<RetentionPolicy>,
+    global: Option<RetentionPolicy>,
+    /// The shortest of the above, which is what actually happens.
+    effective: Option<RetentionPolicy>,
+}
+
+impl RetentionView {
+    fn new(config: &RetentionConfig, user_id: &str, user: Option<RetentionPolicy>) -> Self {
+        let tenant = config.tenant_policy(user_id);
+        let effective = [user, tenant, config.global]
+            .into_iter()
+            .flatten()
+            .min_by_key(|p| (p.days, p.action != ExpiryAction::Delete));
+        RetentionView {
+            user,
+            tenant,
+            global: config.global,
+            effective,
+        }
+    }
+}
+
+/// The retention policies that apply to the caller.
+#[get("/me/retention")]
+async fn get_retention(
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+    config: web::Data<RetentionConfig>,
+) -> ActixResult<web::Json<RetentionView>> {
+    let policy = store.retention_policy(&user.user_id).await.map_err(|e| {
+        error!("Failed to load retention policy: {}", e);
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+    Ok(web::Json(RetentionView::new(&config, &user.user_id, policy)))
+}
+
+/// Sets how long the caller's messages are kept. Organisation policies
+/// still apply when they are shorter.
+#[put("/me/retention")]
+async fn set_retention(
+    body: web::Json<RetentionPolicy>,
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+    config: web::Data<RetentionConfig>,
+) -> ActixResult<web::Json<RetentionView>> {
+    let policy = body.into_inner();
+    if policy.days == 0 || policy.days > MAX_USER_DAYS {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "days must be between 1 and {}",
+            MAX_USER_DAYS
+        )));
+    }
+    store
+        .set_retention_policy(&user.user_id, Some(policy))
+        .await
+        .map_err(|e| {
+            error!("Failed to store retention policy: {}", e);
+            actix_web::error::ErrorInternalServerError(e.to_string())
+        })?;
+    info!(
+        "Retention for user_id={} set to {} after {} days",
+        user.user_id,
+        policy.action.as_str(),
+        policy.days
+    );
+    Ok(web::Json(RetentionView::new(&config, &user.user_id, Some(policy))))
+}
+
+/// Clears the caller's own retention period.
+#[delete("/me/retention")]
+async fn clear_retention(
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+) -> ActixResult<HttpResponse> {
+    store
+        .set_retention_policy(&user.user_id, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to clear retention policy: {}", e);
+            actix_web::error::ErrorInternalServerError(e.to_string())
+        })?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn policy(days: u32, action: ExpiryAction) -> RetentionPolicy {
+        RetentionPolicy { days, action }
+    }
+
+    fn clear_env() {
+        for name in [
+            "RETENTION_POLICY_FILE",
+            "RETENTION_DAYS",
+            "RETENTION_ACTION",
+            "RETENTION_INTERVAL_SECS",
+        ] {
+            env::remove_var(name);
+        }
+    }
+
+    // One test, since the cases share process-wide variables.
+    #[test]
+    fn from_env_parses_and_bounds_operator_settings() {
+        clear_env();
+        let config = RetentionConfig::from_env().unwrap();
+        assert!(config.global.is_none());
+        assert_eq!(config.interval, default_interval());
+
+        env::set_var("RETENTION_DAYS", "30");
+        env::set_var("RETENTION_ACTION", "anonymize");
+        env::set_var("RETENTION_INTERVAL_SECS", "60");
+        let config = RetentionConfig::from_env().unwrap();
+        assert_eq!(config.global, Some(policy(30, ExpiryAction::Anonymize)));
+        assert_eq!(config.interval, Duration::from_secs(60));
+
+        let rejected = [
+            ("RETENTION_DAYS", "0"),
+            ("RETENTION_DAYS", "36501"),
+            ("RETENTION_DAYS", "4294967295"),
+            ("RETENTION_DAYS", "forever"),
+            ("RETENTION_ACTION", "archive"),
+            ("RETENTION_INTERVAL_SECS", "0"),
+        ];
+        for (name, value) in rejected {
+            env::set_var("RETENTION_DAYS", "30");
+            env::set_var("RETENTION_ACTION", "delete");
+            env::set_var("RETENTION_INTERVAL_SECS", "60");
+            env::set_var(name, value);
+            assert!(
+                matches!(RetentionConfig::from_env(), Err(StoreError::Config(_))),
+                "{}={} was accepted",
+                name,
+                value
+            );
+        }
+
+        clear_env();
+        let path = env::temp_dir().join(format!("retention-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"global": {"days": 90}, "tenants": [{"id": "clinic", "days": 4000000, "users": ["u1"]}]}"#,
+        )
+        .unwrap();
+        env::set_var("RETENTION_POLICY_FILE", &path);
+        assert!(matches!(RetentionConfig::from_env(), Err(StoreError::Config(_))));
+        std::fs::remove_file(&path).unwrap();
+        clear_env();
+    }
+
+    #[test]
+    fn effective_policy_is_the_shortest_that_applies() {
+        let config: RetentionConfig = serde_json::from_str(
+            r#"{
+                "global": {"days": 365},
+                "tenants": [
+                    {"id": "clinic", "days": 90, "action": "anonymize", "users": ["u1", "u2"]},
+                    {"id": "ward", "days": 30, "users": ["u2"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let view = RetentionView::new(&config, "u1", None);
+        assert_eq!(view.tenant, Some(policy(90, ExpiryAction::Anonymize)));
+        assert_eq!(view.effective, view.tenant);
+
+        let view = RetentionView::new(&config, "u2", None);
+        assert_eq!(view.effective, Some(policy(30, ExpiryAction::Delete)));
+
+        let view = RetentionView::new(&config, "u3", Some(policy(7, ExpiryAction::Anonymize)));
+        assert_eq!(view.tenant, None);
+        assert_eq!(view.effective, Some(policy(7, ExpiryAction::Anonymize)));
+
+        // On a tie, deleting wins over anonymizing.
+        let view = RetentionView::new(&config, "u1", Some(policy(90, ExpiryAction::Delete)));
+        assert_eq!(view.effective, Some(policy(90, ExpiryAction::Delete)));
+
+        let view = RetentionView::new(&RetentionConfig::default(), "u1", None);
+        assert_eq!(view.effective, None);
+    }
+
+    #[tokio::test]
+    async fn periods_past_the_calendar_keep_everything() {
+        let store = MemoryStore::new();
+        let expired = expire(&store, Some("u1"), policy(u32::MAX, ExpiryAction::Delete)).await;
+        assert_eq!(expired, 0);
+    }
+}
@@ -0,0 +1,742 @@
+use super::{
+    conversation_key, ConversationStore, Cursor, DeferredWriteHook, DeletionReport, ExpiryAction, Fact,
+    RetentionPolicy, SimilarMessage, StoreError, StoredMessage, Summary, SuppressedFact, Thread,
+    ThreadUpdate, Turn, UserExport, UserKey, EXPIRED_CONTENT,
+};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Marks encrypted text: `enc:<key version>:<base64 nonce || ciphertext>`.
+// Text without the prefix is rejected unless plaintext is allowed.
+const PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+const DEFAULT_ROTATION_DAYS: i64 = 90;
+// Bounds how long a deleted key stays usable on other instances.
+const KEY_CACHE_SECS: u64 = 300;
+
+/// Master keys from `ENCRYPTION_MASTER_KEYS`, as comma-separated
+/// `id:base64-key` pairs. The first is used to wrap data keys; the others
+/// only unwrap keys that haven't been rewrapped yet.
+pub struct MasterKeys {
+    active: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl MasterKeys {
+    pub fn from_env() -> Result<Option<Self>, StoreError> {
+        match env::var("ENCRYPTION_MASTER_KEYS") {
+            Ok(value) if !value.trim().is_empty() => Self::parse(&value).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, StoreError> {
+        let mut active = None;
+        let mut keys = HashMap::new();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, key) = entry.split_once(':').ok_or_else(|| {
+                StoreError::Config("ENCRYPTION_MASTER_KEYS entries must be id:base64-key".to_string())
+            })?;
+            let bytes = STANDARD.decode(key).map_err(|e| {
+                StoreError::Config(format!("Master key '{}' is not valid base64: {}", id, e))
+            })?;
+            let cipher = Aes256Gcm::new_from_slice(&bytes).map_err(|_| {
+                StoreError::Config(format!("Master key '{}' must be 32 bytes", id))
+            })?;
+            active.get_or_insert_with(|| id.to_string());
+            keys.insert(id.to_string(), cipher);
+        }
+        let active = active
+            .ok_or_else(|| StoreError::Config("ENCRYPTION_MASTER_KEYS is empty".to_string()))?;
+        Ok(MasterKeys { active, keys })
+    }
+}
+
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Result<String, StoreError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| StoreError::Crypto("encryption failed".to_string()))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(STANDARD.encode(sealed))
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &str, aad: &[u8]) -> Result<Vec<u8>, StoreError> {
+    let bytes = STANDARD
+        .decode(sealed)
+        .map_err(|e| StoreError::Crypto(format!("malformed ciphertext: {}", e)))?;
+    if bytes.len() < NONCE_LEN {
+        return Err(StoreError::Crypto("malformed ciphertext".to_string()));
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| StoreError::Crypto("decryption failed".to_string()))
+}
+
+// Binds a wrapped data key to its owner and version.
+fn key_aad(user_id: &str, version: i64) -> Vec<u8> {
+    format!("{}:{}", user_id, version).into_bytes()
+}
+
+// Binds encrypted text to its owner, row and field, so ciphertext copied
+// into another row or column fails to decrypt. Ids can contain ':', hence
+// the NUL separators.
+fn field_aad(user_id: &str, row: &str, field: &str) -> Vec<u8> {
+    format!("{}\0{}\0{}", user_id, row, field).into_bytes()
+}
+
+/// A user's unwrapped data keys, by version.
+struct UserKeys {
+    active: i64,
+    ciphers: HashMap<i64, Aes256Gcm>,
+    loaded_at: Instant,
+    allow_plaintext: bool,
+}
+
+impl UserKeys {
+    fn encrypt(&self, aad: &[u8], text: &str) -> Result<String, StoreError> {
+        let sealed = seal(&self.ciphers[&self.active], text.as_bytes(), aad)?;
+        Ok(format!("{}{}:{}", PREFIX, self.active, sealed))
+    }
+
+    fn decrypt(&self, aad: &[u8], text: String) -> Result<String, StoreError> {
+        let Some(rest) = text.strip_prefix(PREFIX) else {
+            if self.allow_plaintext {
+                return Ok(text);
+            }
+            return Err(StoreError::Crypto("stored text is not encrypted".to_string()));
+        };
+        let (version, sealed) = rest
+            .split_once(':')
+            .and_then(|(v, s)| Some((v.parse::<i64>().ok()?, s)))
+            .ok_or_else(|| StoreError::Crypto("malformed ciphertext".to_string()))?;
+        let cipher = self
+            .ciphers
+            .get(&version)
+            .ok_or_else(|| StoreError::Crypto(format!("data key v{} is not available", version)))?;
+        String::from_utf8(open(cipher, sealed, aad)?)
+            .map_err(|_| StoreError::Crypto("decrypted text is not UTF-8".to_string()))
+    }
+}
+
+/// Encrypts message content, summaries, facts and thread titles with
+/// AES-256-GCM under a per-user data key before they reach the wrapped
+/// store, and decrypts them on the way out. Each ciphertext is bound to its
+/// user, row and field: messages by idempotency key, facts and threads by
+/// id, summaries by thread.
+///
+/// Embedding vectors are not encrypted: similarity search runs inside the
+/// database and needs them as they are. They still reveal something about
+/// the text they were computed from, and are deleted along with it.
+///
+/// Data keys are wrapped by the active master key and stored through the
+/// inner store. A data key older than `ENCRYPTION_KEY_ROTATION_DAYS`
+/// (default 90) is retired: new writes get a fresh version while old
+/// versions stay available for reading. Keys wrapped under a retired master
+/// key are rewrapped the next time they are loaded. Once a user's keys are
+/// deleted their ciphertext can no longer be read.
+///
+/// Unencrypted text is an error, since anyone able to write to the database
+/// could otherwise slip it past the row binding. While data written before
+/// encryption was enabled is still around, `ENCRYPTION_ALLOW_PLAINTEXT=true`
+/// lets it through. Expired messages are plaintext by design and always
+/// read.
+pub struct EncryptedStore {
+    inner: Arc<dyn ConversationStore>,
+    master: MasterKeys,
+    rotation: Duration,
+    allow_plaintext: bool,
+    cache: Mutex<HashMap<String, Arc<UserKeys>>>,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Arc<dyn ConversationStore>, master: MasterKeys) -> Self {
+        let days = env::var("ENCRYPTION_KEY_ROTATION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_ROTATION_DAYS);
+        let allow_plaintext = env::var("ENCRYPTION_ALLOW_PLAINTEXT")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        if allow_plaintext {
+            warn!("ENCRYPTION_ALLOW_PLAINTEXT is set: unencrypted stored text will be accepted");
+        }
+        EncryptedStore {
+            inner,
+            master,
+            rotation: Duration::days(days),
+            allow_plaintext,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn wrap(&self, user_id: &str, version: i64, data_key: &[u8]) -> Result<UserKey, StoreError> {
+        let master = &self.master.keys[&self.master.active];
+        Ok(UserKey {
+            version,
+            master_key_id: self.master.active.clone(),
+            wrapped: seal(master, data_key, &key_aad(user_id, version))?,
+            created_at: Utc::now().to_rfc3339(),
+        })
+    }
+
+    fn unwrap(&self, user_id: &str, key: &UserKey) -> Result<Vec<u8>, StoreError> {
+        let master = self.master.keys.get(&key.master_key_id).ok_or_else(|| {
+            StoreError::Crypto(format!("master key '{}' is not configured", key.master_key_id))
+        })?;
+        open(master, &key.wrapped, &key_aad(user_id, key.version))
+    }
+
+    fn is_due_for_rotation(&self, key: &UserKey) -> bool {
+        DateTime::parse_from_rfc3339(&key.created_at)
+            .map(|created| Utc::now().signed_duration_since(created) > self.rotation)
+            .unwrap_or(true)
+    }
+
+    /// The user's data keys. With `for_writing`, makes sure there is an
+    /// active key that isn't due for rotation, creating one if needed.
+    async fn keys(&self, user_id: &str, for_writing: bool) -> Result<Arc<UserKeys>, StoreError> {
+        if let Some(keys) = self.cache.lock().unwrap().get(user_id) {
+            if keys.loaded_at.elapsed().as_secs() < KEY_CACHE_SECS {
+                return Ok(keys.clone());
+            }
+        }
+
+        // Two attempts: a concurrent writer may create the same version first.
+        for _ in 0..2 {
+            let stored = self.inner.user_keys(user_id).await?;
+            let mut ciphers = HashMap::new();
+            for key in &stored {
+                let data_key = self.unwrap(user_id, key)?;
+                if key.master_key_id != self.master.active {
+                    let mut rewrapped = self.wrap(user_id, key.version, &data_key)?;
+                    rewrapped.created_at = key.created_at.clone();
+                    self.inner.update_user_key(user_id, rewrapped).await?;
+                    info!(
+                        "Rewrapped data key v{} for user_id={} under master key '{}'",
+                        key.version, user_id, self.master.active
+                    );
+                }
+                let cipher = Aes256Gcm::new_from_slice(&data_key)
+                    .map_err(|_| StoreError::Crypto("stored data key has the wrong length".to_string()))?;
+                ciphers.insert(key.version, cipher);
+            }
+
+            let newest = stored.last();
+            if for_writing && newest.is_none_or(|k| self.is_due_for_rotation(k)) {
+                let version = newest.map_or(1, |k| k.version + 1);
+                let data_key = Aes256Gcm::generate_key(OsRng);
+                let key = self.wrap(user_id, version, &data_key)?;
+                if !self.inner.add_user_key(user_id, key).await? {
+                    debug!("Data key v{} for user_id={} was created concurrently", version, user_id);
+                    continue;
+                }
+                info!("Created data key v{} for user_id={}", version, user_id);
+                ciphers.insert(version, Aes256Gcm::new(&data_key));
+            }
+
+            let keys = Arc::new(UserKeys {
+                active: ciphers.keys().copied().max().unwrap_or(0),
+                ciphers,
+                loaded_at: Instant::now(),
+                allow_plaintext: self.allow_plaintext,
+            });
+            if !keys.ciphers.is_empty() {
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(user_id.to_string(), keys.clone());
+            }
+            return Ok(keys);
+        }
+        error!("Could not settle on a data key for user_id={}", user_id);
+        Err(StoreError::Crypto("data key creation kept conflicting".to_string()))
+    }
+
+    /// A message read back without its idempotency key can't be decrypted.
+    fn decrypt_message(
+        keys: &UserKeys,
+        user_id: &str,
+        mut message: StoredMessage,
+    ) -> Result<StoredMessage, StoreError> {
+        if message.content == EXPIRED_CONTENT {
+            return Ok(message);
+        }
+        let row = message.idempotency_key.clone().unwrap_or_default();
+        message.content = keys.decrypt(&field_aad(user_id, &row, "message.content"), message.content)?;
+        Ok(message)
+    }
+
+    fn decrypt_fact(keys: &UserKeys, user_id: &str, mut fact: Fact) -> Result<Fact, StoreError> {
+        fact.content = keys.decrypt(&field_aad(user_id, &fact.id, "fact.content"), fact.content)?;
+        fact.source_excerpt = keys.decrypt(
+            &field_aad(user_id, &fact.id, "fact.source_excerpt"),
+            fact.source_excerpt,
+        )?;
+        Ok(fact)
+    }
+
+    /// Suppressed facts keep the deleted fact's id and ciphertext as they were.
+    fn decrypt_suppressed_fact(
+        keys: &UserKeys,
+        user_id: &str,
+        mut fact: SuppressedFact,
+    ) -> Result<SuppressedFact, StoreError> {
+        fact.content = keys.decrypt(&field_aad(user_id, &fact.id, "fact.content"), fact.content)?;
+        Ok(fact)
+    }
+
+    fn decrypt_summary(
+        keys: &UserKeys,
+        user_id: &str,
+        mut summary: Summary,
+    ) -> Result<Summary, StoreError> {
+        let row = conversation_key(summary.conversation_id.as_deref()).to_string();
+        summary.content = keys.decrypt(&field_aad(user_id, &row, "summary.content"), summary.content)?;
+        Ok(summary)
+    }
+
+    fn decrypt_thread(keys: &UserKeys, user_id: &str, mut thread: Thread) -> Result<Thread, StoreError> {
+        thread.title = keys.decrypt(&field_aad(user_id, &thread.id, "thread.title"), thread.title)?;
+        Ok(thread)
+    }
+}
+
+impl ConversationStore for EncryptedStore {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn append_turn<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        mut turn: Turn,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        Box::pin(async move {
+            let keys = self.keys(user_id, true).await?;
+            let plaintexts: Vec<String> = turn.messages.iter().map(|m| m.content.clone()).collect();
+            let message_keys = turn.message_keys();
+            for (message, key) in turn.messages.iter_mut().zip(&message_keys) {
+                message.content = keys.encrypt(&field_aad(user_id, key, "message.content"), &message.content)?;
+            }
+            let mut stored = self.inner.append_turn(user_id, conversation_id, turn).await?;
+            for (message, plaintext) in stored.iter_mut().zip(plaintexts) {
+                message.content = plaintext;
+            }
+            Ok(stored)
+        })
+    }
+
+    fn messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        Box::pin(async move {
+            let page = self.inner.messages(user_id, conversation_id, before, limit).await?;
+            let keys = self.keys(user_id, false).await?;
+            page.into_iter()
+                .map(|m| Self::decrypt_message(&keys, user_id, m))
+                .collect()
+        })
+    }
+
+    fn create_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        mut thread: Thread,
+    ) -> BoxFuture<'a, Result<Thread, StoreError>> {
+        Box::pin(async move {
+            let keys = self.keys(user_id, true).await?;
+            let title = thread.title.clone();
+            thread.title = keys.encrypt(&field_aad(user_id, &thread.id, "thread.title"), &title)?;
+            let mut created = self.inner.create_thread(user_id, thread).await?;
+            created.title = title;
+            Ok(created)
+        })
+    }
+
+    fn list_threads<'a>(
+        &'a self,
+        user_id: &'a str,
+        include_archived: bool,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Thread>, StoreError>> {
+        Box::pin(async move {
+            let threads = self.inner.list_threads(user_id, include_archived, before, limit).await?;
+            let keys = self.keys(user_id, false).await?;
+            threads
+                .into_iter()
+                .map(|t| Self::decrypt_thread(&keys, user_id, t))
+                .collect()
+        })
+    }
+
+    fn get_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        Box::pin(async move {
+            let Some(thread) = self.inner.get_thread(user_id, thread_id).await? else {
+                return Ok(None);
+            };
+            let keys = self.keys(user_id, false).await?;
+            Self::decrypt_thread(&keys, user_id, thread).map(Some)
+        })
+    }
+
+    fn update_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+        mut update: ThreadUpdate,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        Box::pin(async move {
+            let keys = self.keys(user_id, update.title.is_some()).await?;
+            if let Some(title) = &update.title {
+                update.title = Some(keys.encrypt(&field_aad(user_id, thread_id, "thread.title"), title)?);
+            }
+            let Some(thread) = self.inner.update_thread(user_id, thread_id, update).await? else {
+                return Ok(None);
+            };
+            Self::decrypt_thread(&keys, user_id, thread).map(Some)
+        })
+    }
+
+    fn get_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Summary>, StoreError>> {
+        Box::pin(async move {
+            let Some(summary) = self.inner.get_summary(user_id, conversation_id).await? else {
+                return Ok(None);
+            };
+            let keys = self.keys(user_id, false).await?;
+            Self::decrypt_summary(&keys, user_id, summary).map(Some)
+        })
+    }
+
+    fn put_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        mut summary: Summary,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let keys = self.keys(user_id, true).await?;
+            let row = conversation_key(summary.conversation_id.as_deref()).to_string();
+            summary.content = keys.encrypt(&field_aad(user_id, &row, "summary.content"), &summary.content)?;
+            self.inner.put_summary(user_id, summary).await
+        })
+    }
+
+    fn add_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+        facts: Vec<Fact>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let keys = self.keys(user_id, true).await?;
+            let facts = facts
+                .into_iter()
+                .map(|mut f| {
+                    f.content = keys.encrypt(&field_aad(user_id, &f.id, "fact.content"), &f.content)?;
+                    f.source_excerpt = keys.encrypt(
+                        &field_aad(user_id, &f.id, "fact.source_excerpt"),
+                        &f.source_excerpt,
+                    )?;
+                    Ok(f)
+                })
+                .collect::<Result<Vec<_>, StoreError>>()?;
+            self.inner.add_facts(user_id, facts).await
+        })
+    }
+
+    fn list_facts<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<Fact>, StoreError>> {
+        Box::pin(async move {
+            let facts = self.inner.list_facts(user_id).await?;
+            let keys = self.keys(user_id, false).await?;
+            facts
+                .into_iter()
+                .map(|f| Self::decrypt_fact(&keys, user_id, f))
+                .collect()
+        })
+    }
+
+    fn delete_fact<'a>(
+        &'a self,
+        user_id: &'a str,
+        fact_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.inner.delete_fact(user_id, fact_id)
+    }
+
+    fn suppressed_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<SuppressedFact>, StoreError>> {
+        Box::pin(async move {
+            let suppressed = self.inner.suppressed_facts(user_id).await?;
+            let keys = self.keys(user_id, false).await?;
+            suppressed
+                .into_iter()
+                .map(|f| Self::decrypt_suppressed_fact(&keys, user_id, f))
+                .collect()
+        })
+    }
+
+    fn put_embeddings<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.put_embeddings(user_id, model, embeddings)
+    }
+
+    fn similar_messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        query: &'a [f32],
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SimilarMessage>, StoreError>> {
+        Box::pin(async move {
+            let matches = self.inner.similar_messages(user_id, model, query, limit).await?;
+            let keys = self.keys(user_id, false).await?;
+            matches
+                .into_iter()
+                .map(|m| {
+                    Ok(SimilarMessage {
+                        message: Self::decrypt_message(&keys, user_id, m.message)?,
+                        score: m.score,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<UserKey>, StoreError>> {
+        self.inner.user_keys(user_id)
+    }
+
+    fn add_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.inner.add_user_key(user_id, key)
+    }
+
+    fn update_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.update_user_key(user_id, key)
+    }
+
+    fn delete_user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<u64, StoreError>> {
+        self.cache.lock().unwrap().remove(user_id);
+        self.inner.delete_user_keys(user_id)
+    }
+
+    fn retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RetentionPolicy>, StoreError>> {
+        self.inner.retention_policy(user_id)
+    }
+
+    fn set_retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+        policy: Option<RetentionPolicy>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.set_retention_policy(user_id, policy)
+    }
+
+    fn retention_policies(&self) -> BoxFuture<'_, Result<Vec<(String, RetentionPolicy)>, StoreError>> {
+        self.inner.retention_policies()
+    }
+
+    fn expire_messages<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        before: &'a str,
+        action: ExpiryAction,
+    ) -> BoxFuture<'a, Result<u64, StoreError>> {
+        self.inner.expire_messages(user_id, before, action)
+    }
+
+    fn export_user<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<UserExport, StoreError>> {
+        Box::pin(async move {
+            let mut export = self.inner.export_user(user_id).await?;
+            let keys = self.keys(user_id, false).await?;
+            export.threads = std::mem::take(&mut export.threads)
+                .into_iter()
+                .map(|t| Self::decrypt_thread(&keys, user_id, t))
+                .collect::<Result<_, _>>()?;
+            for exported in &mut export.messages {
+                let message = exported.message.clone();
+                exported.message = Self::decrypt_message(&keys, user_id, message)?;
+            }
+            export.summaries = std::mem::take(&mut export.summaries)
+                .into_iter()
+                .map(|s| Self::decrypt_summary(&keys, user_id, s))
+                .collect::<Result<_, _>>()?;
+            export.facts = std::mem::take(&mut export.facts)
+                .into_iter()
+                .map(|f| Self::decrypt_fact(&keys, user_id, f))
+                .collect::<Result<_, _>>()?;
+            export.suppressed_facts = std::mem::take(&mut export.suppressed_facts)
+                .into_iter()
+                .map(|f| Self::decrypt_suppressed_fact(&keys, user_id, f))
+                .collect::<Result<_, _>>()?;
+            Ok(export)
+        })
+    }
+
+    /// Destroys the keys first, so the data is unrecoverable even if
+    /// removing the rows themselves fails partway.
+    fn delete_user<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<DeletionReport, StoreError>> {
+        Box::pin(async move {
+            let keys = self.delete_user_keys(user_id).await?;
+            let mut report = self.inner.delete_user(user_id).await?;
+            report.keys += keys;
+            Ok(report)
+        })
+    }
+
+    /// Messages reach `hook` still encrypted; read them back through this
+    /// store for their text.
+    fn on_deferred_write(&self, hook: DeferredWriteHook) {
+        self.inner.on_deferred_write(hook)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{ChatMessage, MemoryStore};
+
+    fn stores() -> (Arc<MemoryStore>, EncryptedStore) {
+        let inner = Arc::new(MemoryStore::new());
+        let master = MasterKeys::parse(&format!("test:{}", STANDARD.encode([7u8; 32]))).unwrap();
+        (inner.clone(), EncryptedStore::new(inner, master))
+    }
+
+    fn fact(id: &str, content: &str) -> Fact {
+        Fact {
+            id: id.to_string(),
+            category: "personal".to_string(),
+            content: content.to_string(),
+            conversation_id: None,
+            source_excerpt: format!("I said {}", content),
+            created_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_messages_threads_and_facts() {
+        let (inner, store) = stores();
+        let thread = store
+            .create_thread("u1", Thread::new("Work stress".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(thread.title, "Work stress");
+        let stored = inner.get_thread("u1", &thread.id).await.unwrap().unwrap();
+        assert!(stored.title.starts_with(PREFIX));
+        let update = ThreadUpdate {
+            title: Some("Job change".to_string()),
+            archived: None,
+        };
+        let updated = store.update_thread("u1", &thread.id, update).await.unwrap().unwrap();
+        assert_eq!(updated.title, "Job change");
+        let listed = store.list_threads("u1", false, None, 10).await.unwrap();
+        assert_eq!(listed[0].title, "Job change");
+
+        let turn = Turn::new(vec![ChatMessage {
+            role: "user".to_string(),
+            content: "I start on Monday".to_string(),
+        }]);
+        store.append_turn("u1", Some(&thread.id), turn).await.unwrap();
+        let raw = inner.messages("u1", Some(&thread.id), None, 10).await.unwrap();
+        assert!(raw[0].content.starts_with(PREFIX));
+        let page = store.messages("u1", Some(&thread.id), None, 10).await.unwrap();
+        assert_eq!(page[0].content, "I start on Monday");
+
+        store.add_facts("u1", vec![fact("a", "likes tea")]).await.unwrap();
+        let facts = store.list_facts("u1").await.unwrap();
+        assert_eq!(facts[0].content, "likes tea");
+        assert_eq!(facts[0].source_excerpt, "I said likes tea");
+    }
+
+    #[tokio::test]
+    async fn ciphertext_moved_to_another_row_or_field_does_not_decrypt() {
+        let (inner, store) = stores();
+        store.add_facts("u1", vec![fact("a", "likes tea")]).await.unwrap();
+        let mut other_row = inner.list_facts("u1").await.unwrap().remove(0);
+        other_row.id = "b".to_string();
+        inner.add_facts("u1", vec![other_row]).await.unwrap();
+        let err = store.list_facts("u1").await.unwrap_err();
+        assert!(matches!(err, StoreError::Crypto(_)));
+
+        store.add_facts("u2", vec![fact("c", "has a dog")]).await.unwrap();
+        let mut other_field = inner.list_facts("u2").await.unwrap().remove(0);
+        other_field.source_excerpt = other_field.content.clone();
+        inner.delete_fact("u2", "c").await.unwrap();
+        inner.add_facts("u2", vec![other_field]).await.unwrap();
+        let err = store.list_facts("u2").await.unwrap_err();
+        assert!(matches!(err, StoreError::Crypto(_)));
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_rejected_unless_allowed() {
+        let (inner, mut store) = stores();
+        store.add_facts("u1", vec![fact("a", "likes tea")]).await.unwrap();
+        inner.add_facts("u1", vec![fact("b", "planted")]).await.unwrap();
+        let err = store.list_facts("u1").await.unwrap_err();
+        assert!(matches!(err, StoreError::Crypto(_)));
+
+        store.allow_plaintext = true;
+        store.cache.lock().unwrap().clear();
+        let facts = store.list_facts("u1").await.unwrap();
+        assert_eq!(facts[1].content, "planted");
+    }
+
+    #[tokio::test]
+    async fn expired_messages_read_back_as_the_marker() {
+        let (inner, store) = stores();
+        let turn = Turn::new(vec![ChatMessage {
+            role: "user".to_string(),
+            content: "I start on Monday".to_string(),
+        }]);
+        store.append_turn("u1", None, turn).await.unwrap();
+        let cutoff = (Utc::now() + Duration::days(1)).to_rfc3339();
+        inner
+            .expire_messages(Some("u1"), &cutoff, ExpiryAction::Anonymize)
+            .await
+            .unwrap();
+        let page = store.messages("u1", None, None, 10).await.unwrap();
+        assert_eq!(page[0].content, EXPIRED_CONTENT);
+    }
+}
@@ -0,0 +1,512 @@
+use super::{
+    cosine_similarity, top_matches, ConversationStore, Cursor, DeletionReport,
+    ExpiryAction, ExportedEmbedding, ExportedMessage, Fact, RetentionPolicy, SimilarMessage, StoreError,
+    StoredMessage, Summary, SuppressedFact, Thread, ThreadUpdate, Turn, UserExport, UserKey,
+    EXPIRED_CONTENT,
+};
+use chrono::Utc;
+use futures::future::{ready, BoxFuture};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+struct MemoryMessage {
+    conversation_id: Option<String>,
+    idempotency_key: String,
+    stored: StoredMessage,
+}
+
+#[derive(Default)]
+struct UserData {
+    messages: Vec<MemoryMessage>,
+    threads: Vec<Thread>,
+    summaries: Vec<Summary>,
+    facts: Vec<Fact>,
+    suppressed_facts: Vec<SuppressedFact>,
+    // Message id -> (model, vector)
+    embeddings: HashMap<i64, (String, Vec<f32>)>,
+    keys: Vec<UserKey>,
+    retention: Option<RetentionPolicy>,
+}
+
+/// Process-local store. Nothing survives a restart; meant for tests and
+/// local development.
+#[derive(Default)]
+pub struct MemoryStore {
+    users: Mutex<HashMap<String, UserData>>,
+    next_id: AtomicI64,
+}
+
+fn message_before(msg: &StoredMessage, cursor: Option<&Cursor>) -> bool {
+    match cursor {
+        Some(c) => {
+            let id: i64 = c.id.parse().unwrap_or(i64::MAX);
+            (msg.timestamp.as_str(), msg.id) < (c.timestamp.as_str(), id)
+        }
+        None => true,
+    }
+}
+
+fn thread_before(thread: &Thread, cursor: Option<&Cursor>) -> bool {
+    match cursor {
+        Some(c) => (thread.created_at.as_str(), thread.id.as_str()) < (c.timestamp.as_str(), c.id.as_str()),
+        None => true,
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ConversationStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn append_turn<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        turn: Turn,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let data = users.entry(user_id.to_string()).or_default();
+        let keys = turn.message_keys();
+        let existing: Vec<StoredMessage> = data
+            .messages
+            .iter()
+            .filter(|m| keys.contains(&m.idempotency_key))
+            .map(|m| m.stored.clone())
+            .collect();
+        if !existing.is_empty() {
+            return Box::pin(ready(Ok(existing)));
+        }
+
+        let mut stored = Vec::new();
+        for (message, key) in turn.messages.into_iter().zip(keys) {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let message = StoredMessage {
+                id,
+                role: message.role,
+                content: message.content,
+                timestamp: turn.timestamp.clone(),
+                idempotency_key: Some(key.clone()),
+            };
+            data.messages.push(MemoryMessage {
+                conversation_id: conversation_id.map(str::to_string),
+                idempotency_key: key,
+                stored: message.clone(),
+            });
+            stored.push(message);
+        }
+        Box::pin(ready(Ok(stored)))
+    }
+
+    fn messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let page = users
+            .get(user_id)
+            .map(|data| {
+                data.messages
+                    .iter()
+                    .rev()
+                    .filter(|m| m.conversation_id.as_deref() == conversation_id)
+                    .filter(|m| message_before(&m.stored, before))
+                    .take(limit)
+                    .map(|m| m.stored.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(page)))
+    }
+
+    fn create_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread: Thread,
+    ) -> BoxFuture<'a, Result<Thread, StoreError>> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(user_id.to_string())
+            .or_default()
+            .threads
+            .push(thread.clone());
+        Box::pin(ready(Ok(thread)))
+    }
+
+    fn list_threads<'a>(
+        &'a self,
+        user_id: &'a str,
+        include_archived: bool,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Thread>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let threads = users
+            .get(user_id)
+            .map(|data| {
+                data.threads
+                    .iter()
+                    .rev()
+                    .filter(|t| include_archived || !t.archived)
+                    .filter(|t| thread_before(t, before))
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(threads)))
+    }
+
+    fn get_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let thread = users
+            .get(user_id)
+            .and_then(|data| data.threads.iter().find(|t| t.id == thread_id).cloned());
+        Box::pin(ready(Ok(thread)))
+    }
+
+    fn update_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+        update: ThreadUpdate,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let thread = users
+            .get_mut(user_id)
+            .and_then(|data| data.threads.iter_mut().find(|t| t.id == thread_id))
+            .map(|t| {
+                t.apply(&update);
+                t.clone()
+            });
+        Box::pin(ready(Ok(thread)))
+    }
+
+    fn get_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Summary>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let summary = users.get(user_id).and_then(|data| {
+            data.summaries
+                .iter()
+                .find(|s| s.conversation_id.as_deref() == conversation_id)
+                .cloned()
+        });
+        Box::pin(ready(Ok(summary)))
+    }
+
+    fn put_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        summary: Summary,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let summaries = &mut users.entry(user_id.to_string()).or_default().summaries;
+        summaries.retain(|s| s.conversation_id != summary.conversation_id);
+        summaries.push(summary);
+        Box::pin(ready(Ok(())))
+    }
+
+    fn add_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+        facts: Vec<Fact>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(user_id.to_string())
+            .or_default()
+            .facts
+            .extend(facts);
+        Box::pin(ready(Ok(())))
+    }
+
+    fn list_facts<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<Fact>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let facts = users.get(user_id).map(|data| data.facts.clone()).unwrap_or_default();
+        Box::pin(ready(Ok(facts)))
+    }
+
+    fn delete_fact<'a>(
+        &'a self,
+        user_id: &'a str,
+        fact_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let deleted = users.get_mut(user_id).is_some_and(|data| {
+            let Some(index) = data.facts.iter().position(|f| f.id == fact_id) else {
+                return false;
+            };
+            let fact = data.facts.remove(index);
+            data.suppressed_facts.push(SuppressedFact {
+                id: fact.id,
+                content: fact.content,
+                suppressed_at: Utc::now().to_rfc3339(),
+            });
+            true
+        });
+        Box::pin(ready(Ok(deleted)))
+    }
+
+    fn suppressed_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<SuppressedFact>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let suppressed = users
+            .get(user_id)
+            .map(|data| data.suppressed_facts.clone())
+            .unwrap_or_default();
+        Box::pin(ready(Ok(suppressed)))
+    }
+
+    fn put_embeddings<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let data = users.entry(user_id.to_string()).or_default();
+        for (id, vector) in embeddings {
+            data.embeddings.insert(id, (model.to_string(), vector));
+        }
+        Box::pin(ready(Ok(())))
+    }
+
+    fn similar_messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        query: &'a [f32],
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SimilarMessage>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let matches = users
+            .get(user_id)
+            .map(|data| {
+                data.messages
+                    .iter()
+                    .filter_map(|m| {
+                        let (m_model, vector) = data.embeddings.get(&m.stored.id)?;
+                        (m_model == model).then(|| SimilarMessage {
+                            message: m.stored.clone(),
+                            score: cosine_similarity(query, vector),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(top_matches(matches, limit))))
+    }
+
+    fn user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<UserKey>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let keys = users.get(user_id).map(|data| data.keys.clone()).unwrap_or_default();
+        Box::pin(ready(Ok(keys)))
+    }
+
+    fn add_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let keys = &mut users.entry(user_id.to_string()).or_default().keys;
+        let added = !keys.iter().any(|k| k.version == key.version);
+        if added {
+            keys.push(key);
+        }
+        Box::pin(ready(Ok(added)))
+    }
+
+    fn update_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(existing) = users
+            .get_mut(user_id)
+            .and_then(|data| data.keys.iter_mut().find(|k| k.version == key.version))
+        {
+            *existing = key;
+        }
+        Box::pin(ready(Ok(())))
+    }
+
+    fn delete_user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<u64, StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let deleted = users
+            .get_mut(user_id)
+            .map(|data| std::mem::take(&mut data.keys).len() as u64)
+            .unwrap_or(0);
+        Box::pin(ready(Ok(deleted)))
+    }
+
+    fn retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RetentionPolicy>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let policy = users.get(user_id).and_then(|data| data.retention);
+        Box::pin(ready(Ok(policy)))
+    }
+
+    fn set_retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+        policy: Option<RetentionPolicy>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(user_id.to_string())
+            .or_default()
+            .retention = policy;
+        Box::pin(ready(Ok(())))
+    }
+
+    fn retention_policies(&self) -> BoxFuture<'_, Result<Vec<(String, RetentionPolicy)>, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let policies = users
+            .iter()
+            .filter_map(|(user_id, data)| Some((user_id.clone(), data.retention?)))
+            .collect();
+        Box::pin(ready(Ok(policies)))
+    }
+
+    fn expire_messages<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        before: &'a str,
+        action: ExpiryAction,
+    ) -> BoxFuture<'a, Result<u64, StoreError>> {
+        let mut users = self.users.lock().unwrap();
+        let mut expired = 0;
+        for (_, data) in users
+            .iter_mut()
+            .filter(|(id, _)| user_id.is_none_or(|u| u == id.as_str()))
+        {
+            let UserData {
+                messages,
+                summaries,
+                facts,
+                embeddings,
+                ..
+            } = data;
+            let mut touched = HashSet::new();
+            messages.retain_mut(|m| {
+                if m.stored.timestamp.as_str() >= before {
+                    return true;
+                }
+                match action {
+                    ExpiryAction::Delete => {
+                        embeddings.remove(&m.stored.id);
+                        touched.insert(m.conversation_id.clone());
+                        expired += 1;
+                        false
+                    }
+                    ExpiryAction::Anonymize => {
+                        if m.stored.content != EXPIRED_CONTENT {
+                            m.stored.content = EXPIRED_CONTENT.to_string();
+                            embeddings.remove(&m.stored.id);
+                            touched.insert(m.conversation_id.clone());
+                            expired += 1;
+                        }
+                        true
+                    }
+                }
+            });
+            summaries.retain(|s| !touched.contains(&s.conversation_id));
+            facts.retain(|f| f.created_at.as_str() >= before);
+        }
+        Box::pin(ready(Ok(expired)))
+    }
+
+    fn export_user<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<UserExport, StoreError>> {
+        let users = self.users.lock().unwrap();
+        let (threads, messages, summaries, facts, suppressed_facts, embeddings, retention) = users
+            .get(user_id)
+            .map(|data| {
+                let messages = data
+                    .messages
+                    .iter()
+                    .map(|m| ExportedMessage {
+                        conversation_id: m.conversation_id.clone(),
+                        message: m.stored.clone(),
+                    })
+                    .collect();
+                let mut embeddings: Vec<ExportedEmbedding> = data
+                    .embeddings
+                    .iter()
+                    .map(|(id, (model, vector))| ExportedEmbedding {
+                        message_id: *id,
+                        model: model.clone(),
+                        vector: vector.clone(),
+                    })
+                    .collect();
+                embeddings.sort_by_key(|e| e.message_id);
+                (
+                    data.threads.clone(),
+                    messages,
+                    data.summaries.clone(),
+                    data.facts.clone(),
+                    data.suppressed_facts.clone(),
+                    embeddings,
+                    data.retention,
+                )
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(UserExport {
+            user_id: user_id.to_string(),
+            exported_at: Utc::now().to_rfc3339(),
+            threads,
+            messages,
+            summaries,
+            facts,
+            suppressed_facts,
+            embeddings,
+            retention,
+        })))
+    }
+
+    fn delete_user<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<DeletionReport, StoreError>> {
+        let report = self
+            .users
+            .lock()
+            .unwrap()
+            .remove(user_id)
+            .map(|data| DeletionReport {
+                messages: data.messages.len() as u64,
+                threads: data.threads.len() as u64,
+                summaries: data.summaries.len() as u64,
+                facts: data.facts.len() as u64,
+                suppressed_facts: data.suppressed_facts.len() as u64,
+                embeddings: data.embeddings.len() as u64,
+                keys: data.keys.len() as u64,
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(report)))
+    }
+}
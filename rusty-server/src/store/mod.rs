@@ -0,0 +1,578 @@
+mod encrypted;
+mod memory;
+mod outbox;
+mod postgrest;
+mod sqlite;
+mod supabase;
+
+pub use encrypted::EncryptedStore;
+pub use memory::MemoryStore;
+pub use outbox::OutboxStore;
+pub use sqlite::SqliteStore;
+pub use supabase::SupabaseStore;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Store configuration error: {0}")]
+    Config(String),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Supabase error: {0}")]
+    Supabase(String),
+    /// The backend answered but is overloaded or failing (5xx, 429).
+    #[error("Store unavailable: {0}")]
+    Unavailable(String),
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Store task failed: {0}")]
+    Task(String),
+    #[error("Encryption error: {0}")]
+    Crypto(String),
+}
+
+impl StoreError {
+    /// Whether retrying the same call later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StoreError::Http(_) | StoreError::Unavailable(_))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// Messages written together: all of them or none, and at most once per
+/// `id`, so a retried write never duplicates a turn.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Turn {
+    pub id: String,
+    /// Shared by every message in the turn; they are ordered by id.
+    pub timestamp: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+impl Turn {
+    pub fn new(messages: Vec<ChatMessage>) -> Self {
+        Turn {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            messages,
+        }
+    }
+
+    /// Per-message idempotency keys, in order.
+    fn message_keys(&self) -> Vec<String> {
+        (0..self.messages.len())
+            .map(|i| format!("{}:{}", self.id, i))
+            .collect()
+    }
+}
+
+/// Told about a turn a store wrote after `append_turn` had returned: the
+/// user, the thread and the messages as that store holds them.
+pub type DeferredWriteHook = Arc<dyn Fn(&str, Option<&str>, Vec<StoredMessage>) + Send + Sync>;
+
+/// A message as persisted, with its store-assigned id and timestamp.
+#[derive(Clone, Debug, Serialize)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub role: String,
+    pub content: String,
+    pub timestamp: String,
+    /// The key the message was written under (see `Turn`), if the store
+    /// returned it.
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
+}
+
+/// A stored message returned by similarity search, with its cosine
+/// similarity to the query.
+#[derive(Clone, Debug)]
+pub struct SimilarMessage {
+    pub message: StoredMessage,
+    pub score: f32,
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denom = norm(a) * norm(b);
+    if denom == 0.0 {
+        0.0
+    } else {
+        dot / denom
+    }
+}
+
+/// Best `limit` matches first.
+fn top_matches(mut matches: Vec<SimilarMessage>, limit: usize) -> Vec<SimilarMessage> {
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches.truncate(limit);
+    matches
+}
+
+/// Keyset pagination position. A page continues with the items strictly
+/// older than `(timestamp, id)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub timestamp: String,
+    pub id: String,
+}
+
+impl Cursor {
+    pub fn new(timestamp: &str, id: impl ToString) -> Self {
+        Cursor {
+            timestamp: timestamp.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    /// Opaque, URL-safe form handed to clients.
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}\n{}", self.timestamp, self.id))
+    }
+
+    pub fn decode(value: &str) -> Option<Self> {
+        let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(value).ok()?).ok()?;
+        let (timestamp, id) = raw.split_once('\n')?;
+        DateTime::parse_from_rfc3339(timestamp).ok()?;
+        Some(Cursor::new(timestamp, id))
+    }
+}
+
+/// A named conversation thread. Messages sent without a `conversation_id`
+/// belong to the user's untitled default stream, which has no `Thread` row.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Thread {
+    pub id: String,
+    pub title: String,
+    pub archived: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Thread {
+    pub fn new(title: String) -> Self {
+        let now = Utc::now().to_rfc3339();
+        Thread {
+            id: Uuid::new_v4().to_string(),
+            title,
+            archived: false,
+            created_at: now.clone(),
+            updated_at: now,
+        }
+    }
+
+    fn apply(&mut self, update: &ThreadUpdate) {
+        if let Some(title) = &update.title {
+            self.title = title.clone();
+        }
+        if let Some(archived) = update.archived {
+            self.archived = archived;
+        }
+        self.updated_at = Utc::now().to_rfc3339();
+    }
+}
+
+/// Running summary of the turns that have scrolled out of a thread's
+/// prompt window.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub conversation_id: Option<String>,
+    pub content: String,
+    /// Position of the newest message folded into `content`.
+    pub through_timestamp: String,
+    pub through_id: i64,
+    pub updated_at: String,
+}
+
+/// A durable fact about the user (a name, goal, trigger...) extracted from
+/// a conversation, with where it came from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fact {
+    pub id: String,
+    pub category: String,
+    pub content: String,
+    /// Thread the fact was mentioned in; `None` for the default stream.
+    pub conversation_id: Option<String>,
+    /// The user's words the fact was extracted from.
+    pub source_excerpt: String,
+    pub created_at: String,
+}
+
+impl Fact {
+    pub fn new(
+        category: String,
+        content: String,
+        conversation_id: Option<String>,
+        source_excerpt: String,
+    ) -> Self {
+        Fact {
+            id: Uuid::new_v4().to_string(),
+            category,
+            content,
+            conversation_id,
+            source_excerpt,
+            created_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// What is left of a fact the user deleted: enough to recognise it if a
+/// later conversation mentions it again, so it isn't extracted anew.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SuppressedFact {
+    /// Id of the deleted fact.
+    pub id: String,
+    pub content: String,
+    pub suppressed_at: String,
+}
+
+/// One version of a user's data key, encrypted ("wrapped") under a master
+/// key from config. Only the wrapped form is ever stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserKey {
+    pub version: i64,
+    pub master_key_id: String,
+    /// Base64 of nonce || ciphertext.
+    pub wrapped: String,
+    pub created_at: String,
+}
+
+/// What happens to messages once they pass their retention period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryAction {
+    #[default]
+    Delete,
+    /// Keeps the message's place in its thread but replaces its content
+    /// with `EXPIRED_CONTENT` and drops its embedding.
+    Anonymize,
+}
+
+impl ExpiryAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExpiryAction::Delete => "delete",
+            ExpiryAction::Anonymize => "anonymize",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "delete" => Some(ExpiryAction::Delete),
+            "anonymize" => Some(ExpiryAction::Anonymize),
+            _ => None,
+        }
+    }
+}
+
+/// Content left in place of an anonymized message.
+pub const EXPIRED_CONTENT: &str = "[expired]";
+
+/// Messages older than `days` are expired with `action`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub days: u32,
+    #[serde(default)]
+    pub action: ExpiryAction,
+}
+
+// Summaries are keyed per thread; the default stream has no id of its own.
+fn conversation_key(conversation_id: Option<&str>) -> &str {
+    conversation_id.unwrap_or("default")
+}
+
+fn conversation_from_key(key: String) -> Option<String> {
+    if key == "default" {
+        None
+    } else {
+        Some(key)
+    }
+}
+
+/// Everything stored about one user, as returned by `GET /me/export`.
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub user_id: String,
+    pub exported_at: String,
+    pub threads: Vec<Thread>,
+    pub messages: Vec<ExportedMessage>,
+    pub summaries: Vec<Summary>,
+    pub facts: Vec<Fact>,
+    pub suppressed_facts: Vec<SuppressedFact>,
+    pub embeddings: Vec<ExportedEmbedding>,
+    pub retention: Option<RetentionPolicy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedMessage {
+    pub conversation_id: Option<String>,
+    #[serde(flatten)]
+    pub message: StoredMessage,
+}
+
+/// A message's vector from `put_embeddings`, with the model that made it.
+#[derive(Debug, Serialize)]
+pub struct ExportedEmbedding {
+    pub message_id: i64,
+    pub model: String,
+    pub vector: Vec<f32>,
+}
+
+/// Row counts removed by `delete_user`, per kind of data.
+#[derive(Debug, Default, Serialize)]
+pub struct DeletionReport {
+    pub messages: u64,
+    pub threads: u64,
+    pub summaries: u64,
+    pub facts: u64,
+    pub suppressed_facts: u64,
+    pub embeddings: u64,
+    pub keys: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ThreadUpdate {
+    pub title: Option<String>,
+    pub archived: Option<bool>,
+}
+
+/// Persistence for conversation messages. One backend is chosen at startup
+/// and registered as `web::Data<dyn ConversationStore>`.
+pub trait ConversationStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Stores a turn's messages atomically, returning them with their
+    /// assigned ids. Writing a turn id that is already stored changes
+    /// nothing and returns the stored messages. The result is empty if the
+    /// write was queued for later delivery.
+    fn append_turn<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        turn: Turn,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>>;
+
+    /// One page of stored messages in a thread, newest first, starting
+    /// after `before` when given.
+    fn messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>>;
+
+    /// Stores `thread` (usually from `Thread::new`) and returns it as stored.
+    fn create_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread: Thread,
+    ) -> BoxFuture<'a, Result<Thread, StoreError>>;
+
+    /// One page of `user_id`'s threads, newest first, starting after
+    /// `before` when given.
+    fn list_threads<'a>(
+        &'a self,
+        user_id: &'a str,
+        include_archived: bool,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Thread>, StoreError>>;
+
+    /// `None` if the thread doesn't exist or belongs to someone else.
+    fn get_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>>;
+
+    /// Renames and/or (un)archives a thread, returning the updated row.
+    fn update_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+        update: ThreadUpdate,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>>;
+
+    fn get_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Summary>, StoreError>>;
+
+    /// Inserts or replaces the summary for `summary.conversation_id`.
+    fn put_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        summary: Summary,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    fn add_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+        facts: Vec<Fact>,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// All of `user_id`'s facts, oldest first.
+    fn list_facts<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<Fact>, StoreError>>;
+
+    /// Returns whether a fact was deleted. A deleted fact is kept as a
+    /// `SuppressedFact`, so extraction doesn't add it back.
+    fn delete_fact<'a>(
+        &'a self,
+        user_id: &'a str,
+        fact_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>>;
+
+    /// The facts `user_id` has deleted, oldest first.
+    fn suppressed_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<SuppressedFact>, StoreError>>;
+
+    /// Stores embedding vectors for messages, as `(message id, vector)`.
+    /// `model` names the vector space; vectors are only compared within one.
+    fn put_embeddings<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// The `limit` messages of `user_id`, across all threads, whose `model`
+    /// embeddings are most similar to `query`, best first.
+    fn similar_messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        query: &'a [f32],
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SimilarMessage>, StoreError>>;
+
+    /// All versions of `user_id`'s data key, oldest first.
+    fn user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<UserKey>, StoreError>>;
+
+    /// Stores a new key version. Returns false, storing nothing, if that
+    /// version already exists.
+    fn add_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<bool, StoreError>>;
+
+    /// Replaces the wrapping of an existing key version.
+    fn update_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// Removes every version of `user_id`'s data key, returning how many.
+    fn delete_user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<u64, StoreError>>;
+
+    /// The retention period `user_id` chose for their own messages.
+    fn retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RetentionPolicy>, StoreError>>;
+
+    /// Sets `user_id`'s retention period, or clears it with `None`.
+    fn set_retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+        policy: Option<RetentionPolicy>,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// Every user-chosen retention period, as `(user id, policy)`.
+    fn retention_policies(&self) -> BoxFuture<'_, Result<Vec<(String, RetentionPolicy)>, StoreError>>;
+
+    /// Deletes or anonymizes the messages stored before `before` (RFC 3339),
+    /// for one user or, with `None`, for everyone. Returns how many messages
+    /// changed; already anonymized messages are not counted again.
+    ///
+    /// What was derived from those messages goes with them: the summaries
+    /// of the threads they belonged to are dropped, to be rebuilt from the
+    /// messages that remain, and facts extracted before `before` are
+    /// deleted.
+    fn expire_messages<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        before: &'a str,
+        action: ExpiryAction,
+    ) -> BoxFuture<'a, Result<u64, StoreError>>;
+
+    /// Everything stored for `user_id`, oldest first, including derived
+    /// data such as summaries and embeddings.
+    fn export_user<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<UserExport, StoreError>>;
+
+    /// Permanently removes everything belonging to `user_id`.
+    fn delete_user<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<DeletionReport, StoreError>>;
+
+    /// Registers `hook` for turns written in the background, such as queued
+    /// writes replayed after an outage. Stores that only write within
+    /// `append_turn` have nothing to report.
+    fn on_deferred_write(&self, _hook: DeferredWriteHook) {}
+}
+
+/// Picks the backend named by `CONVERSATION_STORE` (`supabase`, `sqlite` or
+/// `memory`), defaulting to Supabase, and wraps it in `EncryptedStore` when
+/// `ENCRYPTION_MASTER_KEYS` is set.
+pub fn store_from_env() -> Result<Arc<dyn ConversationStore>, StoreError> {
+    let store = backend_from_env()?;
+    match encrypted::MasterKeys::from_env()? {
+        Some(master_keys) => {
+            info!("Encrypting conversation content at rest");
+            Ok(Arc::new(EncryptedStore::new(store, master_keys)))
+        }
+        None => Ok(store),
+    }
+}
+
+fn backend_from_env() -> Result<Arc<dyn ConversationStore>, StoreError> {
+    let store: Arc<dyn ConversationStore> = match env::var("CONVERSATION_STORE")
+        .unwrap_or_else(|_| "supabase".to_string())
+        .as_str()
+    {
+        "supabase" => {
+            let supabase = Arc::new(SupabaseStore::from_env()?);
+            let path = env::var("OUTBOX_PATH").unwrap_or_else(|_| "hearthly-outbox.db".to_string());
+            OutboxStore::open(supabase, &path)?.start()?
+        }
+        "sqlite" => {
+            let path = env::var("SQLITE_PATH").unwrap_or_else(|_| "hearthly.db".to_string());
+            Arc::new(SqliteStore::open(&path)?)
+        }
+        "memory" => Arc::new(MemoryStore::new()),
+        other => {
+            error!("Unknown CONVERSATION_STORE '{}'", other);
+            return Err(StoreError::Config(format!(
+                "unknown CONVERSATION_STORE '{}'",
+                other
+            )));
+        }
+    };
+    info!("Using conversation store: {}", store.name());
+    Ok(store)
+}
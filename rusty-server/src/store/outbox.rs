@@ -0,0 +1,516 @@
+use super::{
+    ConversationStore, Cursor, DeferredWriteHook, DeletionReport, ExpiryAction, Fact,
+    RetentionPolicy, SimilarMessage, StoreError, StoredMessage, Summary, SuppressedFact, Thread,
+    ThreadUpdate, Turn, UserExport, UserKey,
+};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use log::{debug, error, info};
+use rusqlite::{params, Connection};
+use std::env;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT NOT NULL,
+    conversation_id TEXT,
+    turn TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    dead INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL
+);
+";
+
+const WRITE_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY_MS: u64 = 200;
+const DEFAULT_REPLAY_SECS: u64 = 15;
+
+struct PendingTurn {
+    id: i64,
+    user_id: String,
+    conversation_id: Option<String>,
+    turn: Turn,
+}
+
+/// Makes turn writes survive a backend outage. Each write is retried with
+/// backoff; if the backend is still unavailable, the turn is saved to a
+/// local SQLite outbox and replayed in order by a background task once the
+/// backend recovers. Queued turns keep their original timestamps and
+/// idempotency keys, so a replay never reorders or duplicates messages.
+///
+/// Turns that the backend rejects outright are kept in the outbox, marked
+/// dead, for an operator to inspect.
+pub struct OutboxStore {
+    inner: Arc<dyn ConversationStore>,
+    outbox: Arc<Mutex<Connection>>,
+    // Held by a replay pass from reading the queue to settling its last
+    // turn, and by account deletion, so neither sees the other half done.
+    delivery: AsyncMutex<()>,
+    on_replayed: OnceLock<DeferredWriteHook>,
+}
+
+impl OutboxStore {
+    pub fn open(inner: Arc<dyn ConversationStore>, path: &str) -> Result<Self, StoreError> {
+        info!("Opening write outbox at {}", path);
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(OutboxStore {
+            inner,
+            outbox: Arc::new(Mutex::new(conn)),
+            delivery: AsyncMutex::new(()),
+            on_replayed: OnceLock::new(),
+        })
+    }
+
+    /// Starts replaying the outbox every `OUTBOX_REPLAY_SECS` (default 15,
+    /// at least 1) for as long as the store is alive.
+    pub fn start(self) -> Result<Arc<dyn ConversationStore>, StoreError> {
+        let interval = match env::var("OUTBOX_REPLAY_SECS") {
+            Ok(value) => value.parse().ok().filter(|secs| *secs > 0).ok_or_else(|| {
+                StoreError::Config(format!(
+                    "OUTBOX_REPLAY_SECS must be a whole number of seconds, at least 1, not '{}'",
+                    value
+                ))
+            })?,
+            Err(_) => DEFAULT_REPLAY_SECS,
+        };
+        let store = Arc::new(self);
+        let weak: Weak<OutboxStore> = Arc::downgrade(&store);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+            loop {
+                ticker.tick().await;
+                let Some(store) = weak.upgrade() else {
+                    return;
+                };
+                if let Err(e) = store.replay().await {
+                    error!("Outbox replay failed: {}", e);
+                }
+            }
+        });
+        Ok(store)
+    }
+
+    /// Runs `f` against the outbox database on the blocking thread pool.
+    async fn with_outbox<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let conn = self.outbox.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| StoreError::Task(e.to_string()))?
+    }
+
+    async fn write_with_retries(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+        turn: &Turn,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.append_turn(user_id, conversation_id, turn.clone()).await {
+                Err(e) if e.is_transient() && attempt < WRITE_ATTEMPTS => {
+                    let delay = RETRY_BASE_DELAY_MS << (attempt - 1);
+                    debug!("Turn write attempt {} failed ({}), retrying in {}ms", attempt, e, delay);
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn enqueue(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+        turn: &Turn,
+        reason: &StoreError,
+    ) -> Result<(), StoreError> {
+        let user_id = user_id.to_string();
+        let conversation_id = conversation_id.map(str::to_string);
+        let payload = serde_json::to_string(turn)?;
+        let reason = reason.to_string();
+        self.with_outbox(move |conn| {
+            conn.execute(
+                "INSERT INTO outbox (user_id, conversation_id, turn, last_error, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![user_id, conversation_id, payload, reason, Utc::now().to_rfc3339()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn pending(&self) -> Result<Vec<PendingTurn>, StoreError> {
+        self.with_outbox(|conn| {
+            let rows = conn
+                .prepare(
+                    "SELECT id, user_id, conversation_id, turn FROM outbox
+                     WHERE dead = 0 ORDER BY id",
+                )?
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            rows.into_iter()
+                .map(|(id, user_id, conversation_id, turn)| {
+                    Ok(PendingTurn {
+                        id,
+                        user_id,
+                        conversation_id,
+                        turn: serde_json::from_str(&turn)?,
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    /// Records the outcome of one replay attempt: delivered turns are
+    /// removed, the others keep their error.
+    async fn settle(&self, id: i64, failure: Option<(String, bool)>) -> Result<(), StoreError> {
+        self.with_outbox(move |conn| {
+            match failure {
+                None => conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?,
+                Some((error, dead)) => conn.execute(
+                    "UPDATE outbox SET attempts = attempts + 1, last_error = ?2, dead = ?3
+                     WHERE id = ?1",
+                    params![id, error, dead],
+                )?,
+            };
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delivers queued turns oldest first, stopping at the first transient
+    /// failure so later turns don't overtake earlier ones.
+    async fn replay(&self) -> Result<(), StoreError> {
+        let _delivering = self.delivery.lock().await;
+        let pending = self.pending().await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut delivered = 0;
+        for entry in &pending {
+            let result = self
+                .inner
+                .append_turn(&entry.user_id, entry.conversation_id.as_deref(), entry.turn.clone())
+                .await;
+            match result {
+                Ok(stored) => {
+                    self.settle(entry.id, None).await?;
+                    delivered += 1;
+                    if let Some(hook) = self.on_replayed.get() {
+                        hook(&entry.user_id, entry.conversation_id.as_deref(), stored);
+                    }
+                }
+                Err(e) if e.is_transient() => {
+                    self.settle(entry.id, Some((e.to_string(), false))).await?;
+                    debug!("Backend still unavailable, {} turns remain queued", pending.len() - delivered);
+                    break;
+                }
+                Err(e) => {
+                    error!(
+                        "Outbox turn {} for user_id={} was rejected, marking dead: {}",
+                        entry.turn.id, entry.user_id, e
+                    );
+                    self.settle(entry.id, Some((e.to_string(), true))).await?;
+                }
+            }
+        }
+        if delivered > 0 {
+            info!("Replayed {} queued turns from the outbox", delivered);
+        }
+        Ok(())
+    }
+
+    async fn discard_user(&self, user_id: &str) -> Result<u64, StoreError> {
+        let user_id = user_id.to_string();
+        self.with_outbox(move |conn| {
+            Ok(conn.execute("DELETE FROM outbox WHERE user_id = ?1", params![user_id])? as u64)
+        })
+        .await
+    }
+}
+
+impl ConversationStore for OutboxStore {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn append_turn<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        turn: Turn,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        Box::pin(async move {
+            match self.write_with_retries(user_id, conversation_id, &turn).await {
+                Err(e) if e.is_transient() => {
+                    error!(
+                        "Backend unavailable, queueing turn {} for user_id={}: {}",
+                        turn.id, user_id, e
+                    );
+                    self.enqueue(user_id, conversation_id, &turn, &e).await?;
+                    Ok(Vec::new())
+                }
+                result => result,
+            }
+        })
+    }
+
+    fn messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        self.inner.messages(user_id, conversation_id, before, limit)
+    }
+
+    fn create_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread: Thread,
+    ) -> BoxFuture<'a, Result<Thread, StoreError>> {
+        self.inner.create_thread(user_id, thread)
+    }
+
+    fn list_threads<'a>(
+        &'a self,
+        user_id: &'a str,
+        include_archived: bool,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Thread>, StoreError>> {
+        self.inner.list_threads(user_id, include_archived, before, limit)
+    }
+
+    fn get_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        self.inner.get_thread(user_id, thread_id)
+    }
+
+    fn update_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+        update: ThreadUpdate,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        self.inner.update_thread(user_id, thread_id, update)
+    }
+
+    fn get_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Summary>, StoreError>> {
+        self.inner.get_summary(user_id, conversation_id)
+    }
+
+    fn put_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        summary: Summary,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.put_summary(user_id, summary)
+    }
+
+    fn add_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+        facts: Vec<Fact>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.add_facts(user_id, facts)
+    }
+
+    fn list_facts<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<Fact>, StoreError>> {
+        self.inner.list_facts(user_id)
+    }
+
+    fn delete_fact<'a>(
+        &'a self,
+        user_id: &'a str,
+        fact_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.inner.delete_fact(user_id, fact_id)
+    }
+
+    fn suppressed_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<SuppressedFact>, StoreError>> {
+        self.inner.suppressed_facts(user_id)
+    }
+
+    fn put_embeddings<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.put_embeddings(user_id, model, embeddings)
+    }
+
+    fn similar_messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        query: &'a [f32],
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SimilarMessage>, StoreError>> {
+        self.inner.similar_messages(user_id, model, query, limit)
+    }
+
+    fn user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<UserKey>, StoreError>> {
+        self.inner.user_keys(user_id)
+    }
+
+    fn add_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.inner.add_user_key(user_id, key)
+    }
+
+    fn update_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.update_user_key(user_id, key)
+    }
+
+    fn delete_user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<u64, StoreError>> {
+        self.inner.delete_user_keys(user_id)
+    }
+
+    fn retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RetentionPolicy>, StoreError>> {
+        self.inner.retention_policy(user_id)
+    }
+
+    fn set_retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+        policy: Option<RetentionPolicy>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.set_retention_policy(user_id, policy)
+    }
+
+    fn retention_policies(&self) -> BoxFuture<'_, Result<Vec<(String, RetentionPolicy)>, StoreError>> {
+        self.inner.retention_policies()
+    }
+
+    fn expire_messages<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        before: &'a str,
+        action: ExpiryAction,
+    ) -> BoxFuture<'a, Result<u64, StoreError>> {
+        self.inner.expire_messages(user_id, before, action)
+    }
+
+    fn export_user<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<UserExport, StoreError>> {
+        self.inner.export_user(user_id)
+    }
+
+    /// Queued turns are dropped first so a later replay can't bring the
+    /// user's messages back, after waiting out any replay under way.
+    fn delete_user<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<DeletionReport, StoreError>> {
+        Box::pin(async move {
+            let _delivering = self.delivery.lock().await;
+            let queued = self.discard_user(user_id).await?;
+            if queued > 0 {
+                info!("Discarded {} queued turns for user_id={}", queued, user_id);
+            }
+            self.inner.delete_user(user_id).await
+        })
+    }
+
+    /// Replayed turns are reported to `hook`, as the backend stored them.
+    fn on_deferred_write(&self, hook: DeferredWriteHook) {
+        if self.on_replayed.set(hook).is_err() {
+            error!("Outbox replay hook registered twice, keeping the first");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{ChatMessage, MemoryStore};
+
+    fn turn() -> Turn {
+        Turn::new(vec![
+            ChatMessage {
+                role: "user".to_string(),
+                content: "hello".to_string(),
+            },
+            ChatMessage {
+                role: "assistant".to_string(),
+                content: "hi".to_string(),
+            },
+        ])
+    }
+
+    fn outbox() -> (OutboxStore, Arc<dyn ConversationStore>) {
+        let inner: Arc<dyn ConversationStore> = Arc::new(MemoryStore::new());
+        (OutboxStore::open(inner.clone(), ":memory:").unwrap(), inner)
+    }
+
+    #[tokio::test]
+    async fn replay_delivers_queued_turns_and_reports_them() {
+        let (outbox, inner) = outbox();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        outbox.on_deferred_write(Arc::new(move |user_id, conversation_id, messages| {
+            assert_eq!((user_id, conversation_id), ("user-1", Some("thread-1")));
+            sink.lock().unwrap().extend(messages);
+        }));
+        let reason = StoreError::Unavailable("down".to_string());
+        outbox.enqueue("user-1", Some("thread-1"), &turn(), &reason).await.unwrap();
+
+        outbox.replay().await.unwrap();
+        let history = inner.messages("user-1", Some("thread-1"), None, 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(outbox.pending().await.unwrap().is_empty());
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["hello", "hi"]);
+    }
+
+    #[tokio::test]
+    async fn deleted_users_turns_are_not_replayed() {
+        let (outbox, inner) = outbox();
+        let reason = StoreError::Unavailable("down".to_string());
+        outbox.enqueue("user-1", None, &turn(), &reason).await.unwrap();
+
+        outbox.delete_user("user-1").await.unwrap();
+        outbox.replay().await.unwrap();
+        assert!(inner.messages("user-1", None, None, 10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+use reqwest::{Method, RequestBuilder};
+use serde_json::Value;
+use std::fmt::Display;
+
+#[derive(Clone, Copy)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// What an upsert does with rows that collide on the conflict columns.
+#[derive(Clone, Copy)]
+pub enum Resolution {
+    MergeDuplicates,
+    IgnoreDuplicates,
+}
+
+/// Double-quotes a value for use inside `in.(...)` and `or=(...)` lists,
+/// where commas, dots and parentheses would otherwise be syntax.
+fn quote(value: impl Display) -> String {
+    format!(
+        "\"{}\"",
+        value.to_string().replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// One condition inside a logical group, e.g. for keyset pagination:
+/// `Condition::lt("timestamp", ts)` or `Condition::and(vec![...])`.
+pub struct Condition(String);
+
+impl Condition {
+    pub fn eq(column: &str, value: impl Display) -> Self {
+        Condition(format!("{}.eq.{}", column, quote(value)))
+    }
+
+    pub fn lt(column: &str, value: impl Display) -> Self {
+        Condition(format!("{}.lt.{}", column, quote(value)))
+    }
+
+    pub fn and(conditions: Vec<Condition>) -> Self {
+        Condition(format!("and({})", Self::join(conditions)))
+    }
+
+    fn join(conditions: Vec<Condition>) -> String {
+        conditions
+            .into_iter()
+            .map(|c| c.0)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// A PostgREST request against one table or RPC function. Values are
+/// passed as URL query parameters, so reqwest percent-encodes them and no
+/// caller ever splices user input into a URL.
+#[derive(Clone)]
+pub struct Query {
+    method: Method,
+    path: String,
+    params: Vec<(String, String)>,
+    order: Vec<String>,
+    prefer: Vec<String>,
+    range: Option<(usize, usize)>,
+    body: Option<Value>,
+}
+
+impl Query {
+    fn new(method: Method, path: String) -> Self {
+        Query {
+            method,
+            path,
+            params: Vec::new(),
+            order: Vec::new(),
+            prefer: Vec::new(),
+            range: None,
+            body: None,
+        }
+    }
+
+    pub fn select(table: &str, columns: &str) -> Self {
+        Query::new(Method::GET, table.to_string()).param("select", columns)
+    }
+
+    /// Inserts one row (an object) or many (an array) in one statement.
+    pub fn insert(table: &str, rows: Value) -> Self {
+        Query::new(Method::POST, table.to_string()).body(rows)
+    }
+
+    /// Inserts rows, resolving collisions on `on_conflict` (comma-separated
+    /// columns with a unique constraint) as `resolution` says.
+    pub fn upsert(table: &str, rows: Value, on_conflict: &str, resolution: Resolution) -> Self {
+        let prefer = match resolution {
+            Resolution::MergeDuplicates => "resolution=merge-duplicates",
+            Resolution::IgnoreDuplicates => "resolution=ignore-duplicates",
+        };
+        Query::insert(table, rows)
+            .param("on_conflict", on_conflict)
+            .prefer(prefer)
+    }
+
+    pub fn update(table: &str, changes: Value) -> Self {
+        Query::new(Method::PATCH, table.to_string()).body(changes)
+    }
+
+    pub fn delete(table: &str) -> Self {
+        Query::new(Method::DELETE, table.to_string())
+    }
+
+    /// Calls a Postgres function exposed under `/rpc`.
+    pub fn rpc(function: &str, args: Value) -> Self {
+        Query::new(Method::POST, format!("rpc/{}", function)).body(args)
+    }
+
+    fn param(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.params.push((key.to_string(), value.into()));
+        self
+    }
+
+    fn prefer(mut self, preference: &str) -> Self {
+        self.prefer.push(preference.to_string());
+        self
+    }
+
+    fn body(mut self, body: Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    pub fn eq(self, column: &str, value: impl Display) -> Self {
+        self.param(column, format!("eq.{}", value))
+    }
+
+    pub fn neq(self, column: &str, value: impl Display) -> Self {
+        self.param(column, format!("neq.{}", value))
+    }
+
+    pub fn null(self, column: &str) -> Self {
+        self.param(column, "is.null")
+    }
+
+    pub fn is(self, column: &str, value: bool) -> Self {
+        self.param(column, format!("is.{}", value))
+    }
+
+    /// `eq` for `Some`, `is.null` for `None`.
+    pub fn eq_or_null(self, column: &str, value: Option<impl Display>) -> Self {
+        match value {
+            Some(value) => self.eq(column, value),
+            None => self.null(column),
+        }
+    }
+
+    pub fn lt(self, column: &str, value: impl Display) -> Self {
+        self.param(column, format!("lt.{}", value))
+    }
+
+    pub fn one_of<T: Display>(self, column: &str, values: &[T]) -> Self {
+        let list = values.iter().map(quote).collect::<Vec<_>>().join(",");
+        self.param(column, format!("in.({})", list))
+    }
+
+    /// Rows matching any of `conditions`.
+    pub fn or(self, conditions: Vec<Condition>) -> Self {
+        self.param("or", format!("({})", Condition::join(conditions)))
+    }
+
+    /// Adds a sort key; later calls break ties of earlier ones.
+    pub fn order(mut self, column: &str, order: Order) -> Self {
+        let direction = match order {
+            Order::Asc => "asc",
+            Order::Desc => "desc",
+        };
+        self.order.push(format!("{}.{}", column, direction));
+        self
+    }
+
+    pub fn limit(self, limit: usize) -> Self {
+        self.param("limit", limit.to_string())
+    }
+
+    /// Rows `from..=to` of the result, via the `Range` header.
+    pub fn range(mut self, from: usize, to: usize) -> Self {
+        self.range = Some((from, to));
+        self
+    }
+
+    /// Has inserts and updates return the affected rows' `columns`.
+    pub fn returning(self, columns: &str) -> Self {
+        self.param("select", columns).prefer("return=representation")
+    }
+
+    /// Has the response report the affected row count in `Content-Range`.
+    pub fn count_exact(self) -> Self {
+        self.prefer("count=exact")
+    }
+
+    /// Builds the request, starting from `base(method, path)` which adds
+    /// the server URL and credentials.
+    pub fn into_request(self, base: impl FnOnce(Method, &str) -> RequestBuilder) -> RequestBuilder {
+        let mut params = self.params;
+        if !self.order.is_empty() {
+            params.push(("order".to_string(), self.order.join(",")));
+        }
+        let mut request = base(self.method, &self.path).query(&params);
+        if !self.prefer.is_empty() {
+            request = request.header("Prefer", self.prefer.join(","));
+        }
+        if let Some((from, to)) = self.range {
+            request = request
+                .header("Range-Unit", "items")
+                .header("Range", format!("{}-{}", from, to));
+        }
+        if let Some(body) = self.body {
+            request = request.json(&body);
+        }
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Client;
+    use serde_json::json;
+
+    // Values that are syntax somewhere in PostgREST's query language.
+    const AWKWARD: &str = r#"a,b (c) "d" \e.f"#;
+
+    fn build(query: Query) -> reqwest::Request {
+        let client = Client::new();
+        query
+            .into_request(|method, path| client.request(method, format!("http://db.test/rest/v1/{}", path)))
+            .build()
+            .unwrap()
+    }
+
+    fn pairs(query: Query) -> Vec<(String, String)> {
+        build(query).url().query_pairs().into_owned().collect()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn top_level_filters_pass_values_verbatim() {
+        let query = Query::select("facts", "id")
+            .eq("content", AWKWARD)
+            .neq("category", "null")
+            .lt("created_at", "2024-01-01T00:00:00+00:00")
+            .eq_or_null("conversation_id", Some("t,1"))
+            .eq_or_null("archived_by", None::<&str>)
+            .is("archived", false);
+        assert_eq!(
+            pairs(query),
+            [
+                pair("select", "id"),
+                pair("content", &format!("eq.{}", AWKWARD)),
+                pair("category", "neq.null"),
+                pair("created_at", "lt.2024-01-01T00:00:00+00:00"),
+                pair("conversation_id", "eq.t,1"),
+                pair("archived_by", "is.null"),
+                pair("archived", "is.false"),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_values_are_quoted_and_escaped() {
+        let keys = ["a,b", "c)d", "e\"f", "g\\h", "null"];
+        let query = Query::delete("conversations").one_of("idempotency_key", &keys);
+        assert_eq!(
+            pairs(query),
+            [pair("idempotency_key", r#"in.("a,b","c)d","e\"f","g\\h","null")"#)]
+        );
+    }
+
+    #[test]
+    fn conditions_nest_inside_or() {
+        let query = Query::select("conversations", "id").or(vec![
+            Condition::lt("timestamp", "2024-01-01T00:00:00+00:00"),
+            Condition::and(vec![
+                Condition::eq("timestamp", "2024-01-01T00:00:00+00:00"),
+                Condition::lt("id", 42),
+            ]),
+            Condition::eq("note", AWKWARD),
+        ]);
+        assert_eq!(
+            pairs(query)[1],
+            pair(
+                "or",
+                r#"(timestamp.lt."2024-01-01T00:00:00+00:00",and(timestamp.eq."2024-01-01T00:00:00+00:00",id.lt."42"),note.eq."a,b (c) \"d\" \\e.f")"#
+            )
+        );
+    }
+
+    #[test]
+    fn values_are_percent_encoded_in_the_url() {
+        let request = build(Query::select("facts", "id").eq("content", AWKWARD));
+        let raw = request.url().query().unwrap();
+        assert!(
+            !raw.contains(['"', '(', ')', ' ', '\\']),
+            "unencoded query string: {}",
+            raw
+        );
+        assert_eq!(request.url().path(), "/rest/v1/facts");
+    }
+
+    #[test]
+    fn order_range_and_preferences_become_params_and_headers() {
+        let query = Query::upsert("facts", json!([{"id": "a"}]), "id", Resolution::IgnoreDuplicates)
+            .returning("id")
+            .count_exact();
+        let request = build(query);
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(
+            request.url().query_pairs().into_owned().collect::<Vec<_>>(),
+            [pair("on_conflict", "id"), pair("select", "id")]
+        );
+        assert_eq!(
+            request.headers()["Prefer"],
+            "resolution=ignore-duplicates,return=representation,count=exact"
+        );
+        let body: Value = serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body, json!([{"id": "a"}]));
+
+        let query = Query::select("conversations", "id")
+            .order("timestamp", Order::Desc)
+            .order("id", Order::Asc)
+            .limit(50)
+            .range(0, 999);
+        let request = build(query);
+        assert_eq!(
+            request.url().query_pairs().into_owned().collect::<Vec<_>>(),
+            [pair("select", "id"), pair("limit", "50"), pair("order", "timestamp.desc,id.asc")]
+        );
+        assert_eq!(request.headers()["Range"], "0-999");
+        assert_eq!(request.headers()["Range-Unit"], "items");
+    }
+
+    #[test]
+    fn rpc_calls_post_their_arguments() {
+        let request = build(Query::rpc("match_messages", json!({"p_limit": 4})));
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.url().path(), "/rest/v1/rpc/match_messages");
+        assert!(request.url().query().is_none_or(str::is_empty));
+    }
+}
@@ -0,0 +1,968 @@
+use super::{
+    conversation_from_key, conversation_key, cosine_similarity, top_matches,
+    ConversationStore, Cursor, DeletionReport, ExpiryAction, ExportedEmbedding, ExportedMessage, Fact,
+    RetentionPolicy, SimilarMessage, StoreError, StoredMessage, Summary, SuppressedFact, Thread,
+    ThreadUpdate, Turn, UserExport, UserKey, EXPIRED_CONTENT,
+};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::{Arc, Mutex};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS conversation_threads (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    archived INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS conversation_threads_user
+    ON conversation_threads (user_id, created_at);
+CREATE TABLE IF NOT EXISTS conversation_summaries (
+    user_id TEXT NOT NULL,
+    conversation_key TEXT NOT NULL,
+    content TEXT NOT NULL,
+    through_timestamp TEXT NOT NULL,
+    through_id INTEGER NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (user_id, conversation_key)
+);
+CREATE TABLE IF NOT EXISTS user_facts (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    category TEXT NOT NULL,
+    content TEXT NOT NULL,
+    conversation_id TEXT,
+    source_excerpt TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS user_facts_user ON user_facts (user_id, created_at);
+CREATE TABLE IF NOT EXISTS suppressed_facts (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    content TEXT NOT NULL,
+    suppressed_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS suppressed_facts_user ON suppressed_facts (user_id, suppressed_at);
+CREATE TABLE IF NOT EXISTS message_embeddings (
+    message_id INTEGER PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    model TEXT NOT NULL,
+    vector BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS message_embeddings_user ON message_embeddings (user_id, model);
+CREATE TABLE IF NOT EXISTS user_keys (
+    user_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    master_key_id TEXT NOT NULL,
+    wrapped TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (user_id, version)
+);
+CREATE TABLE IF NOT EXISTS retention_policies (
+    user_id TEXT PRIMARY KEY,
+    days INTEGER NOT NULL,
+    action TEXT NOT NULL
+);
+";
+
+// Columns added after the first release, as (table, column, declaration).
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    ("conversations", "conversation_id", "TEXT"),
+    ("conversations", "idempotency_key", "TEXT"),
+];
+
+const INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS conversations_user_thread_timestamp
+    ON conversations (user_id, conversation_id, timestamp);
+CREATE UNIQUE INDEX IF NOT EXISTS conversations_idempotency_key
+    ON conversations (idempotency_key);
+";
+
+const THREAD_COLUMNS: &str = "id, title, archived, created_at, updated_at";
+const FACT_COLUMNS: &str = "id, category, content, conversation_id, source_excerpt, created_at";
+const SUMMARY_COLUMNS: &str = "conversation_key, content, through_timestamp, through_id, updated_at";
+const MESSAGE_COLUMNS: &str = "id, role, content, timestamp, idempotency_key";
+
+/// Embedded SQLite store for self-hosted deployments.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        info!("Opening SQLite conversation store at {}", path);
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        for (table, column, decl) in MIGRATIONS {
+            ensure_column(&conn, table, column, decl)?;
+        }
+        conn.execute_batch(INDEXES)?;
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` against the connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| StoreError::Task(e.to_string()))?
+    }
+}
+
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<(), StoreError> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        info!("Migrating SQLite store: adding {}.{}", table, column);
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+    }
+    Ok(())
+}
+
+fn message_from_row(row: &Row) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        role: row.get(1)?,
+        content: row.get(2)?,
+        timestamp: row.get(3)?,
+        idempotency_key: row.get(4)?,
+    })
+}
+
+fn thread_from_row(row: &Row) -> rusqlite::Result<Thread> {
+    Ok(Thread {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        archived: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+fn summary_from_row(row: &Row) -> rusqlite::Result<Summary> {
+    Ok(Summary {
+        conversation_id: conversation_from_key(row.get(0)?),
+        content: row.get(1)?,
+        through_timestamp: row.get(2)?,
+        through_id: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+// Vectors are stored as little-endian f32s.
+fn vector_to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn vector_from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn fact_from_row(row: &Row) -> rusqlite::Result<Fact> {
+    Ok(Fact {
+        id: row.get(0)?,
+        category: row.get(1)?,
+        content: row.get(2)?,
+        conversation_id: row.get(3)?,
+        source_excerpt: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+fn select_facts(conn: &Connection, user_id: &str) -> Result<Vec<Fact>, StoreError> {
+    Ok(conn
+        .prepare(&format!(
+            "SELECT {} FROM user_facts WHERE user_id = ?1 ORDER BY created_at, id",
+            FACT_COLUMNS
+        ))?
+        .query_map(params![user_id], fact_from_row)?
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn select_suppressed_facts(conn: &Connection, user_id: &str) -> Result<Vec<SuppressedFact>, StoreError> {
+    Ok(conn
+        .prepare(
+            "SELECT id, content, suppressed_at FROM suppressed_facts
+             WHERE user_id = ?1 ORDER BY suppressed_at, id",
+        )?
+        .query_map(params![user_id], |row| {
+            Ok(SuppressedFact {
+                id: row.get(0)?,
+                content: row.get(1)?,
+                suppressed_at: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn retention_from_row(row: &Row) -> rusqlite::Result<RetentionPolicy> {
+    let action: String = row.get(1)?;
+    Ok(RetentionPolicy {
+        days: row.get(0)?,
+        action: ExpiryAction::parse(&action).unwrap_or_default(),
+    })
+}
+
+fn select_retention(conn: &Connection, user_id: &str) -> Result<Option<RetentionPolicy>, StoreError> {
+    Ok(conn
+        .query_row(
+            "SELECT days, action FROM retention_policies WHERE user_id = ?1",
+            params![user_id],
+            retention_from_row,
+        )
+        .optional()?)
+}
+
+fn load_thread(conn: &Connection, user_id: &str, thread_id: &str) -> Result<Option<Thread>, StoreError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM conversation_threads WHERE user_id = ?1 AND id = ?2",
+                THREAD_COLUMNS
+            ),
+            params![user_id, thread_id],
+            thread_from_row,
+        )
+        .optional()?)
+}
+
+impl ConversationStore for SqliteStore {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn append_turn<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        turn: Turn,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        let user_id = user_id.to_string();
+        let conversation_id = conversation_id.map(str::to_string);
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let keys = turn.message_keys();
+            for (message, key) in turn.messages.iter().zip(&keys) {
+                tx.execute(
+                    "INSERT INTO conversations
+                     (user_id, conversation_id, role, content, timestamp, idempotency_key)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (idempotency_key) DO NOTHING",
+                    params![
+                        user_id,
+                        conversation_id,
+                        message.role,
+                        message.content,
+                        turn.timestamp,
+                        key
+                    ],
+                )?;
+            }
+            let mut stored = Vec::new();
+            for key in &keys {
+                stored.push(tx.query_row(
+                    &format!("SELECT {} FROM conversations WHERE idempotency_key = ?1", MESSAGE_COLUMNS),
+                    params![key],
+                    message_from_row,
+                )?);
+            }
+            tx.commit()?;
+            Ok(stored)
+        }))
+    }
+
+    fn messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        let user_id = user_id.to_string();
+        let conversation_id = conversation_id.map(str::to_string);
+        let (before_ts, before_id) = match before {
+            Some(c) => (Some(c.timestamp.clone()), c.id.parse::<i64>().ok()),
+            None => (None, None),
+        };
+        Box::pin(self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM conversations
+                 WHERE user_id = ?1 AND conversation_id IS ?2
+                   AND (?3 IS NULL OR timestamp < ?3 OR (timestamp = ?3 AND id < ?4))
+                 ORDER BY timestamp DESC, id DESC
+                 LIMIT ?5",
+                MESSAGE_COLUMNS
+            ))?;
+            let page = stmt
+                .query_map(
+                    params![
+                        user_id,
+                        conversation_id,
+                        before_ts,
+                        before_id.unwrap_or(i64::MAX),
+                        limit as i64
+                    ],
+                    message_from_row,
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(page)
+        }))
+    }
+
+    fn create_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread: Thread,
+    ) -> BoxFuture<'a, Result<Thread, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO conversation_threads (id, user_id, title, archived, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    thread.id,
+                    user_id,
+                    thread.title,
+                    thread.archived,
+                    thread.created_at,
+                    thread.updated_at
+                ],
+            )?;
+            Ok(thread)
+        }))
+    }
+
+    fn list_threads<'a>(
+        &'a self,
+        user_id: &'a str,
+        include_archived: bool,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Thread>, StoreError>> {
+        let user_id = user_id.to_string();
+        let before = before.cloned();
+        Box::pin(self.with_conn(move |conn| {
+            let (before_ts, before_id) = match before {
+                Some(c) => (Some(c.timestamp), Some(c.id)),
+                None => (None, None),
+            };
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM conversation_threads
+                 WHERE user_id = ?1 AND (?2 OR archived = 0)
+                   AND (?3 IS NULL OR created_at < ?3 OR (created_at = ?3 AND id < ?4))
+                 ORDER BY created_at DESC, id DESC
+                 LIMIT ?5",
+                THREAD_COLUMNS
+            ))?;
+            let threads = stmt
+                .query_map(
+                    params![user_id, include_archived, before_ts, before_id, limit as i64],
+                    thread_from_row,
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(threads)
+        }))
+    }
+
+    fn get_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        let user_id = user_id.to_string();
+        let thread_id = thread_id.to_string();
+        Box::pin(self.with_conn(move |conn| load_thread(conn, &user_id, &thread_id)))
+    }
+
+    fn update_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+        update: ThreadUpdate,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        let user_id = user_id.to_string();
+        let thread_id = thread_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let mut thread = match load_thread(conn, &user_id, &thread_id)? {
+                Some(thread) => thread,
+                None => return Ok(None),
+            };
+            thread.apply(&update);
+            conn.execute(
+                "UPDATE conversation_threads SET title = ?1, archived = ?2, updated_at = ?3
+                 WHERE user_id = ?4 AND id = ?5",
+                params![thread.title, thread.archived, thread.updated_at, user_id, thread_id],
+            )?;
+            Ok(Some(thread))
+        }))
+    }
+
+    fn get_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Summary>, StoreError>> {
+        let user_id = user_id.to_string();
+        let key = conversation_key(conversation_id).to_string();
+        Box::pin(self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM conversation_summaries
+                         WHERE user_id = ?1 AND conversation_key = ?2",
+                        SUMMARY_COLUMNS
+                    ),
+                    params![user_id, key],
+                    summary_from_row,
+                )
+                .optional()?)
+        }))
+    }
+
+    fn put_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        summary: Summary,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO conversation_summaries
+                 (user_id, conversation_key, content, through_timestamp, through_id, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    user_id,
+                    conversation_key(summary.conversation_id.as_deref()),
+                    summary.content,
+                    summary.through_timestamp,
+                    summary.through_id,
+                    summary.updated_at
+                ],
+            )?;
+            Ok(())
+        }))
+    }
+
+    fn add_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+        facts: Vec<Fact>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            for fact in facts {
+                tx.execute(
+                    "INSERT INTO user_facts
+                     (id, user_id, category, content, conversation_id, source_excerpt, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        fact.id,
+                        user_id,
+                        fact.category,
+                        fact.content,
+                        fact.conversation_id,
+                        fact.source_excerpt,
+                        fact.created_at
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }))
+    }
+
+    fn list_facts<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<Fact>, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| select_facts(conn, &user_id)))
+    }
+
+    fn delete_fact<'a>(
+        &'a self,
+        user_id: &'a str,
+        fact_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        let user_id = user_id.to_string();
+        let fact_id = fact_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO suppressed_facts (id, user_id, content, suppressed_at)
+                 SELECT id, user_id, content, ?3 FROM user_facts WHERE user_id = ?1 AND id = ?2",
+                params![user_id, fact_id, Utc::now().to_rfc3339()],
+            )?;
+            let deleted = tx.execute(
+                "DELETE FROM user_facts WHERE user_id = ?1 AND id = ?2",
+                params![user_id, fact_id],
+            )?;
+            tx.commit()?;
+            Ok(deleted > 0)
+        }))
+    }
+
+    fn suppressed_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<SuppressedFact>, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| select_suppressed_facts(conn, &user_id)))
+    }
+
+    fn put_embeddings<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let user_id = user_id.to_string();
+        let model = model.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            for (message_id, vector) in embeddings {
+                tx.execute(
+                    "INSERT OR REPLACE INTO message_embeddings (message_id, user_id, model, vector)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![message_id, user_id, model, vector_to_blob(&vector)],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }))
+    }
+
+    fn similar_messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        query: &'a [f32],
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SimilarMessage>, StoreError>> {
+        let user_id = user_id.to_string();
+        let model = model.to_string();
+        let query = query.to_vec();
+        Box::pin(self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT c.id, c.role, c.content, c.timestamp, c.idempotency_key, e.vector
+                 FROM message_embeddings e JOIN conversations c ON c.id = e.message_id
+                 WHERE e.user_id = ?1 AND e.model = ?2",
+            )?;
+            let matches = stmt
+                .query_map(params![user_id, model], |row| {
+                    let vector: Vec<u8> = row.get(5)?;
+                    Ok(SimilarMessage {
+                        message: message_from_row(row)?,
+                        score: cosine_similarity(&query, &vector_from_blob(&vector)),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(top_matches(matches, limit))
+        }))
+    }
+
+    fn user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<UserKey>, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            Ok(conn
+                .prepare(
+                    "SELECT version, master_key_id, wrapped, created_at FROM user_keys
+                     WHERE user_id = ?1 ORDER BY version",
+                )?
+                .query_map(params![user_id], |row| {
+                    Ok(UserKey {
+                        version: row.get(0)?,
+                        master_key_id: row.get(1)?,
+                        wrapped: row.get(2)?,
+                        created_at: row.get(3)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?)
+        }))
+    }
+
+    fn add_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let added = conn.execute(
+                "INSERT OR IGNORE INTO user_keys (user_id, version, master_key_id, wrapped, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![user_id, key.version, key.master_key_id, key.wrapped, key.created_at],
+            )?;
+            Ok(added > 0)
+        }))
+    }
+
+    fn update_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE user_keys SET master_key_id = ?3, wrapped = ?4
+                 WHERE user_id = ?1 AND version = ?2",
+                params![user_id, key.version, key.master_key_id, key.wrapped],
+            )?;
+            Ok(())
+        }))
+    }
+
+    fn delete_user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<u64, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            Ok(conn.execute("DELETE FROM user_keys WHERE user_id = ?1", params![user_id])? as u64)
+        }))
+    }
+
+    fn retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RetentionPolicy>, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| select_retention(conn, &user_id)))
+    }
+
+    fn set_retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+        policy: Option<RetentionPolicy>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            match policy {
+                Some(policy) => conn.execute(
+                    "INSERT INTO retention_policies (user_id, days, action) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (user_id) DO UPDATE SET days = excluded.days, action = excluded.action",
+                    params![user_id, policy.days, policy.action.as_str()],
+                )?,
+                None => conn.execute(
+                    "DELETE FROM retention_policies WHERE user_id = ?1",
+                    params![user_id],
+                )?,
+            };
+            Ok(())
+        }))
+    }
+
+    fn retention_policies(&self) -> BoxFuture<'_, Result<Vec<(String, RetentionPolicy)>, StoreError>> {
+        Box::pin(self.with_conn(move |conn| {
+            Ok(conn
+                .prepare("SELECT days, action, user_id FROM retention_policies")?
+                .query_map([], |row| Ok((row.get(2)?, retention_from_row(row)?)))?
+                .collect::<Result<Vec<_>, _>>()?)
+        }))
+    }
+
+    fn expire_messages<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        before: &'a str,
+        action: ExpiryAction,
+    ) -> BoxFuture<'a, Result<u64, StoreError>> {
+        let user_id = user_id.map(str::to_string);
+        let before = before.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            // Summaries of the threads losing messages, while those can
+            // still be found; the summarizer rebuilds them from the rest.
+            tx.execute(
+                "DELETE FROM conversation_summaries WHERE (?1 IS NULL OR user_id = ?1) AND EXISTS (
+                     SELECT 1 FROM conversations c
+                     WHERE c.user_id = conversation_summaries.user_id
+                       AND COALESCE(c.conversation_id, ?4) = conversation_summaries.conversation_key
+                       AND c.timestamp < ?2 AND c.content != ?3)",
+                params![user_id, before, EXPIRED_CONTENT, conversation_key(None)],
+            )?;
+            tx.execute(
+                "DELETE FROM user_facts WHERE created_at < ?2 AND (?1 IS NULL OR user_id = ?1)",
+                params![user_id, before],
+            )?;
+            let expired = match action {
+                ExpiryAction::Delete => {
+                    tx.execute(
+                        "DELETE FROM message_embeddings WHERE message_id IN (
+                             SELECT id FROM conversations
+                             WHERE timestamp < ?2 AND (?1 IS NULL OR user_id = ?1))",
+                        params![user_id, before],
+                    )?;
+                    tx.execute(
+                        "DELETE FROM conversations
+                         WHERE timestamp < ?2 AND (?1 IS NULL OR user_id = ?1)",
+                        params![user_id, before],
+                    )?
+                }
+                ExpiryAction::Anonymize => {
+                    tx.execute(
+                        "DELETE FROM message_embeddings WHERE message_id IN (
+                             SELECT id FROM conversations
+                             WHERE timestamp < ?2 AND (?1 IS NULL OR user_id = ?1)
+                               AND content != ?3)",
+                        params![user_id, before, EXPIRED_CONTENT],
+                    )?;
+                    tx.execute(
+                        "UPDATE conversations SET content = ?3
+                         WHERE timestamp < ?2 AND (?1 IS NULL OR user_id = ?1) AND content != ?3",
+                        params![user_id, before, EXPIRED_CONTENT],
+                    )?
+                }
+            };
+            tx.commit()?;
+            Ok(expired as u64)
+        }))
+    }
+
+    fn export_user<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<UserExport, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let threads = conn
+                .prepare(&format!(
+                    "SELECT {} FROM conversation_threads WHERE user_id = ?1 ORDER BY created_at, id",
+                    THREAD_COLUMNS
+                ))?
+                .query_map(params![user_id], thread_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            let messages = conn
+                .prepare(&format!(
+                    "SELECT {}, conversation_id FROM conversations
+                     WHERE user_id = ?1 ORDER BY timestamp, id",
+                    MESSAGE_COLUMNS
+                ))?
+                .query_map(params![user_id], |row| {
+                    Ok(ExportedMessage {
+                        conversation_id: row.get(5)?,
+                        message: message_from_row(row)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let summaries = conn
+                .prepare(&format!(
+                    "SELECT {} FROM conversation_summaries WHERE user_id = ?1",
+                    SUMMARY_COLUMNS
+                ))?
+                .query_map(params![user_id], summary_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            let facts = select_facts(conn, &user_id)?;
+            let suppressed_facts = select_suppressed_facts(conn, &user_id)?;
+            let embeddings = conn
+                .prepare(
+                    "SELECT message_id, model, vector FROM message_embeddings
+                     WHERE user_id = ?1 ORDER BY message_id",
+                )?
+                .query_map(params![user_id], |row| {
+                    let vector: Vec<u8> = row.get(2)?;
+                    Ok(ExportedEmbedding {
+                        message_id: row.get(0)?,
+                        model: row.get(1)?,
+                        vector: vector_from_blob(&vector),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let retention = select_retention(conn, &user_id)?;
+            Ok(UserExport {
+                user_id,
+                exported_at: Utc::now().to_rfc3339(),
+                threads,
+                messages,
+                summaries,
+                facts,
+                suppressed_facts,
+                embeddings,
+                retention,
+            })
+        }))
+    }
+
+    fn delete_user<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<DeletionReport, StoreError>> {
+        let user_id = user_id.to_string();
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let messages = tx.execute("DELETE FROM conversations WHERE user_id = ?1", params![user_id])?;
+            let threads = tx.execute(
+                "DELETE FROM conversation_threads WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            let summaries = tx.execute(
+                "DELETE FROM conversation_summaries WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            let facts = tx.execute("DELETE FROM user_facts WHERE user_id = ?1", params![user_id])?;
+            let suppressed_facts = tx.execute(
+                "DELETE FROM suppressed_facts WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            let embeddings = tx.execute(
+                "DELETE FROM message_embeddings WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            let keys = tx.execute("DELETE FROM user_keys WHERE user_id = ?1", params![user_id])?;
+            tx.execute(
+                "DELETE FROM retention_policies WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            tx.commit()?;
+            Ok(DeletionReport {
+                messages: messages as u64,
+                threads: threads as u64,
+                summaries: summaries as u64,
+                facts: facts as u64,
+                suppressed_facts: suppressed_facts as u64,
+                embeddings: embeddings as u64,
+                keys: keys as u64,
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{ChatMessage, MemoryStore, Turn};
+
+    const LONG_AGO: &str = "2001-01-01T00:00:00+00:00";
+    const CUTOFF: &str = "2010-01-01T00:00:00+00:00";
+
+    fn turn(timestamp: &str) -> Turn {
+        let mut turn = Turn::new(vec![
+            ChatMessage {
+                role: "user".to_string(),
+                content: "my sister is called Ana".to_string(),
+            },
+            ChatMessage {
+                role: "assistant".to_string(),
+                content: "Nice to hear about Ana".to_string(),
+            },
+        ]);
+        turn.timestamp = timestamp.to_string();
+        turn
+    }
+
+    fn summary(conversation_id: Option<&str>, through: &StoredMessage) -> Summary {
+        Summary {
+            conversation_id: conversation_id.map(str::to_string),
+            content: "Talked about Ana".to_string(),
+            through_timestamp: through.timestamp.clone(),
+            through_id: through.id,
+            updated_at: through.timestamp.clone(),
+        }
+    }
+
+    // Memory and SQLite both, as they implement expiry separately.
+    fn stores() -> Vec<Arc<dyn ConversationStore>> {
+        vec![
+            Arc::new(SqliteStore::open(":memory:").unwrap()),
+            Arc::new(MemoryStore::new()),
+        ]
+    }
+
+    #[tokio::test]
+    async fn expiry_takes_summaries_and_facts_with_the_messages() {
+        for action in [ExpiryAction::Delete, ExpiryAction::Anonymize] {
+            for store in stores() {
+                let old = store.append_turn("u1", None, turn(LONG_AGO)).await.unwrap();
+                let recent_turn = turn(&Utc::now().to_rfc3339());
+                let recent = store.append_turn("u1", Some("t1"), recent_turn).await.unwrap();
+                store.put_summary("u1", summary(None, &old[1])).await.unwrap();
+                store.put_summary("u1", summary(Some("t1"), &recent[1])).await.unwrap();
+                let mut old_fact = Fact::new("family".into(), "Sister Ana".into(), None, "my sister".into());
+                old_fact.created_at = LONG_AGO.to_string();
+                let recent_fact = Fact::new("family".into(), "Brother Rui".into(), Some("t1".into()), "my brother".into());
+                store.add_facts("u1", vec![old_fact, recent_fact]).await.unwrap();
+
+                let expired = store.expire_messages(Some("u1"), CUTOFF, action).await.unwrap();
+                assert_eq!(expired, 2);
+                assert!(store.get_summary("u1", None).await.unwrap().is_none());
+                assert!(store.get_summary("u1", Some("t1")).await.unwrap().is_some());
+                let facts = store.list_facts("u1").await.unwrap();
+                assert_eq!(facts.len(), 1);
+                assert_eq!(facts[0].content, "Brother Rui");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_facts_are_suppressed_until_the_account_goes() {
+        for store in stores() {
+            let fact = Fact::new("family".into(), "Sister Ana".into(), None, "my sister".into());
+            let fact_id = fact.id.clone();
+            store.add_facts("u1", vec![fact]).await.unwrap();
+
+            assert!(store.delete_fact("u1", &fact_id).await.unwrap());
+            assert!(!store.delete_fact("u1", &fact_id).await.unwrap());
+            assert!(store.list_facts("u1").await.unwrap().is_empty());
+            let suppressed = store.suppressed_facts("u1").await.unwrap();
+            assert_eq!(suppressed.len(), 1);
+            assert_eq!((suppressed[0].id.as_str(), suppressed[0].content.as_str()), (fact_id.as_str(), "Sister Ana"));
+            assert!(store.suppressed_facts("u2").await.unwrap().is_empty());
+            assert_eq!(store.export_user("u1").await.unwrap().suppressed_facts.len(), 1);
+
+            assert_eq!(store.delete_user("u1").await.unwrap().suppressed_facts, 1);
+            assert!(store.suppressed_facts("u1").await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilt_summaries_survive_later_passes() {
+        for store in stores() {
+            let old = store.append_turn("u1", None, turn(LONG_AGO)).await.unwrap();
+            store
+                .expire_messages(Some("u1"), CUTOFF, ExpiryAction::Anonymize)
+                .await
+                .unwrap();
+            // What the summarizer would fold from the anonymized thread.
+            store.put_summary("u1", summary(None, &old[1])).await.unwrap();
+
+            let expired = store
+                .expire_messages(Some("u1"), CUTOFF, ExpiryAction::Anonymize)
+                .await
+                .unwrap();
+            assert_eq!(expired, 0);
+            assert!(store.get_summary("u1", None).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_embeddings_with_their_model() {
+        for store in stores() {
+            let stored = store.append_turn("u1", None, turn(LONG_AGO)).await.unwrap();
+            let vectors = stored.iter().map(|m| (m.id, vec![m.id as f32, 0.5])).collect();
+            store.put_embeddings("u1", "test-model", vectors).await.unwrap();
+            store.put_embeddings("u2", "test-model", vec![(999, vec![1.0])]).await.unwrap();
+
+            let exported = store.export_user("u1").await.unwrap().embeddings;
+            let ids: Vec<i64> = exported.iter().map(|e| e.message_id).collect();
+            assert_eq!(ids, [stored[0].id, stored[1].id]);
+            assert_eq!(exported[1].model, "test-model");
+            assert_eq!(exported[1].vector, [stored[1].id as f32, 0.5]);
+        }
+    }
+}
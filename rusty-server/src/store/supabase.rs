@@ -0,0 +1,891 @@
+use super::postgrest::{Condition, Order, Query, Resolution};
+use super::{
+    conversation_from_key, conversation_key, ChatMessage, ConversationStore, Cursor,
+    DeletionReport, ExpiryAction, ExportedEmbedding, ExportedMessage, Fact, RetentionPolicy, SimilarMessage,
+    StoreError, StoredMessage, Summary, SuppressedFact, Thread, ThreadUpdate, Turn, UserExport,
+    UserKey, EXPIRED_CONTENT,
+};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use log::error;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::env;
+
+const THREAD_COLUMNS: &str = "id,title,archived,created_at,updated_at";
+const FACT_COLUMNS: &str = "id,category,content,conversation_id,source_excerpt,created_at";
+const SUMMARY_COLUMNS: &str = "conversation_key,content,through_timestamp,through_id,updated_at";
+const MESSAGE_COLUMNS: &str = "id,message,timestamp,idempotency_key";
+const KEY_COLUMNS: &str = "version,master_key_id,wrapped,created_at";
+// Page size for full-table reads; Supabase caps responses at 1000 rows by default.
+const EXPORT_PAGE_SIZE: usize = 1000;
+// Ids per `in.(...)` filter, keeping request URLs well under server limits.
+const ID_BATCH_SIZE: usize = 200;
+// Roles `append_turn` writes; anonymizing rewrites each role's messages separately.
+const STORED_ROLES: &[&str] = &["user", "assistant"];
+
+/// Conditions for an `or` filter selecting rows strictly older than
+/// `cursor` in `(time_column, id)` order.
+fn keyset_conditions(time_column: &str, cursor: &Cursor) -> Vec<Condition> {
+    vec![
+        Condition::lt(time_column, &cursor.timestamp),
+        Condition::and(vec![
+            Condition::eq(time_column, &cursor.timestamp),
+            Condition::lt("id", &cursor.id),
+        ]),
+    ]
+}
+
+/// Rebuilds a stored message from a row selected with at least
+/// `MESSAGE_COLUMNS`.
+fn stored_message(row: &Value) -> Option<StoredMessage> {
+    let message: ChatMessage = serde_json::from_value(row["message"].clone()).ok()?;
+    Some(StoredMessage {
+        id: row["id"].as_i64()?,
+        role: message.role,
+        content: message.content,
+        timestamp: row["timestamp"].as_str()?.to_string(),
+        idempotency_key: row["idempotency_key"].as_str().map(str::to_string),
+    })
+}
+
+/// pgvector columns come back from PostgREST as text such as `[0.1,0.2]`.
+fn vector_from_value(value: &Value) -> Option<Vec<f32>> {
+    match value {
+        Value::String(text) => serde_json::from_str(text).ok(),
+        other => serde_json::from_value(other.clone()).ok(),
+    }
+}
+
+#[derive(Deserialize)]
+struct SummaryRow {
+    conversation_key: String,
+    content: String,
+    through_timestamp: String,
+    through_id: i64,
+    updated_at: String,
+}
+
+#[derive(Deserialize)]
+struct RetentionRow {
+    user_id: String,
+    #[serde(flatten)]
+    policy: RetentionPolicy,
+}
+
+impl From<SummaryRow> for Summary {
+    fn from(row: SummaryRow) -> Self {
+        Summary {
+            conversation_id: conversation_from_key(row.conversation_key),
+            content: row.content,
+            through_timestamp: row.through_timestamp,
+            through_id: row.through_id,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+/// The `conversations`, `conversation_threads`, `conversation_summaries`,
+/// `user_facts` and `suppressed_facts` tables, among others, behind
+/// Supabase's PostgREST API. Their schema is in `supabase/migrations` at
+/// the repository root.
+pub struct SupabaseStore {
+    client: Client,
+    url: String,
+    key: String,
+}
+
+impl SupabaseStore {
+    pub fn new(url: String, key: String) -> Self {
+        SupabaseStore {
+            client: Client::new(),
+            url,
+            key,
+        }
+    }
+
+    pub fn from_env() -> Result<Self, StoreError> {
+        let key = env::var("SUPABASE_KEY")
+            .map_err(|e| StoreError::Config(format!("Missing SUPABASE_KEY: {}", e)))?;
+        let url = env::var("SUPABASE_URL")
+            .map_err(|e| StoreError::Config(format!("Missing SUPABASE_URL: {}", e)))?;
+        Ok(SupabaseStore::new(url, key))
+    }
+
+    fn rest(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}/rest/v1/{}", self.url, path))
+            .header("apikey", &self.key)
+            .header("Authorization", format!("Bearer {}", self.key))
+    }
+
+    async fn check(response: Response, action: &str) -> Result<Response, StoreError> {
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Supabase {} failed: status={}, error={}", action, status, error_text);
+            let message = format!("Supabase {} failed: {}", action, error_text);
+            if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                return Err(StoreError::Unavailable(message));
+            }
+            return Err(StoreError::Supabase(message));
+        }
+        Ok(response)
+    }
+
+    async fn send(&self, query: Query, action: &str) -> Result<Response, StoreError> {
+        let response = query
+            .into_request(|method, path| self.rest(method, path))
+            .send()
+            .await?;
+        Self::check(response, action).await
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, query: Query, action: &str) -> Result<T, StoreError> {
+        Ok(self.send(query, action).await?.json().await?)
+    }
+
+    async fn fetch_messages(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+        before: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        let mut query = Query::select("conversations", MESSAGE_COLUMNS)
+            .eq("user_id", user_id)
+            .eq_or_null("conversation_id", conversation_id)
+            .order("timestamp", Order::Desc)
+            .order("id", Order::Desc)
+            .limit(limit);
+        if let Some(cursor) = before {
+            query = query.or(keyset_conditions("timestamp", cursor));
+        }
+        let rows: Vec<Value> = self.fetch(query, "message fetch").await?;
+        Ok(rows.iter().filter_map(stored_message).collect())
+    }
+
+    /// One bulk insert, which PostgREST runs as a single statement. Rows
+    /// already written under the same idempotency key are left as they are.
+    async fn insert_turn(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+        turn: Turn,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        let keys = turn.message_keys();
+        let rows: Vec<Value> = turn
+            .messages
+            .iter()
+            .zip(&keys)
+            .map(|(message, key)| {
+                json!({
+                    "user_id": user_id,
+                    "conversation_id": conversation_id,
+                    "message": message,
+                    "timestamp": turn.timestamp,
+                    "idempotency_key": key,
+                })
+            })
+            .collect();
+        let insert = Query::upsert(
+            "conversations",
+            json!(rows),
+            "idempotency_key",
+            Resolution::IgnoreDuplicates,
+        );
+        self.send(insert, "store").await?;
+
+        // Read back ids, including those of rows an earlier attempt wrote.
+        let query = Query::select("conversations", MESSAGE_COLUMNS)
+            .one_of("idempotency_key", &keys)
+            .order("id", Order::Asc);
+        let rows: Vec<Value> = self.fetch(query, "store").await?;
+        Ok(rows.iter().filter_map(stored_message).collect())
+    }
+
+    async fn insert_thread(&self, user_id: &str, thread: Thread) -> Result<Thread, StoreError> {
+        let insert = Query::insert(
+            "conversation_threads",
+            json!({
+                "id": thread.id,
+                "user_id": user_id,
+                "title": thread.title,
+                "archived": thread.archived,
+                "created_at": thread.created_at,
+                "updated_at": thread.updated_at,
+            }),
+        );
+        self.send(insert, "thread create").await?;
+        Ok(thread)
+    }
+
+    /// Threads matching `query`'s filters, newest first.
+    async fn select_threads(
+        &self,
+        query: Query,
+        before: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Thread>, StoreError> {
+        let mut query = query
+            .order("created_at", Order::Desc)
+            .order("id", Order::Desc)
+            .limit(limit);
+        if let Some(cursor) = before {
+            query = query.or(keyset_conditions("created_at", cursor));
+        }
+        self.fetch(query, "thread fetch").await
+    }
+
+    /// Reads every row matching `query`, a page at a time past the
+    /// server's row cap. `query` must be ordered.
+    async fn select_all(&self, query: Query) -> Result<Vec<Value>, StoreError> {
+        let mut rows = Vec::new();
+        loop {
+            let page = query
+                .clone()
+                .range(rows.len(), rows.len() + EXPORT_PAGE_SIZE - 1);
+            let page: Vec<Value> = self.fetch(page, "export").await?;
+            let done = page.len() < EXPORT_PAGE_SIZE;
+            rows.extend(page);
+            if done {
+                return Ok(rows);
+            }
+        }
+    }
+
+    /// Runs a delete, returning how many rows it removed.
+    async fn delete_where(&self, query: Query) -> Result<u64, StoreError> {
+        let response = self.send(query.count_exact(), "delete").await?;
+        // Content-Range looks like `*/42` or `0-41/42`.
+        Ok(response
+            .headers()
+            .get("Content-Range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0))
+    }
+
+    async fn select_summaries(&self, query: Query) -> Result<Vec<Summary>, StoreError> {
+        let rows: Vec<SummaryRow> = self.fetch(query, "summary fetch").await?;
+        Ok(rows.into_iter().map(Summary::from).collect())
+    }
+
+    async fn upsert_summary(&self, user_id: &str, summary: Summary) -> Result<(), StoreError> {
+        let upsert = Query::upsert(
+            "conversation_summaries",
+            json!({
+                "user_id": user_id,
+                "conversation_key": conversation_key(summary.conversation_id.as_deref()),
+                "content": summary.content,
+                "through_timestamp": summary.through_timestamp,
+                "through_id": summary.through_id,
+                "updated_at": summary.updated_at,
+            }),
+            "user_id,conversation_key",
+            Resolution::MergeDuplicates,
+        );
+        self.send(upsert, "summary store").await?;
+        Ok(())
+    }
+
+    async fn insert_facts(&self, user_id: &str, facts: Vec<Fact>) -> Result<(), StoreError> {
+        if facts.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<Value> = facts
+            .into_iter()
+            .map(|fact| {
+                json!({
+                    "id": fact.id,
+                    "user_id": user_id,
+                    "category": fact.category,
+                    "content": fact.content,
+                    "conversation_id": fact.conversation_id,
+                    "source_excerpt": fact.source_excerpt,
+                    "created_at": fact.created_at,
+                })
+            })
+            .collect();
+        self.send(Query::insert("user_facts", json!(rows)), "fact store")
+            .await?;
+        Ok(())
+    }
+
+    async fn select_facts(&self, user_id: &str) -> Result<Vec<Fact>, StoreError> {
+        let query = Query::select("user_facts", FACT_COLUMNS)
+            .eq("user_id", user_id)
+            .order("created_at", Order::Asc)
+            .order("id", Order::Asc);
+        self.select_all(query)
+            .await?
+            .into_iter()
+            .map(|row| Ok(serde_json::from_value(row)?))
+            .collect()
+    }
+
+    /// Copies the fact to `suppressed_facts` before deleting it, so a
+    /// failure part way leaves it still listed, never gone but extractable.
+    async fn suppress_fact(&self, user_id: &str, fact_id: &str) -> Result<bool, StoreError> {
+        let query = Query::select("user_facts", "id,content")
+            .eq("user_id", user_id)
+            .eq("id", fact_id);
+        let rows: Vec<Value> = self.fetch(query, "fact fetch").await?;
+        let Some(fact) = rows.into_iter().next() else {
+            return Ok(false);
+        };
+        let tombstone = Query::upsert(
+            "suppressed_facts",
+            json!({
+                "id": fact["id"],
+                "user_id": user_id,
+                "content": fact["content"],
+                "suppressed_at": Utc::now().to_rfc3339(),
+            }),
+            "id",
+            Resolution::MergeDuplicates,
+        );
+        self.send(tombstone, "fact suppression").await?;
+        let delete = Query::delete("user_facts")
+            .eq("user_id", user_id)
+            .eq("id", fact_id);
+        Ok(self.delete_where(delete).await? > 0)
+    }
+
+    async fn select_suppressed_facts(&self, user_id: &str) -> Result<Vec<SuppressedFact>, StoreError> {
+        let query = Query::select("suppressed_facts", "id,content,suppressed_at")
+            .eq("user_id", user_id)
+            .order("suppressed_at", Order::Asc)
+            .order("id", Order::Asc);
+        self.select_all(query)
+            .await?
+            .into_iter()
+            .map(|row| Ok(serde_json::from_value(row)?))
+            .collect()
+    }
+
+    async fn insert_embeddings(
+        &self,
+        user_id: &str,
+        model: &str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> Result<(), StoreError> {
+        if embeddings.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<Value> = embeddings
+            .into_iter()
+            .map(|(message_id, vector)| {
+                json!({
+                    "message_id": message_id,
+                    "user_id": user_id,
+                    "model": model,
+                    "embedding": vector,
+                })
+            })
+            .collect();
+        let upsert = Query::upsert(
+            "message_embeddings",
+            json!(rows),
+            "message_id",
+            Resolution::MergeDuplicates,
+        );
+        self.send(upsert, "embedding store").await?;
+        Ok(())
+    }
+
+    /// Nearest-neighbour search runs in Postgres (pgvector) through the
+    /// `match_messages` function, which returns `MESSAGE_COLUMNS` and
+    /// `similarity` for each match.
+    async fn match_messages(
+        &self,
+        user_id: &str,
+        model: &str,
+        query: &[f32],
+        limit: usize,
+    ) -> Result<Vec<SimilarMessage>, StoreError> {
+        let call = Query::rpc(
+            "match_messages",
+            json!({
+                "p_user_id": user_id,
+                "p_model": model,
+                "p_query": query,
+                "p_limit": limit,
+            }),
+        );
+        let rows: Vec<Value> = self.fetch(call, "similarity search").await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                Some(SimilarMessage {
+                    message: stored_message(row)?,
+                    score: row["similarity"].as_f64()? as f32,
+                })
+            })
+            .collect())
+    }
+
+    async fn select_user_keys(&self, user_id: &str) -> Result<Vec<UserKey>, StoreError> {
+        let query = Query::select("user_keys", KEY_COLUMNS)
+            .eq("user_id", user_id)
+            .order("version", Order::Asc);
+        self.fetch(query, "key fetch").await
+    }
+
+    async fn insert_user_key(&self, user_id: &str, key: UserKey) -> Result<bool, StoreError> {
+        let insert = Query::upsert(
+            "user_keys",
+            json!({
+                "user_id": user_id,
+                "version": key.version,
+                "master_key_id": key.master_key_id,
+                "wrapped": key.wrapped,
+                "created_at": key.created_at,
+            }),
+            "user_id,version",
+            Resolution::IgnoreDuplicates,
+        )
+        .returning("version");
+        let rows: Vec<Value> = self.fetch(insert, "key store").await?;
+        Ok(!rows.is_empty())
+    }
+
+    async fn patch_user_key(&self, user_id: &str, key: UserKey) -> Result<(), StoreError> {
+        let update = Query::update(
+            "user_keys",
+            json!({
+                "master_key_id": key.master_key_id,
+                "wrapped": key.wrapped,
+            }),
+        )
+        .eq("user_id", user_id)
+        .eq("version", key.version);
+        self.send(update, "key update").await?;
+        Ok(())
+    }
+
+    async fn select_retention(&self, user_id: &str) -> Result<Option<RetentionPolicy>, StoreError> {
+        let query = Query::select("retention_policies", "days,action").eq("user_id", user_id);
+        let policies: Vec<RetentionPolicy> = self.fetch(query, "retention fetch").await?;
+        Ok(policies.into_iter().next())
+    }
+
+    async fn store_retention(
+        &self,
+        user_id: &str,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<(), StoreError> {
+        let query = match policy {
+            Some(policy) => Query::upsert(
+                "retention_policies",
+                json!({
+                    "user_id": user_id,
+                    "days": policy.days,
+                    "action": policy.action,
+                }),
+                "user_id",
+                Resolution::MergeDuplicates,
+            ),
+            None => Query::delete("retention_policies").eq("user_id", user_id),
+        };
+        self.send(query, "retention store").await?;
+        Ok(())
+    }
+
+    async fn select_retention_policies(&self) -> Result<Vec<(String, RetentionPolicy)>, StoreError> {
+        let query = Query::select("retention_policies", "user_id,days,action")
+            .order("user_id", Order::Asc);
+        self.select_all(query)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let row: RetentionRow = serde_json::from_value(row)?;
+                Ok((row.user_id, row.policy))
+            })
+            .collect()
+    }
+
+    /// Deleted messages take their embeddings with them through the
+    /// foreign key; anonymized ones have theirs removed here.
+    async fn expire(
+        &self,
+        user_id: Option<&str>,
+        before: &str,
+        action: ExpiryAction,
+    ) -> Result<u64, StoreError> {
+        let scope = |query: Query| {
+            let query = query.lt("timestamp", before);
+            match user_id {
+                Some(user_id) => query.eq("user_id", user_id),
+                None => query,
+            }
+        };
+        // Summaries of the threads losing messages go first, while those
+        // can still be found; the summarizer rebuilds them from the rest.
+        let rows = self
+            .select_all(
+                scope(Query::select("conversations", "user_id,conversation_id"))
+                    .neq("message->>content", EXPIRED_CONTENT)
+                    .order("id", Order::Asc),
+            )
+            .await?;
+        let threads: HashSet<(&str, &str)> = rows
+            .iter()
+            .filter_map(|row| {
+                let thread = conversation_key(row["conversation_id"].as_str());
+                Some((row["user_id"].as_str()?, thread))
+            })
+            .collect();
+        for (owner, thread) in threads {
+            self.send(
+                Query::delete("conversation_summaries")
+                    .eq("user_id", owner)
+                    .eq("conversation_key", thread),
+                "expiry",
+            )
+            .await?;
+        }
+        let facts = Query::delete("user_facts").lt("created_at", before);
+        let facts = match user_id {
+            Some(user_id) => facts.eq("user_id", user_id),
+            None => facts,
+        };
+        self.send(facts, "expiry").await?;
+
+        match action {
+            ExpiryAction::Delete => self.delete_where(scope(Query::delete("conversations"))).await,
+            ExpiryAction::Anonymize => {
+                let mut expired = 0;
+                for role in STORED_ROLES {
+                    let update = scope(Query::update(
+                        "conversations",
+                        json!({ "message": { "role": role, "content": EXPIRED_CONTENT } }),
+                    ))
+                    .eq("message->>role", role)
+                    .neq("message->>content", EXPIRED_CONTENT)
+                    .returning("id");
+                    let rows: Vec<Value> = self.fetch(update, "expiry").await?;
+                    let ids: Vec<i64> = rows.iter().filter_map(|row| row["id"].as_i64()).collect();
+                    for batch in ids.chunks(ID_BATCH_SIZE) {
+                        self.send(
+                            Query::delete("message_embeddings").one_of("message_id", batch),
+                            "expiry",
+                        )
+                        .await?;
+                    }
+                    expired += ids.len() as u64;
+                }
+                Ok(expired)
+            }
+        }
+    }
+
+    async fn export(&self, user_id: &str) -> Result<UserExport, StoreError> {
+        let threads = self
+            .select_all(
+                Query::select("conversation_threads", THREAD_COLUMNS)
+                    .eq("user_id", user_id)
+                    .order("created_at", Order::Asc)
+                    .order("id", Order::Asc),
+            )
+            .await?
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<Vec<Thread>, _>>()?;
+
+        let messages = self
+            .select_all(
+                Query::select("conversations", &format!("conversation_id,{}", MESSAGE_COLUMNS))
+                    .eq("user_id", user_id)
+                    .order("timestamp", Order::Asc)
+                    .order("id", Order::Asc),
+            )
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                Some(ExportedMessage {
+                    conversation_id: row["conversation_id"].as_str().map(str::to_string),
+                    message: stored_message(&row)?,
+                })
+            })
+            .collect();
+
+        let summaries = self
+            .select_summaries(
+                Query::select("conversation_summaries", SUMMARY_COLUMNS).eq("user_id", user_id),
+            )
+            .await?;
+
+        let facts = self.select_facts(user_id).await?;
+        let suppressed_facts = self.select_suppressed_facts(user_id).await?;
+
+        let embeddings = self
+            .select_all(
+                Query::select("message_embeddings", "message_id,model,embedding")
+                    .eq("user_id", user_id)
+                    .order("message_id", Order::Asc),
+            )
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                Some(ExportedEmbedding {
+                    message_id: row["message_id"].as_i64()?,
+                    model: row["model"].as_str()?.to_string(),
+                    vector: vector_from_value(&row["embedding"])?,
+                })
+            })
+            .collect();
+
+        let retention = self.select_retention(user_id).await?;
+
+        Ok(UserExport {
+            user_id: user_id.to_string(),
+            exported_at: Utc::now().to_rfc3339(),
+            threads,
+            messages,
+            summaries,
+            facts,
+            suppressed_facts,
+            embeddings,
+            retention,
+        })
+    }
+
+    async fn delete_all(&self, user_id: &str) -> Result<DeletionReport, StoreError> {
+        let delete = |table: &str| Query::delete(table).eq("user_id", user_id);
+        self.delete_where(delete("retention_policies")).await?;
+        // Embeddings cascade from their messages, so they are counted, and
+        // removed, before the messages go.
+        let embeddings = self.delete_where(delete("message_embeddings")).await?;
+        Ok(DeletionReport {
+            messages: self.delete_where(delete("conversations")).await?,
+            threads: self.delete_where(delete("conversation_threads")).await?,
+            summaries: self.delete_where(delete("conversation_summaries")).await?,
+            facts: self.delete_where(delete("user_facts")).await?,
+            suppressed_facts: self.delete_where(delete("suppressed_facts")).await?,
+            embeddings,
+            keys: self.delete_where(delete("user_keys")).await?,
+        })
+    }
+
+    async fn patch_thread(
+        &self,
+        user_id: &str,
+        thread_id: &str,
+        update: ThreadUpdate,
+    ) -> Result<Option<Thread>, StoreError> {
+        let mut body = json!({ "updated_at": Utc::now().to_rfc3339() });
+        if let Some(title) = update.title {
+            body["title"] = json!(title);
+        }
+        if let Some(archived) = update.archived {
+            body["archived"] = json!(archived);
+        }
+
+        let query = Query::update("conversation_threads", body)
+            .eq("user_id", user_id)
+            .eq("id", thread_id)
+            .returning(THREAD_COLUMNS);
+        let threads: Vec<Thread> = self.fetch(query, "thread update").await?;
+        Ok(threads.into_iter().next())
+    }
+}
+
+impl ConversationStore for SupabaseStore {
+    fn name(&self) -> &'static str {
+        "supabase"
+    }
+
+    fn append_turn<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        turn: Turn,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        Box::pin(self.insert_turn(user_id, conversation_id, turn))
+    }
+
+    fn messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<StoredMessage>, StoreError>> {
+        Box::pin(self.fetch_messages(user_id, conversation_id, before, limit))
+    }
+
+    fn create_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread: Thread,
+    ) -> BoxFuture<'a, Result<Thread, StoreError>> {
+        Box::pin(self.insert_thread(user_id, thread))
+    }
+
+    fn list_threads<'a>(
+        &'a self,
+        user_id: &'a str,
+        include_archived: bool,
+        before: Option<&'a Cursor>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Thread>, StoreError>> {
+        let mut query = Query::select("conversation_threads", THREAD_COLUMNS).eq("user_id", user_id);
+        if !include_archived {
+            query = query.is("archived", false);
+        }
+        Box::pin(self.select_threads(query, before, limit))
+    }
+
+    fn get_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        let query = Query::select("conversation_threads", THREAD_COLUMNS)
+            .eq("user_id", user_id)
+            .eq("id", thread_id);
+        Box::pin(async move { Ok(self.select_threads(query, None, 1).await?.into_iter().next()) })
+    }
+
+    fn update_thread<'a>(
+        &'a self,
+        user_id: &'a str,
+        thread_id: &'a str,
+        update: ThreadUpdate,
+    ) -> BoxFuture<'a, Result<Option<Thread>, StoreError>> {
+        Box::pin(self.patch_thread(user_id, thread_id, update))
+    }
+
+    fn get_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        conversation_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Summary>, StoreError>> {
+        let query = Query::select("conversation_summaries", SUMMARY_COLUMNS)
+            .eq("user_id", user_id)
+            .eq("conversation_key", conversation_key(conversation_id));
+        Box::pin(async move { Ok(self.select_summaries(query).await?.into_iter().next()) })
+    }
+
+    fn put_summary<'a>(
+        &'a self,
+        user_id: &'a str,
+        summary: Summary,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(self.upsert_summary(user_id, summary))
+    }
+
+    fn add_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+        facts: Vec<Fact>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(self.insert_facts(user_id, facts))
+    }
+
+    fn list_facts<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<Fact>, StoreError>> {
+        Box::pin(self.select_facts(user_id))
+    }
+
+    fn delete_fact<'a>(
+        &'a self,
+        user_id: &'a str,
+        fact_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(self.suppress_fact(user_id, fact_id))
+    }
+
+    fn suppressed_facts<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<SuppressedFact>, StoreError>> {
+        Box::pin(self.select_suppressed_facts(user_id))
+    }
+
+    fn put_embeddings<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        embeddings: Vec<(i64, Vec<f32>)>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(self.insert_embeddings(user_id, model, embeddings))
+    }
+
+    fn similar_messages<'a>(
+        &'a self,
+        user_id: &'a str,
+        model: &'a str,
+        query: &'a [f32],
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SimilarMessage>, StoreError>> {
+        Box::pin(self.match_messages(user_id, model, query, limit))
+    }
+
+    fn user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<Vec<UserKey>, StoreError>> {
+        Box::pin(self.select_user_keys(user_id))
+    }
+
+    fn add_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(self.insert_user_key(user_id, key))
+    }
+
+    fn update_user_key<'a>(
+        &'a self,
+        user_id: &'a str,
+        key: UserKey,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(self.patch_user_key(user_id, key))
+    }
+
+    fn delete_user_keys<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<u64, StoreError>> {
+        Box::pin(self.delete_where(Query::delete("user_keys").eq("user_id", user_id)))
+    }
+
+    fn retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RetentionPolicy>, StoreError>> {
+        Box::pin(self.select_retention(user_id))
+    }
+
+    fn set_retention_policy<'a>(
+        &'a self,
+        user_id: &'a str,
+        policy: Option<RetentionPolicy>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(self.store_retention(user_id, policy))
+    }
+
+    fn retention_policies(&self) -> BoxFuture<'_, Result<Vec<(String, RetentionPolicy)>, StoreError>> {
+        Box::pin(self.select_retention_policies())
+    }
+
+    fn expire_messages<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        before: &'a str,
+        action: ExpiryAction,
+    ) -> BoxFuture<'a, Result<u64, StoreError>> {
+        Box::pin(self.expire(user_id, before, action))
+    }
+
+    fn export_user<'a>(&'a self, user_id: &'a str) -> BoxFuture<'a, Result<UserExport, StoreError>> {
+        Box::pin(self.export(user_id))
+    }
+
+    fn delete_user<'a>(
+        &'a self,
+        user_id: &'a str,
+    ) -> BoxFuture<'a, Result<DeletionReport, StoreError>> {
+        Box::pin(self.delete_all(user_id))
+    }
+}
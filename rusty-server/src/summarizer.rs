@@ -0,0 +1,193 @@
+use crate::prompt;
+use crate::store::{ConversationStore, Cursor, StoredMessage, Summary};
+use crate::tasks::UserTasks;
+use crate::AudioError;
+use chrono::Utc;
+use log::{debug, error, info};
+use reqwest::Client;
+use serde_json::json;
+use std::collections::HashSet;
+use std::env;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Share, in percent, of the prompt's history allowance kept verbatim;
+// everything older is folded into the summary. The rest is slack for later
+// turns, whose longer message, summary or recall leave history less room,
+// so a turn is summarized before the prompt window drops it.
+const VERBATIM_PERCENT: usize = 60;
+// Don't call the model for every turn that scrolls out; wait for a batch.
+const MIN_FOLD_MESSAGES: usize = 6;
+// Bound on how many unsummarized messages one run will read; on a first run
+// over a long legacy thread, anything older is not backfilled.
+const MAX_FOLD_MESSAGES: usize = 200;
+const PAGE_SIZE: usize = 50;
+
+const SUMMARY_INSTRUCTIONS: &str = r#"You maintain a private running summary of a therapy conversation between a user and Hearthly, their therapist. Merge the new messages into the existing summary. Keep what matters for future sessions: people and relationships, events, feelings, goals, coping strategies tried, and anything the user asked to be remembered. Drop small talk. Write in the third person about "the user", in plain prose, at most 300 words. Reply with the updated summary only."#;
+
+/// (user_id, conversation key) pairs with a fold in flight, so bursts of
+/// turns don't summarize the same messages twice.
+fn in_flight() -> &'static Mutex<HashSet<String>> {
+    static IN_FLIGHT: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    IN_FLIGHT.get_or_init(Default::default)
+}
+
+/// Clears an `in_flight` entry when its fold ends, including by being
+/// cancelled.
+struct InFlight(String);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        in_flight().lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Starts a background fold of the thread's evicted turns into its summary.
+/// `history_tokens` is what the turn's prompt had left for history, from
+/// `prompt::history_budget`.
+pub fn spawn_fold(
+    tasks: &UserTasks,
+    store: Arc<dyn ConversationStore>,
+    user_id: String,
+    conversation_id: Option<String>,
+    history_tokens: usize,
+) {
+    let key = format!("{}/{}", user_id, conversation_id.as_deref().unwrap_or(""));
+    if !in_flight().lock().unwrap().insert(key.clone()) {
+        debug!("Summary fold already running for {}", key);
+        return;
+    }
+    let in_flight = InFlight(key);
+
+    tasks.spawn(&user_id.clone(), async move {
+        let _in_flight = in_flight;
+        let verbatim_tokens = history_tokens * VERBATIM_PERCENT / 100;
+        if let Err(e) = fold(store.as_ref(), &user_id, conversation_id.as_deref(), verbatim_tokens).await {
+            error!("Summary fold failed for user_id={}: {}", user_id, e);
+        }
+    });
+}
+
+async fn fold(
+    store: &dyn ConversationStore,
+    user_id: &str,
+    conversation_id: Option<&str>,
+    verbatim_tokens: usize,
+) -> Result<(), AudioError> {
+    let existing = store.get_summary(user_id, conversation_id).await?;
+    let through = existing
+        .as_ref()
+        .map(|s| (s.through_timestamp.clone(), s.through_id));
+
+    // Walk back from the newest message to the summary's high-water mark.
+    let mut unsummarized: Vec<StoredMessage> = Vec::new();
+    let mut before: Option<Cursor> = None;
+    'pages: loop {
+        let page = store
+            .messages(user_id, conversation_id, before.as_ref(), PAGE_SIZE)
+            .await?;
+        let exhausted = page.len() < PAGE_SIZE;
+        for msg in page {
+            let folded = through
+                .as_ref()
+                .is_some_and(|(ts, id)| (msg.timestamp.as_str(), msg.id) <= (ts.as_str(), *id));
+            if folded || unsummarized.len() >= MAX_FOLD_MESSAGES {
+                break 'pages;
+            }
+            before = Some(Cursor::new(&msg.timestamp, msg.id));
+            unsummarized.push(msg);
+        }
+        if exhausted {
+            break;
+        }
+    }
+
+    // `unsummarized` is newest first; keep the recent verbatim window out,
+    // counted the way `prompt::fit_history` counts it.
+    let mut window_tokens = 0;
+    let window = unsummarized
+        .iter()
+        .take_while(|msg| {
+            window_tokens += prompt::message_tokens(&msg.content);
+            window_tokens <= verbatim_tokens
+        })
+        .count();
+    let mut evicted = unsummarized.split_off(window);
+    if evicted.len() < MIN_FOLD_MESSAGES {
+        debug!(
+            "Only {} evicted messages for user_id={}, not folding yet",
+            evicted.len(),
+            user_id
+        );
+        return Ok(());
+    }
+    evicted.reverse(); // Chronological order for the model
+
+    let newest = evicted.last().expect("evicted is non-empty");
+    let (through_timestamp, through_id) = (newest.timestamp.clone(), newest.id);
+    let content = summarize(existing.as_ref().map(|s| s.content.as_str()), &evicted).await?;
+
+    store
+        .put_summary(
+            user_id,
+            Summary {
+                conversation_id: conversation_id.map(str::to_string),
+                content,
+                through_timestamp,
+                through_id,
+                updated_at: Utc::now().to_rfc3339(),
+            },
+        )
+        .await?;
+    info!(
+        "Folded {} messages into summary for user_id={}, conversation_id={:?}",
+        evicted.len(),
+        user_id,
+        conversation_id
+    );
+    Ok(())
+}
+
+async fn summarize(existing: Option<&str>, messages: &[StoredMessage]) -> Result<String, AudioError> {
+    let client = Client::new();
+    let api_key = env::var("OPENAI_API_KEY")
+        .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
+
+    let transcript = messages
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let input = format!(
+        "EXISTING SUMMARY:\n{}\n\nNEW MESSAGES:\n{}",
+        existing.unwrap_or("(none)"),
+        transcript
+    );
+
+    let response = client
+        .post("https://api.openai.com/v1/chat/completions")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&json!({
+            "model": "gpt-4o-mini",
+            "messages": [
+                {"role": "system", "content": SUMMARY_INSTRUCTIONS},
+                {"role": "user", "content": input}
+            ],
+            "temperature": 0.2
+        }))
+        .send()
+        .await
+        .map_err(AudioError::Http)?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        error!("Summary API failed: status={}, error={}", status, error_text);
+        return Err(AudioError::OpenAI(format!("Summary API failed: {}", error_text)));
+    }
+
+    let json: serde_json::Value = response.json().await.map_err(AudioError::Http)?;
+    json["choices"][0]["message"]["content"]
+        .as_str()
+        .map(|s| s.trim().to_string())
+        .ok_or_else(|| AudioError::OpenAI("No summary text in Chat API".to_string()))
+}
@@ -0,0 +1,187 @@
+use futures::future::join_all;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+#[derive(Default)]
+struct Running {
+    tasks: HashMap<u64, JoinHandle<()>>,
+    // Deletions under way; nothing new starts while there are any.
+    stopping: usize,
+}
+
+/// Background work started for a user after a turn: indexing, summary
+/// folds and fact extraction. Tracked per user, so that deleting an
+/// account can stop that work first instead of having it write the
+/// user's data back afterwards.
+#[derive(Default)]
+pub struct UserTasks {
+    users: Arc<Mutex<HashMap<String, Running>>>,
+    next_id: AtomicU64,
+}
+
+impl UserTasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `task` in the background for `user_id`, unless the user is
+    /// being deleted.
+    pub fn spawn<F>(&self, user_id: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut users = self.users.lock().unwrap();
+        let running = users.entry(user_id.to_string()).or_default();
+        if running.stopping > 0 {
+            debug!("Not starting background work for user_id={} during deletion", user_id);
+            return;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let registry = self.users.clone();
+        let owner = user_id.to_string();
+        // The task can't deregister before it is registered: that waits
+        // for the lock held here.
+        let handle = tokio::spawn(async move {
+            task.await;
+            let mut users = registry.lock().unwrap();
+            if let Some(running) = users.get_mut(&owner) {
+                running.tasks.remove(&id);
+                if running.tasks.is_empty() && running.stopping == 0 {
+                    users.remove(&owner);
+                }
+            }
+        });
+        running.tasks.insert(id, handle);
+    }
+
+    /// Cancels `user_id`'s background work and waits for it to end.
+    /// Nothing new starts for the user until the returned guard is
+    /// dropped, so hold it across the deletion.
+    pub async fn stop_user(&self, user_id: &str) -> StoppedUser<'_> {
+        let handles: Vec<JoinHandle<()>> = {
+            let mut users = self.users.lock().unwrap();
+            let running = users.entry(user_id.to_string()).or_default();
+            running.stopping += 1;
+            running.tasks.drain().map(|(_, handle)| handle).collect()
+        };
+        // Made before waiting, so a dropped request still lets the user
+        // start work again.
+        let stopped = StoppedUser {
+            tasks: self,
+            user_id: user_id.to_string(),
+        };
+        if !handles.is_empty() {
+            info!("Stopping {} background tasks for user_id={}", handles.len(), user_id);
+        }
+        for handle in &handles {
+            handle.abort();
+        }
+        join_all(handles).await;
+        stopped
+    }
+
+    fn resume_user(&self, user_id: &str) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(running) = users.get_mut(user_id) {
+            running.stopping -= 1;
+            if running.tasks.is_empty() && running.stopping == 0 {
+                users.remove(user_id);
+            }
+        }
+    }
+}
+
+/// Keeps a user's background work from starting; see `UserTasks::stop_user`.
+pub struct StoppedUser<'a> {
+    tasks: &'a UserTasks,
+    user_id: String,
+}
+
+impl Drop for StoppedUser<'_> {
+    fn drop(&mut self) {
+        self.tasks.resume_user(&self.user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn tracked(tasks: &UserTasks, user_id: &str) -> usize {
+        tasks.users.lock().unwrap().get(user_id).map_or(0, |r| r.tasks.len())
+    }
+
+    /// A task that sets the returned flag once `release` fires.
+    fn blocked(tasks: &UserTasks, user_id: &str) -> (oneshot::Sender<()>, Arc<AtomicBool>) {
+        let (release, released) = oneshot::channel();
+        let wrote = Arc::new(AtomicBool::new(false));
+        let flag = wrote.clone();
+        tasks.spawn(user_id, async move {
+            let _ = released.await;
+            flag.store(true, Ordering::SeqCst);
+        });
+        (release, wrote)
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_are_forgotten() {
+        let tasks = UserTasks::new();
+        let (release, wrote) = blocked(&tasks, "alice");
+        assert_eq!(tracked(&tasks, "alice"), 1);
+
+        release.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tracked(&tasks, "alice") > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(wrote.load(Ordering::SeqCst));
+        assert!(tasks.users.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stopping_a_user_cancels_only_their_work() {
+        let tasks = UserTasks::new();
+        let (alice_release, alice_wrote) = blocked(&tasks, "alice");
+        let (bob_release, bob_wrote) = blocked(&tasks, "bob");
+
+        let stopped = tasks.stop_user("alice").await;
+        // Cancelled, so the release goes nowhere and nothing is written.
+        assert!(alice_release.send(()).is_err());
+        assert!(!alice_wrote.load(Ordering::SeqCst));
+        assert_eq!(tracked(&tasks, "alice"), 0);
+
+        bob_release.send(()).unwrap();
+        drop(stopped);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !bob_wrote.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn nothing_starts_for_a_user_being_deleted() {
+        let tasks = UserTasks::new();
+        let stopped = tasks.stop_user("alice").await;
+        let (_release, _) = blocked(&tasks, "alice");
+        assert_eq!(tracked(&tasks, "alice"), 0);
+
+        drop(stopped);
+        assert!(tasks.users.lock().unwrap().is_empty());
+        let (_release, _) = blocked(&tasks, "alice");
+        assert_eq!(tracked(&tasks, "alice"), 1);
+    }
+}
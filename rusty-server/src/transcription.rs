@@ -0,0 +1,363 @@
+use crate::{audio, AudioError};
+use futures::future::BoxFuture;
+use log::{debug, error, info};
+use reqwest::Client;
+use std::env;
+use std::sync::Arc;
+
+// whisper.cpp's server reads only 16 kHz WAV unless started with --convert.
+const WHISPER_CPP_SAMPLE_RATE: u32 = 16_000;
+
+/// Turns speech into text. One backend is chosen at startup and registered
+/// as `web::Data<dyn SpeechToText>`; long recordings are split before they
+/// get here, so each call is one upload-sized WAV.
+pub trait SpeechToText: Send + Sync {
+    /// Where audio goes, for logs.
+    fn name(&self) -> &str;
+
+    /// The text of a 24 kHz mono WAV spoken in `language` (ISO 639-1).
+    fn transcribe<'a>(&'a self, wav: Vec<u8>, language: &'a str) -> BoxFuture<'a, Result<String, AudioError>>;
+}
+
+/// OpenAI's hosted Whisper (`whisper-1` unless `TRANSCRIPTION_MODEL` says
+/// otherwise).
+pub struct OpenAITranscription {
+    client: Client,
+    model: String,
+}
+
+impl OpenAITranscription {
+    pub fn from_env() -> Self {
+        OpenAITranscription {
+            client: Client::new(),
+            model: env::var("TRANSCRIPTION_MODEL").unwrap_or_else(|_| "whisper-1".to_string()),
+        }
+    }
+
+    async fn request(&self, wav: Vec<u8>, language: &str) -> Result<String, AudioError> {
+        let api_key = env::var("OPENAI_API_KEY")
+            .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
+        let form = reqwest::multipart::Form::new()
+            .text("model", self.model.clone())
+            .text("language", language.to_string())
+            .part("file", wav_part(wav)?);
+
+        let response = self
+            .client
+            .post("https://api.openai.com/v1/audio/transcriptions")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .multipart(form)
+            .send()
+            .await
+            .map_err(AudioError::Http)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Whisper API failed: status={}, error={}", status, error_text);
+            return Err(AudioError::OpenAI(format!("Whisper API failed: {}", error_text)));
+        }
+        let json: serde_json::Value = response.json().await.map_err(AudioError::Http)?;
+        json["text"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| AudioError::OpenAI("No transcript in response".to_string()))
+    }
+}
+
+impl SpeechToText for OpenAITranscription {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn transcribe<'a>(&'a self, wav: Vec<u8>, language: &'a str) -> BoxFuture<'a, Result<String, AudioError>> {
+        Box::pin(self.request(wav, language))
+    }
+}
+
+/// The HTTP APIs a self-hosted server may speak.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhisperApi {
+    /// `POST /v1/audio/transcriptions`, as served by faster-whisper-server,
+    /// LocalAI, vLLM and others.
+    OpenAICompatible,
+    /// whisper.cpp's `examples/server`: `POST /inference`.
+    WhisperCpp,
+}
+
+/// A Whisper server run by the deployment itself, so recordings never
+/// leave it. Configured by `TRANSCRIPTION_BASE_URL`, with optional
+/// `TRANSCRIPTION_MODEL` and `TRANSCRIPTION_API_KEY` (sent as a bearer
+/// token).
+pub struct SelfHostedTranscription {
+    client: Client,
+    api: WhisperApi,
+    base_url: String,
+    model: Option<String>,
+    api_key: Option<String>,
+    name: String,
+}
+
+impl SelfHostedTranscription {
+    pub fn from_env(api: WhisperApi) -> Result<Self, AudioError> {
+        let base_url = env::var("TRANSCRIPTION_BASE_URL").map_err(|_| {
+            AudioError::Config(
+                "TRANSCRIPTION_BASE_URL must be set for a self-hosted transcription server"
+                    .to_string(),
+            )
+        })?;
+        let base_url = base_url.trim_end_matches('/').to_string();
+        Ok(SelfHostedTranscription {
+            client: Client::new(),
+            api,
+            name: format!("{:?} server at {}", api, base_url),
+            base_url,
+            model: env::var("TRANSCRIPTION_MODEL").ok(),
+            api_key: env::var("TRANSCRIPTION_API_KEY").ok(),
+        })
+    }
+
+    async fn request(&self, wav: Vec<u8>, language: &str) -> Result<String, AudioError> {
+        let (url, form) = match self.api {
+            WhisperApi::OpenAICompatible => {
+                let mut form = reqwest::multipart::Form::new().text("language", language.to_string());
+                if let Some(model) = &self.model {
+                    form = form.text("model", model.clone());
+                }
+                (
+                    format!("{}/v1/audio/transcriptions", self.base_url),
+                    form.part("file", wav_part(wav)?),
+                )
+            }
+            WhisperApi::WhisperCpp => {
+                let wav = tokio::task::spawn_blocking(move || {
+                    audio::resample_wav(&wav, WHISPER_CPP_SAMPLE_RATE)
+                })
+                .await
+                .map_err(|e| AudioError::Decode(e.to_string()))??;
+                let form = reqwest::multipart::Form::new()
+                    .text("language", language.to_string())
+                    .text("response_format", "json")
+                    .text("temperature", "0.0")
+                    .part("file", wav_part(wav)?);
+                (format!("{}/inference", self.base_url), form)
+            }
+        };
+
+        let mut request = self.client.post(&url).multipart(form);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("Authorization", format!("Bearer {}", api_key));
+        }
+        let response = request.send().await.map_err(AudioError::Http)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Transcription server failed: status={}, error={}", status, error_text);
+            return Err(AudioError::Transcription(format!(
+                "{} returned {}: {}",
+                url, status, error_text
+            )));
+        }
+        let json: serde_json::Value = response.json().await.map_err(AudioError::Http)?;
+        json["text"]
+            .as_str()
+            // whisper.cpp keeps the leading space of its first token.
+            .map(|text| text.trim().to_string())
+            .ok_or_else(|| AudioError::Transcription(format!("No transcript from {}", url)))
+    }
+}
+
+impl SpeechToText for SelfHostedTranscription {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transcribe<'a>(&'a self, wav: Vec<u8>, language: &'a str) -> BoxFuture<'a, Result<String, AudioError>> {
+        Box::pin(self.request(wav, language))
+    }
+}
+
+fn wav_part(wav: Vec<u8>) -> Result<reqwest::multipart::Part, AudioError> {
+    debug!("Uploading {} bytes of WAV for transcription", wav.len());
+    reqwest::multipart::Part::bytes(wav)
+        .file_name("audio.wav")
+        .mime_str("audio/wav")
+        .map_err(AudioError::Http)
+}
+
+/// Picks the backend named by `TRANSCRIPTION_PROVIDER`: `openai` (the
+/// default), `openai-compatible` or `whisper-cpp`. An unknown name is an
+/// error rather than a fallback, since falling back would send audio to a
+/// third party that the deployment may have meant to avoid.
+pub fn provider_from_env() -> Result<Arc<dyn SpeechToText>, AudioError> {
+    let provider: Arc<dyn SpeechToText> = match env::var("TRANSCRIPTION_PROVIDER")
+        .unwrap_or_else(|_| "openai".to_string())
+        .as_str()
+    {
+        "openai" => Arc::new(OpenAITranscription::from_env()),
+        "openai-compatible" => Arc::new(SelfHostedTranscription::from_env(WhisperApi::OpenAICompatible)?),
+        "whisper-cpp" => Arc::new(SelfHostedTranscription::from_env(WhisperApi::WhisperCpp)?),
+        other => {
+            return Err(AudioError::Config(format!(
+                "Unknown TRANSCRIPTION_PROVIDER '{}'; use openai, openai-compatible or whisper-cpp",
+                other
+            )))
+        }
+    };
+    info!("Transcribing with {}", provider.name());
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    fn wav() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 24_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for _ in 0..12_000 {
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    /// Answers one HTTP request on a local port with `status` and a JSON
+    /// `body`. Returns the base URL and, once served, the raw request.
+    async fn serve_once(status: u16, body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        });
+                    let complete = match length {
+                        Some(length) => request.len() >= end + 4 + length,
+                        None => text.ends_with("0\r\n\r\n"),
+                    };
+                    if complete || n == 0 {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, handle)
+    }
+
+    fn server(api: WhisperApi, base_url: String, model: Option<&str>, api_key: Option<&str>) -> SelfHostedTranscription {
+        SelfHostedTranscription {
+            client: Client::new(),
+            api,
+            name: "test".to_string(),
+            base_url,
+            model: model.map(str::to_string),
+            api_key: api_key.map(str::to_string),
+        }
+    }
+
+    // One test, since the cases share process-wide variables.
+    #[test]
+    fn provider_from_env_rejects_bad_configuration() {
+        for name in ["TRANSCRIPTION_BASE_URL", "TRANSCRIPTION_MODEL", "TRANSCRIPTION_API_KEY"] {
+            env::remove_var(name);
+        }
+        env::set_var("TRANSCRIPTION_PROVIDER", "whisper");
+        assert!(matches!(provider_from_env(), Err(AudioError::Config(_))));
+
+        for provider in ["openai-compatible", "whisper-cpp"] {
+            env::set_var("TRANSCRIPTION_PROVIDER", provider);
+            assert!(
+                matches!(provider_from_env(), Err(AudioError::Config(_))),
+                "{} started without TRANSCRIPTION_BASE_URL",
+                provider
+            );
+        }
+
+        env::set_var("TRANSCRIPTION_BASE_URL", "http://whisper.local:8080/");
+        let provider = provider_from_env().unwrap();
+        assert_eq!(provider.name(), "WhisperCpp server at http://whisper.local:8080");
+
+        env::remove_var("TRANSCRIPTION_PROVIDER");
+        env::remove_var("TRANSCRIPTION_BASE_URL");
+        assert_eq!(provider_from_env().unwrap().name(), "openai");
+    }
+
+    #[tokio::test]
+    async fn whisper_cpp_transcripts_lose_their_leading_space() {
+        let (url, request) = serve_once(200, r#"{"text": " I feel better today.\n"}"#).await;
+        let server = server(WhisperApi::WhisperCpp, url, None, None);
+        let text = server.transcribe(wav(), "en").await.unwrap();
+        assert_eq!(text, "I feel better today.");
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /inference "), "{}", request);
+        assert!(request.contains("name=\"response_format\""));
+        assert!(!request.to_lowercase().contains("authorization:"));
+    }
+
+    #[tokio::test]
+    async fn openai_compatible_servers_get_the_model_and_key() {
+        let (url, request) = serve_once(200, r#"{"text": "Hello"}"#).await;
+        let server = server(WhisperApi::OpenAICompatible, url, Some("small.en"), Some("secret"));
+        assert_eq!(server.transcribe(wav(), "en").await.unwrap(), "Hello");
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /v1/audio/transcriptions "), "{}", request);
+        assert!(request.to_lowercase().contains("authorization: bearer secret"));
+        assert!(request.contains("name=\"model\"\r\n\r\nsmall.en"));
+        assert!(request.contains("name=\"language\"\r\n\r\nen"));
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_missing_text_are_transcription_errors() {
+        let (url, _) = serve_once(500, r#"{"error": "model not loaded"}"#).await;
+        let err = server(WhisperApi::OpenAICompatible, url, None, None)
+            .transcribe(wav(), "en")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, AudioError::Transcription(message) if message.contains("model not loaded")),
+            "{:?}",
+            err
+        );
+
+        let (url, _) = serve_once(200, r#"{"segments": []}"#).await;
+        let err = server(WhisperApi::OpenAICompatible, url, None, None)
+            .transcribe(wav(), "en")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AudioError::Transcription(_)), "{:?}", err);
+    }
+}
+
@@ -0,0 +1,355 @@
+use crate::audio::AudioLimits;
+use crate::{audio_input_error, AudioError, AudioOptions, AudioRequest};
+use actix_multipart::Multipart;
+use actix_web::http::header::{self, Header};
+use actix_web::web::Bytes;
+use actix_web::{dev::Payload, mime, web, FromRequest, HttpMessage, HttpRequest};
+use base64::{engine::general_purpose, Engine as _};
+use futures::future::LocalBoxFuture;
+use futures::{Stream, StreamExt};
+use log::{debug, error};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+// Longest accepted value for a non-file multipart field.
+const MAX_FIELD_BYTES: usize = 4096;
+// Most non-file multipart fields accepted, which with `MAX_FIELD_BYTES`
+// bounds what a form can make us buffer besides the audio.
+const MAX_FIELDS: usize = 32;
+
+/// A recording for `/process-audio` with its options, sent as any of:
+///
+/// - JSON: an `AudioRequest`, the audio base64-encoded;
+/// - `multipart/form-data`: an `audio` file part plus the option fields;
+/// - a raw `audio/*` (or `application/octet-stream`) body, with the
+///   options in the query string.
+pub struct AudioUpload {
+    pub audio: Vec<u8>,
+    pub options: AudioOptions,
+}
+
+impl FromRequest for AudioUpload {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = payload.take();
+
+        Box::pin(async move {
+            let limits = req
+                .app_data::<web::Data<AudioLimits>>()
+                .map(|l| *l.get_ref())
+                .ok_or_else(|| {
+                    error!("No AudioLimits registered as app data");
+                    actix_web::error::ErrorInternalServerError("Audio uploads not configured")
+                })?;
+            let mime = req
+                .mime_type()
+                .map_err(actix_web::error::ErrorBadRequest)?
+                .unwrap_or(mime::APPLICATION_JSON);
+
+            match (mime.type_(), mime.subtype()) {
+                (mime::APPLICATION, mime::JSON) => {
+                    let body = web::Json::<AudioRequest>::from_request(&req, &mut payload)
+                        .await?
+                        .into_inner();
+                    Ok(AudioUpload {
+                        audio: decode_base64(&body.audio, &limits).map_err(audio_input_error)?,
+                        options: body.options,
+                    })
+                }
+                (mime::MULTIPART, mime::FORM_DATA) => {
+                    read_multipart(Multipart::new(req.headers(), payload), &limits).await
+                }
+                (mime::AUDIO, _) | (mime::APPLICATION, mime::OCTET_STREAM) => {
+                    let options = web::Query::<AudioOptions>::from_query(req.query_string())
+                        .map_err(actix_web::error::ErrorBadRequest)?
+                        .into_inner();
+                    let audio = read_limited(payload, &limits).await?;
+                    debug!("Read raw audio body of {} bytes", audio.len());
+                    Ok(AudioUpload { audio, options })
+                }
+                _ => Err(actix_web::error::ErrorUnsupportedMediaType(format!(
+                    "Send JSON, multipart/form-data or an audio/* body, not {}",
+                    mime
+                ))),
+            }
+        })
+    }
+}
+
+fn decode_base64(audio: &str, limits: &AudioLimits) -> Result<Vec<u8>, AudioError> {
+    limits.check_base64_len(audio.len())?;
+    general_purpose::STANDARD.decode(audio).map_err(|e| {
+        error!("Base64 decode failed: {}", e);
+        AudioError::Base64(e)
+    })
+}
+
+/// Collects a body or file part, giving up as soon as it passes the size
+/// limit.
+async fn read_limited<S, E>(mut stream: S, limits: &AudioLimits) -> Result<Vec<u8>, actix_web::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<actix_web::Error>,
+{
+    let mut audio = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        audio.extend_from_slice(&chunk.map_err(Into::into)?);
+        limits.check_size(audio.len()).map_err(audio_input_error)?;
+    }
+    Ok(audio)
+}
+
+async fn read_multipart(mut form: Multipart, limits: &AudioLimits) -> Result<AudioUpload, actix_web::Error> {
+    let mut audio = None;
+    let mut fields = Vec::new();
+    while let Some(field) = form.next().await {
+        let mut field = field?;
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "audio" {
+            if audio.is_some() {
+                return Err(actix_web::error::ErrorBadRequest("More than one 'audio' file part"));
+            }
+            let bytes = read_limited(&mut field, limits).await?;
+            debug!("Read multipart audio part of {} bytes", bytes.len());
+            audio = Some(bytes);
+        } else {
+            if fields.len() == MAX_FIELDS {
+                return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                    "More than {} form fields",
+                    MAX_FIELDS
+                )));
+            }
+            let bytes = field.bytes(MAX_FIELD_BYTES).await.map_err(|_| {
+                actix_web::error::ErrorPayloadTooLarge(format!("Field '{}' is too long", name))
+            })??;
+            let value = std::str::from_utf8(&bytes)
+                .map_err(|_| actix_web::error::ErrorBadRequest(format!("Field '{}' is not UTF-8", name)))?;
+            fields.push(format!(
+                "{}={}",
+                utf8_percent_encode(&name, NON_ALPHANUMERIC),
+                utf8_percent_encode(value, NON_ALPHANUMERIC)
+            ));
+        }
+    }
+
+    let audio = audio.ok_or_else(|| actix_web::error::ErrorBadRequest("Missing 'audio' file part"))?;
+    // The text fields take the same form as query parameters, so they
+    // share that parser.
+    let options = web::Query::<AudioOptions>::from_query(&fields.join("&"))
+        .map_err(actix_web::error::ErrorBadRequest)?
+        .into_inner();
+    Ok(AudioUpload { audio, options })
+}
+
+/// Whether the client would rather have the spoken reply as a raw audio
+/// body than as JSON, going by the first audio or JSON type in `Accept`.
+pub fn prefers_raw_audio(req: &HttpRequest) -> bool {
+    header::Accept::parse(req)
+        .ok()
+        .and_then(|accept| {
+            accept
+                .ranked()
+                .into_iter()
+                .find(|m| m.type_() == mime::AUDIO || m.subtype() == mime::JSON)
+        })
+        .is_some_and(|m| m.type_() == mime::AUDIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    const BOUNDARY: &str = "x-test-boundary";
+
+    fn limits() -> AudioLimits {
+        AudioLimits {
+            max_bytes: 1024,
+            max_duration: Duration::from_secs(60),
+            min_duration: Duration::from_millis(300),
+        }
+    }
+
+    async fn extract(request: TestRequest) -> Result<AudioUpload, actix_web::Error> {
+        let (req, mut payload) = request.app_data(web::Data::new(limits())).to_http_parts();
+        AudioUpload::from_request(&req, &mut payload).await
+    }
+
+    fn status(result: Result<AudioUpload, actix_web::Error>) -> StatusCode {
+        match result {
+            Ok(_) => panic!("upload was accepted"),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    /// A `multipart/form-data` request with the given text fields and, if
+    /// set, an `audio` file part.
+    fn multipart(fields: &[(&str, &str)], audio: Option<&[u8]>) -> TestRequest {
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        if let Some(audio) = audio {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"audio\"; filename=\"note.webm\"\r\nContent-Type: audio/webm\r\n\r\n",
+                    BOUNDARY
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(audio);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn json_bodies_carry_base64_audio() {
+        let request = TestRequest::post().set_json(serde_json::json!({
+            "audio": general_purpose::STANDARD.encode(b"RIFF...."),
+            "language": "en",
+            "genz_mode": true,
+            "conversation_id": "c1",
+        }));
+        let upload = extract(request).await.unwrap();
+        assert_eq!(upload.audio, b"RIFF....");
+        assert_eq!(upload.options.language, "en");
+        assert!(upload.options.genz_mode);
+        assert_eq!(upload.options.conversation_id.as_deref(), Some("c1"));
+
+        let request = TestRequest::post().set_json(serde_json::json!({"audio": "not base64!", "language": "en"}));
+        assert_eq!(status(extract(request).await), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn multipart_forms_carry_a_file_and_option_fields() {
+        let request = multipart(
+            &[("language", "de"), ("sarcastic_mode", "true"), ("output_sample_rate", "16000")],
+            Some(b"OggS\0\x02"),
+        );
+        let upload = extract(request).await.unwrap();
+        assert_eq!(upload.audio, b"OggS\0\x02");
+        assert_eq!(upload.options.language, "de");
+        assert!(upload.options.sarcastic_mode);
+        assert_eq!(upload.options.output_sample_rate, Some(16_000));
+    }
+
+    #[actix_web::test]
+    async fn malformed_multipart_forms_are_rejected() {
+        let missing_audio = multipart(&[("language", "en")], None);
+        assert_eq!(status(extract(missing_audio).await), StatusCode::BAD_REQUEST);
+
+        let long_value = "x".repeat(MAX_FIELD_BYTES + 1);
+        let long_field = multipart(&[("language", &long_value)], Some(b"audio"));
+        assert_eq!(status(extract(long_field).await), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let oversized_audio = multipart(&[("language", "en")], Some(&[0; 1025]));
+        assert_eq!(status(extract(oversized_audio).await), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_web::test]
+    async fn multipart_field_count_is_capped() {
+        let names: Vec<String> = (0..MAX_FIELDS).map(|i| format!("extra_{}", i)).collect();
+        let mut fields: Vec<(&str, &str)> = names.iter().map(|n| (n.as_str(), "1")).collect();
+        fields[0] = ("language", "en");
+        assert!(extract(multipart(&fields, Some(b"audio"))).await.is_ok());
+
+        fields.push(("one_more", "1"));
+        assert_eq!(status(extract(multipart(&fields, Some(b"audio"))).await), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_web::test]
+    async fn repeated_audio_parts_are_rejected() {
+        let part = |filename: &str, data: &str| {
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"audio\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, filename, data
+            )
+        };
+        let language = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"language\"\r\n\r\nen\r\n",
+            BOUNDARY
+        );
+        let body = format!("{}{}--{}--\r\n", language, part("a", "first"), BOUNDARY);
+        let request = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(body);
+        assert!(extract(request).await.is_ok());
+
+        let body = format!("{}{}{}--{}--\r\n", language, part("a", "first"), part("b", "second"), BOUNDARY);
+        let request = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(body);
+        assert_eq!(status(extract(request).await), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn raw_bodies_take_options_from_the_query() {
+        for content_type in ["audio/ogg", "application/octet-stream"] {
+            let request = TestRequest::post()
+                .uri("/process-audio?language=fr&output_bitrate_kbps=64")
+                .insert_header((header::CONTENT_TYPE, content_type))
+                .set_payload(&b"fLaC\0\0"[..]);
+            let upload = extract(request).await.unwrap();
+            assert_eq!(upload.audio, b"fLaC\0\0");
+            assert_eq!(upload.options.language, "fr");
+            assert_eq!(upload.options.output_bitrate_kbps, Some(64));
+        }
+
+        let no_language = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "audio/ogg"))
+            .set_payload(&b"OggS"[..]);
+        assert_eq!(status(extract(no_language).await), StatusCode::BAD_REQUEST);
+
+        let oversized = TestRequest::post()
+            .uri("/process-audio?language=fr")
+            .insert_header((header::CONTENT_TYPE, "audio/ogg"))
+            .set_payload(vec![0; 1025]);
+        assert_eq!(status(extract(oversized).await), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_web::test]
+    async fn other_content_types_are_unsupported() {
+        for content_type in ["text/plain", "application/x-www-form-urlencoded", "video/mp4"] {
+            let request = TestRequest::post()
+                .insert_header((header::CONTENT_TYPE, content_type))
+                .set_payload("language=en");
+            assert_eq!(status(extract(request).await), StatusCode::UNSUPPORTED_MEDIA_TYPE, "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn raw_audio_is_preferred_only_when_ranked_above_json() {
+        let cases = [
+            (None, false),
+            (Some("*/*"), false),
+            (Some("application/json"), false),
+            (Some("audio/mpeg"), true),
+            (Some("audio/*"), true),
+            (Some("application/json, audio/mpeg;q=0.5"), false),
+            (Some("application/json;q=0.5, audio/mpeg"), true),
+            (Some("text/html, audio/ogg;q=0.1"), true),
+        ];
+        for (accept, expected) in cases {
+            let mut request = TestRequest::default();
+            if let Some(accept) = accept {
+                request = request.insert_header((header::ACCEPT, accept));
+            }
+            assert_eq!(prefers_raw_audio(&request.to_http_request()), expected, "{:?}", accept);
+        }
+    }
+}
+
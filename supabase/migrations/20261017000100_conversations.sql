@@ -0,0 +1,17 @@
+-- Messages stored by SupabaseStore, one row per message. Deployments that
+-- predate the store already have this table; it is created only if missing.
+create table if not exists public.conversations (
+    id bigint generated by default as identity primary key,
+    -- The auth provider's subject, not necessarily a Supabase auth.users id.
+    user_id text not null,
+    -- A ChatMessage: {"role": ..., "content": ...}.
+    message jsonb not null,
+    timestamp timestamptz not null default now()
+);
+
+create index if not exists conversations_user_timestamp
+    on public.conversations (user_id, timestamp desc, id desc);
+
+-- The server connects with the service-role key, which bypasses RLS. With
+-- no policies, clients holding the anon key can read nothing.
+alter table public.conversations enable row level security;
@@ -0,0 +1,22 @@
+-- Named threads. Messages outside any thread (conversation_id null) form
+-- the user's default stream, which has no row here.
+create table public.conversation_threads (
+    id text primary key,
+    user_id text not null,
+    title text not null,
+    archived boolean not null default false,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create index conversation_threads_user_created
+    on public.conversation_threads (user_id, created_at desc, id desc);
+
+alter table public.conversation_threads enable row level security;
+
+-- Not a foreign key: messages written before threads existed may carry
+-- ids that were never created as threads.
+alter table public.conversations add column if not exists conversation_id text;
+
+create index conversations_user_thread_timestamp
+    on public.conversations (user_id, conversation_id, timestamp desc, id desc);
@@ -0,0 +1,13 @@
+-- One rolling summary per thread. The default stream's key is 'default'.
+create table public.conversation_summaries (
+    user_id text not null,
+    conversation_key text not null,
+    content text not null,
+    -- The newest message folded in, as (timestamp, id).
+    through_timestamp timestamptz not null,
+    through_id bigint not null,
+    updated_at timestamptz not null default now(),
+    primary key (user_id, conversation_key)
+);
+
+alter table public.conversation_summaries enable row level security;
@@ -0,0 +1,26 @@
+create table public.user_facts (
+    id text primary key,
+    user_id text not null,
+    category text not null,
+    content text not null,
+    -- Thread the fact was mentioned in; null for the default stream.
+    conversation_id text,
+    source_excerpt text not null,
+    created_at timestamptz not null default now()
+);
+
+create index user_facts_user_created on public.user_facts (user_id, created_at, id);
+
+alter table public.user_facts enable row level security;
+
+-- Facts the user deleted, kept so extraction doesn't add them back.
+create table public.suppressed_facts (
+    id text primary key,
+    user_id text not null,
+    content text not null,
+    suppressed_at timestamptz not null default now()
+);
+
+create index suppressed_facts_user on public.suppressed_facts (user_id, suppressed_at, id);
+
+alter table public.suppressed_facts enable row level security;
@@ -0,0 +1,39 @@
+create extension if not exists vector with schema extensions;
+
+-- Dimensions depend on the embedding model, so the column is unsized and
+-- each search is an exact scan over one user's vectors for one model.
+create table public.message_embeddings (
+    message_id bigint primary key references public.conversations (id) on delete cascade,
+    user_id text not null,
+    model text not null,
+    embedding extensions.vector not null
+);
+
+create index message_embeddings_user_model on public.message_embeddings (user_id, model);
+
+alter table public.message_embeddings enable row level security;
+
+-- The user's messages nearest to p_query by cosine similarity, best first.
+create function public.match_messages(
+    p_user_id text,
+    p_model text,
+    p_query extensions.vector,
+    p_limit integer
+)
+returns table (id bigint, message jsonb, "timestamp" timestamptz, similarity double precision)
+language sql
+stable
+set search_path = public, extensions
+as $$
+    select c.id, c.message, c."timestamp", 1 - (e.embedding <=> p_query) as similarity
+    from public.message_embeddings e
+    join public.conversations c on c.id = e.message_id
+    where e.user_id = p_user_id
+      and c.user_id = p_user_id
+      and e.model = p_model
+    order by e.embedding <=> p_query
+    limit p_limit;
+$$;
+
+revoke execute on function public.match_messages(text, text, extensions.vector, integer)
+    from public, anon, authenticated;
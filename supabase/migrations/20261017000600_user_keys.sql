@@ -0,0 +1,13 @@
+-- Each user's data keys, wrapped by a server master key. Versions only
+-- grow; the newest encrypts, older ones still decrypt.
+create table public.user_keys (
+    user_id text not null,
+    version bigint not null,
+    master_key_id text not null,
+    -- Base64 of nonce || ciphertext.
+    wrapped text not null,
+    created_at timestamptz not null default now(),
+    primary key (user_id, version)
+);
+
+alter table public.user_keys enable row level security;
@@ -0,0 +1,41 @@
+-- Per-message keys from Turn::message_keys, so a retried or replayed turn
+-- is written once. Rows stored before this have none.
+alter table public.conversations add column if not exists idempotency_key text;
+
+alter table public.conversations
+    add constraint conversations_idempotency_key_key unique (idempotency_key);
+
+-- match_messages now returns idempotency_key too. Its return type changes,
+-- so it is dropped and recreated rather than replaced.
+drop function public.match_messages(text, text, extensions.vector, integer);
+
+create function public.match_messages(
+    p_user_id text,
+    p_model text,
+    p_query extensions.vector,
+    p_limit integer
+)
+returns table (
+    id bigint,
+    message jsonb,
+    "timestamp" timestamptz,
+    idempotency_key text,
+    similarity double precision
+)
+language sql
+stable
+set search_path = public, extensions
+as $$
+    select c.id, c.message, c."timestamp", c.idempotency_key,
+           1 - (e.embedding <=> p_query) as similarity
+    from public.message_embeddings e
+    join public.conversations c on c.id = e.message_id
+    where e.user_id = p_user_id
+      and c.user_id = p_user_id
+      and e.model = p_model
+    order by e.embedding <=> p_query
+    limit p_limit;
+$$;
+
+revoke execute on function public.match_messages(text, text, extensions.vector, integer)
+    from public, anon, authenticated;
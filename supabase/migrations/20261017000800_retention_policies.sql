@@ -0,0 +1,13 @@
+-- A user's own retention choice; operator policies come from the
+-- environment. The API bounds days further.
+create table public.retention_policies (
+    user_id text primary key,
+    days integer not null check (days > 0),
+    action text not null default 'delete' check (action in ('delete', 'anonymize'))
+);
+
+alter table public.retention_policies enable row level security;
+
+-- Expiry scans every user's rows by age.
+create index conversations_timestamp on public.conversations (timestamp);
+create index user_facts_created on public.user_facts (created_at);